@@ -0,0 +1,219 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use kvs::{Command, KvStore, KvsEngine, SledKvsEngine};
+use rand::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+const KEYS: usize = 100;
+const THREADS: usize = 8;
+const WRITES_PER_THREAD: usize = 100;
+
+fn open_kvs(path: &Path) -> KvStore {
+    KvStore::open(path).unwrap()
+}
+
+fn open_sled(path: &Path) -> SledKvsEngine {
+    SledKvsEngine::new(sled::open(path).unwrap())
+}
+
+/// 固定种子，每次跑出来的 key 顺序都一样
+fn keys(random: bool) -> Vec<String> {
+    let mut keys: Vec<String> = (0..KEYS).map(|i| format!("key{:05}", i)).collect();
+    if random {
+        keys.shuffle(&mut StdRng::seed_from_u64(42));
+    }
+    keys
+}
+
+fn fill<E: KvsEngine>(engine: &E) {
+    for key in keys(false) {
+        engine.set(key, "value".repeat(10)).unwrap();
+    }
+}
+
+/// 顺序和随机写入 `KEYS` 个 key，每次都是一个新的空目录
+fn set<E: KvsEngine>(c: &mut Criterion, name: &str, open: fn(&Path) -> E) {
+    let mut group = c.benchmark_group("set");
+    for (order, random) in [("sequential", false), ("random", true)] {
+        let keys = keys(random);
+        group.bench_function(BenchmarkId::new(name, order), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let engine = open(temp_dir.path());
+                    (temp_dir, engine)
+                },
+                |(_temp_dir, engine)| {
+                    for key in &keys {
+                        engine.set(key.clone(), "value".repeat(10)).unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+    group.finish();
+}
+
+fn get<E: KvsEngine>(c: &mut Criterion, name: &str, open: fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open(temp_dir.path());
+    fill(&engine);
+
+    let mut group = c.benchmark_group("get");
+    for (order, random) in [("sequential", false), ("random", true)] {
+        let keys = keys(random);
+        group.bench_function(BenchmarkId::new(name, order), |b| {
+            b.iter(|| {
+                for key in &keys {
+                    assert!(engine.get(key.clone()).unwrap().is_some());
+                }
+            });
+        });
+    }
+    group.finish();
+}
+
+/// 每个线程拿着一个 clone 出来的引擎同时读
+fn concurrent_get<E: KvsEngine>(c: &mut Criterion, name: &str, open: fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open(temp_dir.path());
+    fill(&engine);
+
+    c.bench_function(&format!("concurrent_get/{}", name), |b| {
+        b.iter(|| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let engine = engine.clone();
+                    thread::spawn(move || {
+                        for i in 0..KEYS {
+                            let key = format!("key{:05}", (i + t) % KEYS);
+                            assert!(engine.get(key).unwrap().is_some());
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        });
+    });
+}
+
+fn engines(c: &mut Criterion) {
+    set(c, "kvs", open_kvs);
+    set(c, "sled", open_sled);
+    get(c, "kvs", open_kvs);
+    get(c, "sled", open_sled);
+    concurrent_get(c, "kvs", open_kvs);
+    concurrent_get(c, "sled", open_sled);
+}
+
+/// 反复覆盖同一批 key，写满 2MB 左右，`KvStore` 中间会触发压缩
+fn compaction(c: &mut Criterion) {
+    let value = "v".repeat(1024);
+    c.bench_function("compaction", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = open_kvs(temp_dir.path());
+                (temp_dir, store)
+            },
+            |(_temp_dir, store)| {
+                for i in 0..2048 {
+                    store
+                        .set(format!("key{}", i % KEYS), value.clone())
+                        .unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        );
+    });
+}
+
+/// 以前的写法作为基准：所有线程抢一把锁，每条命令单独写入并 flush
+///
+/// 不维护索引，比原来的 writer 还少做一点事，对 writer 线程来说是偏严格的对比
+#[derive(Clone)]
+struct MutexWriter(Arc<Mutex<BufWriter<File>>>);
+
+impl MutexWriter {
+    fn open(path: &Path) -> MutexWriter {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join("1.log"))
+            .unwrap();
+        MutexWriter(Arc::new(Mutex::new(BufWriter::new(file))))
+    }
+
+    fn set(&self, key: String, value: String) {
+        let mut writer = self.0.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &Command::Set { key, value }).unwrap();
+        writer.flush().unwrap();
+    }
+}
+
+/// `THREADS` 个线程各写 `WRITES_PER_THREAD` 个不同的 key
+fn set_concurrently<F>(set: F)
+where
+    F: Fn(String, String) + Clone + Send + 'static,
+{
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let set = set.clone();
+            thread::spawn(move || {
+                for i in 0..WRITES_PER_THREAD {
+                    set(format!("key{}-{}", t, i), "value".to_owned());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+/// 多个线程同时写入，对比加锁逐条 flush 的旧写法和 writer 线程合并写入
+fn concurrent_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set");
+    group.bench_function("mutex", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let writer = MutexWriter::open(temp_dir.path());
+                (temp_dir, writer)
+            },
+            |(_temp_dir, writer)| set_concurrently(move |key, value| writer.set(key, value)),
+            BatchSize::PerIteration,
+        );
+    });
+    for &max_batch in &[1, 16, 128] {
+        group.bench_with_input(
+            BenchmarkId::new("batch", max_batch),
+            &max_batch,
+            |b, &max_batch| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        let store =
+                            KvStore::open_with_batch_size(temp_dir.path(), max_batch).unwrap();
+                        (temp_dir, store)
+                    },
+                    |(_temp_dir, store)| {
+                        set_concurrently(move |key, value| store.set(key, value).unwrap())
+                    },
+                    BatchSize::PerIteration,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, engines, compaction, concurrent_set);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, TheBookThreadPool, ThreadPool,
+};
+use kvs::{KvStore, KvsClient, KvsServer};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const POOL_THREADS: u32 = 8;
+const CLIENTS: usize = 4;
+const REQUESTS_PER_CLIENT: usize = 100;
+
+/// 在后台线程里启动一个服务器，一直跑到 bench 进程结束
+fn start_server<P: ThreadPool>(port: u16) -> (TempDir, SocketAddr) {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        let pool = P::new(POOL_THREADS).unwrap();
+        KvsServer::new(engine, pool).run(addr).unwrap()
+    });
+    while KvsClient::connect(addr).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    (temp_dir, addr)
+}
+
+/// 每个客户端一个连接，先写再读
+fn clients(addr: SocketAddr) {
+    let handles: Vec<_> = (0..CLIENTS)
+        .map(|c| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for i in 0..REQUESTS_PER_CLIENT {
+                    client
+                        .set(format!("key{}-{}", c, i), "value".to_owned())
+                        .unwrap();
+                }
+                for i in 0..REQUESTS_PER_CLIENT {
+                    assert!(client.get(format!("key{}-{}", c, i)).unwrap().is_some());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn bench_pool<P: ThreadPool>(c: &mut Criterion, name: &str, port: u16) {
+    let (_temp_dir, addr) = start_server::<P>(port);
+    c.benchmark_group("server")
+        .bench_function(BenchmarkId::new("pool", name), |b| b.iter(|| clients(addr)));
+}
+
+fn pools(c: &mut Criterion) {
+    bench_pool::<NaiveThreadPool>(c, "naive", 4300);
+    bench_pool::<SharedQueueThreadPool>(c, "shared_queue", 4301);
+    bench_pool::<RayonThreadPool>(c, "rayon", 4302);
+    bench_pool::<TheBookThreadPool>(c, "the_book", 4303);
+}
+
+criterion_group!(benches, pools);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::client::{into_result, unexpected};
+use crate::common::{Request, Response};
+use crate::protocol::{self, Frame};
+use crate::{Credentials, KvsError, Result};
+
+/// The async counterpart of `KvsClient`, speaking the framed protocol over a tokio socket.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    next_id: u32,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let mut tcp = TcpStream::connect(addr).await?;
+        protocol::client_handshake_async(&mut tcp).await?;
+        let (reader, writer) = tcp.into_split();
+        Ok(AsyncKvsClient {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            next_id: 0,
+        })
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(Request::Get { key }).await? {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(Request::Set { key, value }).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(Request::Remove { key }).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Authenticates the connection, needed before anything else when the server has an ACL.
+    pub async fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        match self.call(Request::Auth(credentials)).await? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        req.to_frame(id)?.write_async(&mut self.writer).await?;
+        self.writer.flush().await?;
+
+        let frame = Frame::read_async(&mut self.reader)
+            .await?
+            .ok_or_else(|| KvsError::Protocol("Connection closed".to_owned()))?;
+        if frame.id != id {
+            return Err(KvsError::Protocol(format!(
+                "Expected response {}, got {}",
+                id, frame.id
+            )));
+        }
+        into_result(Response::from_frame(&frame)?)
+    }
+}
@@ -0,0 +1,714 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader, BufWriter,
+};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::{Access, Session};
+use crate::common::{GetResponse, Request, Response};
+use crate::metrics::Metrics;
+use crate::protocol::{self, Frame};
+use crate::resp;
+use crate::handler::{self, Limits, Protocol, DEFAULT_SHUTDOWN_TIMEOUT, REJECT_TIMEOUT};
+use crate::trace::Span;
+use crate::{Acl, AsyncEngine, KvsEngine, KvsError, Result, ServerTls, ShutdownHandle};
+
+/// 多路复用的连接上，最多有多少个响应在等着写回去
+const MULTIPLEX_BACKLOG: usize = 64;
+/// 多路复用的连接上最多同时处理多少个请求，和客户端流水线的窗口一样大
+const MULTIPLEX_IN_FLIGHT: usize = 128;
+
+/// A server running on tokio, every connection is a task rather than a thread.
+///
+/// Engine calls run on tokio's blocking pool, through `AsyncEngine`.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+    read_only: bool,
+    tls: Option<TlsAcceptor>,
+    acl: Option<Acl>,
+    limits: Limits,
+    protocol: Protocol,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine: AsyncEngine::new(engine),
+            read_only: false,
+            tls: None,
+            acl: None,
+            limits: Limits::default(),
+            protocol: Protocol::Kvs,
+            metrics: Metrics::new(),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// A read-only server rejects `Set` and `Remove`, used for replicas.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Only accepts TLS connections, authenticated with `tls`.
+    pub fn set_tls(&mut self, tls: ServerTls) {
+        self.tls = Some(TlsAcceptor::from(tls.config()));
+    }
+
+    /// Requires clients to authenticate, and limits each user to the keys `acl` allows.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(acl);
+    }
+
+    /// Replaces the default connection limits and timeouts.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Serves `protocol` instead of the kvs protocol.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Records requests and connections in `metrics`, shared with a `MetricsServer`.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Sets how long a shutdown waits for the requests being handled.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Returns a handle which makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        handler::check_protocol(self.protocol, self.tls.is_some())?;
+        let listener = TcpListener::bind(addr).await?;
+        // 每个连接的任务都拿着一个 sender，全部结束之后 receiver 才会收到 None
+        let (running, mut finished) = mpsc::channel::<()>(1);
+        let permits = Arc::new(Semaphore::new(
+            self.limits.max_connections.min(Semaphore::MAX_PERMITS),
+        ));
+        loop {
+            let tcp = tokio::select! {
+                _ = self.shutdown.wait() => break,
+                res = listener.accept() => match res {
+                    Ok((tcp, _)) => tcp,
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            // 连接结束时 permit 跟着任务一起释放
+            let permit = match Arc::clone(&permits).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    warn!("Too many connections, rejecting {:?}", tcp.peer_addr());
+                    self.metrics.rejected();
+                    tokio::spawn(reject(tcp, self.tls.is_some()));
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            let read_only = self.read_only;
+            let tls = self.tls.clone();
+            let acl = self.acl.clone();
+            let limits = self.limits;
+            let protocol = self.protocol;
+            let metrics = self.metrics.clone();
+            let shutdown = self.shutdown.clone();
+            let running = running.clone();
+            tokio::spawn(Span::connection().scope(async move {
+                debug!("Serving {:?}", tcp.peer_addr());
+                let active = metrics.connection();
+                let res = match tls {
+                    Some(tls) => {
+                        serve_tls(engine, tcp, tls, read_only, acl, limits, metrics, shutdown).await
+                    }
+                    None => {
+                        serve(
+                            engine, tcp, read_only, acl, limits, protocol, metrics, shutdown,
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = res {
+                    error!("Error on serving client: {}", e);
+                }
+                drop(active);
+                drop(permit);
+                drop(running);
+            }));
+        }
+
+        info!("Shutting down");
+        drop(listener);
+        drop(running);
+        if time::timeout(self.shutdown_timeout, finished.recv())
+            .await
+            .is_err()
+        {
+            warn!("Requests still running after {:?}", self.shutdown_timeout);
+        }
+        self.engine.run(|engine| engine.flush()).await?
+    }
+}
+
+/// The async version of `server::reject`, only waits `REJECT_TIMEOUT` in total.
+async fn reject(mut tcp: TcpStream, tls: bool) {
+    let res = time::timeout(REJECT_TIMEOUT, async {
+        if !tls {
+            let mut first = [0; 1];
+            if tcp.peek(&mut first).await? == 1 {
+                tcp.write_all(&handler::busy_reply(first[0])?).await?;
+            }
+        }
+        tcp.shutdown().await?;
+        // 读掉客户端发来的数据再关，避免 RST
+        io::copy(&mut (&mut tcp).take(64 * 1024), &mut io::sink()).await?;
+        Result::Ok(())
+    })
+    .await;
+    if let Ok(Err(e)) = res {
+        debug!("Error on rejecting client: {}", e);
+    }
+}
+
+/// Waits at most `idle_timeout` for the first byte, `None` if the client closed, stayed silent or
+/// the server is shutting down.
+async fn peek_first(
+    tcp: &TcpStream,
+    limits: &Limits,
+    shutdown: &ShutdownHandle,
+) -> Result<Option<u8>> {
+    let mut first = [0; 1];
+    tokio::select! {
+        _ = shutdown.wait() => Ok(None),
+        n = time::timeout(limits.idle_timeout, tcp.peek(&mut first)) => match n {
+            Ok(n) => Ok(if n? == 0 { None } else { Some(first[0]) }),
+            Err(_) => {
+                debug!("Closing idle connection");
+                Ok(None)
+            }
+        },
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve<E: KvsEngine>(
+    engine: AsyncEngine<E>,
+    mut tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    protocol: Protocol,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    let first = match peek_first(&tcp, &limits, &shutdown).await? {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    if protocol == Protocol::Resp {
+        return serve_resp(engine, tcp, read_only, acl, limits, metrics, shutdown).await;
+    }
+    if first != protocol::MAGIC[0] {
+        return serve_json(engine, tcp, read_only, acl, limits, metrics, shutdown).await;
+    }
+
+    within(
+        limits.request_timeout,
+        protocol::server_handshake_async(&mut tcp),
+    )
+    .await?;
+    let (reader, writer) = tcp.into_split();
+    serve_framed(
+        engine, reader, writer, read_only, acl, limits, metrics, shutdown,
+    )
+    .await
+}
+
+/// Serves one TLS connection, which always speaks the framed protocol.
+#[allow(clippy::too_many_arguments)]
+async fn serve_tls<E: KvsEngine>(
+    engine: AsyncEngine<E>,
+    tcp: TcpStream,
+    tls: TlsAcceptor,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    match peek_first(&tcp, &limits, &shutdown).await? {
+        Some(first) if first == protocol::MAGIC[0] => {
+            return Err(KvsError::Tls("Plaintext client on a TLS server".to_owned()))
+        }
+        Some(_) => {}
+        None => return Ok(()),
+    }
+    let mut stream = tokio::select! {
+        _ = shutdown.wait() => return Ok(()),
+        stream = within(limits.request_timeout, async { Ok(tls.accept(tcp).await?) }) => stream?,
+    };
+    within(
+        limits.request_timeout,
+        protocol::server_handshake_async(&mut stream),
+    )
+    .await?;
+    let (reader, writer) = io::split(stream);
+    serve_framed(
+        engine, reader, writer, read_only, acl, limits, metrics, shutdown,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn serve_framed<E, R, W>(
+    engine: AsyncEngine<E>,
+    reader: R,
+    writer: W,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::new(acl);
+
+    loop {
+        let frame = match next_frame(&mut reader, &limits, &shutdown).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(KvsError::FrameTooLarge { id, len }) => {
+                let resp = handler::too_large(id, len, &limits).to_frame(id)?;
+                within(limits.request_timeout, send(&resp, &mut writer, true)).await?;
+                return Err(KvsError::FrameTooLarge { id, len });
+            }
+            Err(e) => return Err(e),
+        };
+        if frame.code == protocol::OP_MULTIPLEX {
+            let resp = Response::Ok.to_frame(frame.id)?;
+            within(limits.request_timeout, send(&resp, &mut writer, true)).await?;
+            return serve_multiplexed(
+                engine, reader, writer, read_only, session, limits, metrics, shutdown,
+            )
+            .await;
+        }
+        let id = frame.id;
+        let resp = match handler::authenticate(&mut session, &frame, &metrics) {
+            Some(resp) => resp,
+            None => {
+                let access = session.access().clone();
+                let span = Span::current_request();
+                respond(&engine, frame, read_only, access, &metrics, span).await
+            }
+        };
+        // 流水线发来的请求还没处理完的话，先不 flush
+        let flush = reader.buffer().is_empty();
+        within(
+            limits.request_timeout,
+            send(&handler::encode(&resp, id)?, &mut writer, flush),
+        )
+        .await?;
+        debug!("Response sent: {:?}", resp);
+    }
+    Ok(())
+}
+
+/// 每个请求一个任务，谁先处理完谁先回复
+///
+/// 任务数有上限，都在处理的时候不再读新的请求，不读响应的客户端撑不大服务器的内存
+#[allow(clippy::too_many_arguments)]
+async fn serve_multiplexed<E, R, W>(
+    engine: AsyncEngine<E>,
+    mut reader: BufReader<R>,
+    mut writer: BufWriter<W>,
+    read_only: bool,
+    mut session: Session,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel::<Frame>(MULTIPLEX_BACKLOG);
+    let responder = tokio::spawn(async move {
+        while let Some(frame) = receiver.recv().await {
+            within(limits.request_timeout, send(&frame, &mut writer, true)).await?;
+        }
+        Result::Ok(())
+    });
+
+    let in_flight = Arc::new(Semaphore::new(MULTIPLEX_IN_FLIGHT));
+    let mut res = Ok(());
+    loop {
+        // semaphore 不会被关闭
+        let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+        let frame = match next_frame(&mut reader, &limits, &shutdown).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                if let KvsError::FrameTooLarge { id, len } = e {
+                    let _ = sender
+                        .send(handler::too_large(id, len, &limits).to_frame(id)?)
+                        .await;
+                }
+                res = Err(e);
+                break;
+            }
+        };
+        // 认证要在后面的请求之前生效，不能放到任务里
+        if let Some(resp) = handler::authenticate(&mut session, &frame, &metrics) {
+            let _ = sender.send(resp.to_frame(frame.id)?).await;
+            continue;
+        }
+        let engine = engine.clone();
+        let sender = sender.clone();
+        let access = session.access().clone();
+        let metrics = metrics.clone();
+        let span = Span::current_request();
+        tokio::spawn(async move {
+            let id = frame.id;
+            let resp = respond(&engine, frame, read_only, access, &metrics, span).await;
+            match handler::encode(&resp, id) {
+                Ok(frame) => {
+                    let _ = sender.send(frame).await;
+                }
+                Err(e) => error!("Error on encoding response: {}", e),
+            }
+            // 响应交给 responder 之后才算处理完
+            drop(permit);
+        });
+    }
+    // 所有请求任务都结束之后 channel 才会关闭
+    drop(sender);
+    responder
+        .await
+        .map_err(|e| KvsError::StringError(format!("Responder failed: {}", e)))??;
+    res
+}
+
+/// Serves one connection speaking RESP, only the commands run on the blocking pool.
+#[allow(clippy::too_many_arguments)]
+async fn serve_resp<E: KvsEngine>(
+    engine: AsyncEngine<E>,
+    tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    let (reader, writer) = tcp.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    // 连接的状态跟着每个命令进出阻塞线程池
+    let mut state = (Session::new(acl), resp::Cursors::default());
+
+    loop {
+        let read = |reader| resp::read_command_async(reader, limits.max_request_size);
+        let args = match next_request(&mut reader, &limits, &shutdown, read).await {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            // 出错之后找不到下一个命令从哪开始，回复之后关闭连接
+            Err(KvsError::Protocol(e)) => {
+                let reply = resp::Frame::error(format!("ERR Protocol error: {}", e));
+                within(
+                    limits.request_timeout,
+                    send_reply(&reply, &mut writer, true),
+                )
+                .await?;
+                return Err(KvsError::Protocol(e));
+            }
+            Err(e) => return Err(e),
+        };
+        // inline 命令的空行直接忽略
+        if args.is_empty() {
+            continue;
+        }
+        let metrics = metrics.clone();
+        let span = Span::current_request();
+        let (reply, quit, returned) = engine
+            .run(move |engine| {
+                let _span = span.enter();
+                let (mut session, mut cursors) = state;
+                let (reply, quit) = resp::execute(
+                    engine,
+                    args,
+                    read_only,
+                    &mut session,
+                    &mut cursors,
+                    &metrics,
+                );
+                (reply, quit, (session, cursors))
+            })
+            .await?;
+        state = returned;
+        // 流水线发来的命令还没处理完的话，先不 flush
+        let flush = quit || reader.buffer().is_empty();
+        within(
+            limits.request_timeout,
+            send_reply(&reply, &mut writer, flush),
+        )
+        .await?;
+        debug!("Reply sent: {:?}", reply);
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The streaming JSON protocol spoken by older clients, only the requests run on the blocking pool.
+#[allow(clippy::too_many_arguments)]
+async fn serve_json<E: KvsEngine>(
+    engine: AsyncEngine<E>,
+    tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    let (reader, writer) = tcp.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::new(acl);
+
+    loop {
+        // 请求后面常跟着换行，已经读到的空白先跳过，不然会被当成下一个请求开始了
+        let blank = reader
+            .buffer()
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        reader.consume(blank);
+        let read = |reader| read_json(reader, &limits);
+        let req = match next_request(&mut reader, &limits, &shutdown, read).await {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            // 太大或者不是 json 对象的请求没法跳过，回复之后就关闭连接
+            Err(KvsError::Protocol(message)) => {
+                let resp = serde_json::to_vec(&GetResponse::Err(message.clone()))?;
+                within(limits.request_timeout, send_raw(&resp, &mut writer, true)).await?;
+                return Err(KvsError::Protocol(message));
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Receive request: {:?}", req);
+        let metrics = metrics.clone();
+        let span = Span::current_request();
+        let (resp, returned) = engine
+            .run(move |engine| {
+                let _span = span.enter();
+                let resp = handler::respond_json(engine, req, read_only, &mut session, &metrics);
+                (resp, session)
+            })
+            .await?;
+        session = returned;
+        within(limits.request_timeout, send_raw(&resp?, &mut writer, true)).await?;
+    }
+    Ok(())
+}
+
+/// Reads the next request of the JSON protocol, `None` if the connection was closed before it.
+///
+/// 旧协议的请求都是 json 对象，先找到和开头的 `{` 配对的 `}`，最多读 `max_request_size` 字节，再解析
+async fn read_json<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<Option<Request>> {
+    let mut scanner = JsonScanner::default();
+    let mut buf = Vec::new();
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return match buf.iter().all(u8::is_ascii_whitespace) {
+                true => Ok(None),
+                false => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            };
+        }
+        let (used, done) = scanner.scan(available)?;
+        buf.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if buf.len() > limits.max_request_size as usize {
+            return Err(KvsError::Protocol(handler::json_too_large(limits)));
+        }
+        if done {
+            return Ok(Some(serde_json::from_slice(&buf)?));
+        }
+    }
+}
+
+/// Finds where a JSON object ends without parsing it.
+#[derive(Default)]
+struct JsonScanner {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonScanner {
+    /// How many bytes of `bytes` belong to the object, and whether the object ends there.
+    fn scan(&mut self, bytes: &[u8]) -> Result<(usize, bool)> {
+        for (i, &b) in bytes.iter().enumerate() {
+            if self.in_string {
+                match b {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match b {
+                b'{' => self.depth += 1,
+                b'[' if self.depth > 0 => self.depth += 1,
+                b'"' if self.depth > 0 => self.in_string = true,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Ok((i + 1, true));
+                    }
+                }
+                // 对象开始之前只能有空白
+                _ if self.depth > 0 || b.is_ascii_whitespace() => {}
+                _ => return Err(KvsError::Protocol("Expected a JSON object".to_owned())),
+            }
+        }
+        Ok((bytes.len(), false))
+    }
+}
+
+/// 读下一个请求，关闭或者空闲超时的时候返回 `None`，不再接收新的请求
+///
+/// 读到一半的帧会被丢掉，这个请求本来也还没开始处理
+async fn next_frame<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    limits: &Limits,
+    shutdown: &ShutdownHandle,
+) -> Result<Option<Frame>> {
+    let read = |reader| Frame::read_async_limited(reader, limits.max_request_size);
+    next_request(reader, limits, shutdown, read).await
+}
+
+/// Waits `idle_timeout` for a request to start, then gives `read` `request_timeout` to read all of it.
+///
+/// 关闭或者空闲超时的时候返回 `None`，读到一半的请求会被丢掉
+async fn next_request<'a, R, T, F>(
+    reader: &'a mut BufReader<R>,
+    limits: &Limits,
+    shutdown: &ShutdownHandle,
+    read: impl FnOnce(&'a mut BufReader<R>) -> F,
+) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    F: Future<Output = Result<Option<T>>>,
+{
+    let read = async move {
+        // 先等到请求的第一个字节，之后整个请求要在 request_timeout 内读完
+        match time::timeout(limits.idle_timeout, reader.fill_buf()).await {
+            Ok(buf) => {
+                if buf?.is_empty() {
+                    return Ok(None);
+                }
+            }
+            Err(_) => {
+                debug!("Closing idle connection");
+                return Ok(None);
+            }
+        }
+        within(limits.request_timeout, read(reader)).await
+    };
+    tokio::select! {
+        biased;
+        _ = shutdown.wait() => Ok(None),
+        req = read => req,
+    }
+}
+
+/// Writes a frame, flushing it unless more responses are about to follow.
+async fn send<W: AsyncWrite + Unpin>(frame: &Frame, writer: &mut W, flush: bool) -> Result<()> {
+    frame.write_async(writer).await?;
+    if flush {
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes a RESP reply, flushing it unless more replies are about to follow.
+async fn send_reply<W: AsyncWrite + Unpin>(
+    reply: &resp::Frame,
+    writer: &mut W,
+    flush: bool,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    reply.write(&mut buf)?;
+    send_raw(&buf, writer, flush).await
+}
+
+async fn send_raw<W: AsyncWrite + Unpin>(buf: &[u8], writer: &mut W, flush: bool) -> Result<()> {
+    writer.write_all(buf).await?;
+    if flush {
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Fails with a `TimedOut` error if `fut` takes longer than `timeout`.
+async fn within<T>(timeout: Duration, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    match time::timeout(timeout, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(KvsError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Request timed out",
+        ))),
+    }
+}
+
+/// 和 `AsyncEngine` 一样，返回的 future 不能借用 engine
+fn respond<E: KvsEngine>(
+    engine: &AsyncEngine<E>,
+    frame: Frame,
+    read_only: bool,
+    access: Access,
+    metrics: &Metrics,
+    span: Span,
+) -> impl Future<Output = Response> {
+    let metrics = metrics.clone();
+    let resp = engine.run(move |engine| {
+        let _span = span.enter();
+        handler::respond(engine, &frame, read_only, &access, &metrics)
+    });
+    async move {
+        resp.await
+            .unwrap_or_else(|e| Response::from_error(&e))
+    }
+}
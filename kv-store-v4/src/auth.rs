@@ -0,0 +1,314 @@
+//! Authentication and per-prefix access control.
+//!
+//! The users are loaded from a JSON file on the server:
+//!
+//! ```json
+//! {
+//!     "users": {
+//!         "alice": {
+//!             "password_hash": "pbkdf2-sha256$100000$9zS0...$Xq1f...",
+//!             "rules": [{ "prefix": "app/", "read": true, "write": true }]
+//!         },
+//!         "monitor": {
+//!             "tokens": ["2c9d4a"],
+//!             "rules": [{ "prefix": "", "read": true }]
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Passwords are only stored as PBKDF2 hashes, made with `hash_password` or
+//! `kvs-server hash-password`.
+//!
+//! Once a server has an `Acl`, a connection can't do anything before sending
+//! an `Auth` request. A user may read or write a key when one of its rules has
+//! a prefix of the key and grants it. Replication reads every key, so it needs
+//! read access to the empty prefix.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::common::{Request, Response};
+use crate::{KvsError, Result};
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// What a client authenticates itself with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token(String),
+}
+
+/// The users allowed to connect and the keys each of them may access.
+#[derive(Clone)]
+pub struct Acl {
+    users: Arc<HashMap<String, Arc<User>>>,
+}
+
+#[derive(Deserialize)]
+struct AclFile {
+    users: HashMap<String, User>,
+}
+
+/// 不认识的字段报错，旧文件里明文的 `password` 不能被悄悄忽略掉
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct User {
+    #[serde(skip)]
+    name: String,
+    password_hash: Option<PasswordHash>,
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// `pbkdf2-sha256$<iterations>$<base64 salt>$<base64 hash>`
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Rule {
+    prefix: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+}
+
+impl Acl {
+    /// Loads the users from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let file: AclFile = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let users = file
+            .users
+            .into_iter()
+            .map(|(name, mut user)| {
+                user.name = name.clone();
+                (name, Arc::new(user))
+            })
+            .collect();
+        Ok(Acl {
+            users: Arc::new(users),
+        })
+    }
+
+    fn authenticate(&self, credentials: &Credentials) -> Option<Arc<User>> {
+        match credentials {
+            Credentials::Password { user, password } => {
+                let user = self.users.get(user);
+                // 没有这个用户或者它没有密码的时候也算一遍 hash，从回复的快慢猜不出哪些用户名存在
+                match user.and_then(|u| u.password_hash.as_ref()) {
+                    Some(hash) if hash.verify(password) => user.cloned(),
+                    Some(_) => None,
+                    None => {
+                        PasswordHash::dummy().verify(password);
+                        None
+                    }
+                }
+            }
+            Credentials::Token(token) => self
+                .users
+                .values()
+                .find(|u| u.tokens.iter().any(|t| same(t, token)))
+                .cloned(),
+        }
+    }
+}
+
+/// Hashes a password for the `password_hash` of a user in the ACL file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| KvsError::StringError("Failed to generate a salt".to_owned()))?;
+    let iterations = NonZeroU32::new(HASH_ITERATIONS).unwrap();
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        iterations,
+        base64::encode(salt),
+        base64::encode(hash)
+    ))
+}
+
+impl PasswordHash {
+    /// 迭代次数和 `hash_password` 生成的一样，什么密码都比不上
+    fn dummy() -> PasswordHash {
+        PasswordHash {
+            iterations: NonZeroU32::new(HASH_ITERATIONS).unwrap(),
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; HASH_LEN],
+        }
+    }
+
+    /// pbkdf2 比较的时间和前面有几个字节对了无关
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+impl TryFrom<String> for PasswordHash {
+    type Error = KvsError;
+
+    fn try_from(s: String) -> Result<Self> {
+        let invalid = || KvsError::StringError(format!("Invalid password hash {:?}", s));
+        let parts: Vec<&str> = s.split('$').collect();
+        let hash = match parts[..] {
+            [HASH_SCHEME, iterations, salt, hash] => PasswordHash {
+                iterations: iterations.parse().map_err(|_| invalid())?,
+                salt: base64::decode(salt).map_err(|_| invalid())?,
+                hash: base64::decode(hash).map_err(|_| invalid())?,
+            },
+            _ => return Err(invalid()),
+        };
+        // 空的 hash 什么密码都比不上，当作写错了
+        if hash.hash.is_empty() {
+            return Err(invalid());
+        }
+        Ok(hash)
+    }
+}
+
+impl User {
+    fn allows(&self, key: &str, write: bool) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| key.starts_with(&rule.prefix) && if write { rule.write } else { rule.read })
+    }
+}
+
+/// 比较密码和 token 的时间不能取决于前面有几个字节是对的
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// What a connection may do.
+#[derive(Clone)]
+pub(crate) enum Access {
+    /// 服务器没有配置 acl
+    All,
+    User(Arc<User>),
+    /// 还没认证
+    Nothing,
+}
+
+impl Access {
+    /// The name of the authenticated user, `None` without an acl.
+    pub(crate) fn user(&self) -> Option<&str> {
+        match self {
+            Access::User(user) => Some(&user.name),
+            _ => None,
+        }
+    }
+
+    /// Returns why the request isn't allowed, if it isn't.
+    pub(crate) fn check(&self, req: &Request) -> std::result::Result<(), String> {
+        match req {
+            Request::Get { key } => self.check_key(key, false),
+            Request::Set { key, .. } | Request::Remove { key } => self.check_key(key, true),
+            Request::Replicate { .. } => self.check_key("", false),
+            Request::Auth(_) => Ok(()),
+            // 多个 key 的请求只要有一个 key 不允许，整个请求都拒绝
+            Request::MGet { keys } => keys.iter().try_for_each(|key| self.check_key(key, false)),
+            Request::MSet { pairs } => pairs
+                .iter()
+                .try_for_each(|(key, _)| self.check_key(key, true)),
+            Request::MRemove { keys } => keys.iter().try_for_each(|key| self.check_key(key, true)),
+            // 扫描的是所有的 key，要能读整个 keyspace
+            Request::Scan { .. } => self.check_key("", false),
+        }
+    }
+
+    /// Also checks a prefix, every key starting with it is allowed if the prefix is.
+    pub(crate) fn check_key(&self, key: &str, write: bool) -> std::result::Result<(), String> {
+        match self {
+            Access::All => Ok(()),
+            Access::User(user) if user.allows(key, write) => Ok(()),
+            Access::User(user) => Err(format!(
+                "{} may not {} {:?}",
+                user.name,
+                if write { "write" } else { "read" },
+                key
+            )),
+            Access::Nothing => Err("Not authenticated".to_owned()),
+        }
+    }
+}
+
+/// The authentication state of one connection.
+pub(crate) struct Session {
+    acl: Option<Acl>,
+    access: Access,
+}
+
+impl Session {
+    pub(crate) fn new(acl: Option<Acl>) -> Self {
+        let access = match acl {
+            Some(_) => Access::Nothing,
+            None => Access::All,
+        };
+        Session { acl, access }
+    }
+
+    pub(crate) fn access(&self) -> &Access {
+        &self.access
+    }
+
+    /// Handles an `Auth` request, a failed one also drops the access granted before.
+    pub(crate) fn authenticate(&mut self, credentials: &Credentials) -> Response {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Response::Ok,
+        };
+        match acl.authenticate(credentials) {
+            Some(user) => {
+                debug!("Authenticated as {}", user.name);
+                self.access = Access::User(user);
+                Response::Ok
+            }
+            None => {
+                warn!("Authentication failed");
+                self.access = Access::Nothing;
+                Response::Denied("Invalid credentials".to_owned())
+            }
+        }
+    }
+}
@@ -1,12 +1,57 @@
-use std::{net::SocketAddr, process::exit};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{net::SocketAddr, path::{Path, PathBuf}, process::exit};
 
-use clap::{Parser, AppSettings, Subcommand};
+use clap::{ArgEnum, Args, Parser, AppSettings, Subcommand};
 
-use kvs::{KvsClient, Result};
+use kvs::{
+    ClientTls, Credentials, DirLock, DumpFormat, DumpReader, DumpWriter, KvStore, KvsClient,
+    KvsEngine, KvsError, Result, SledKvsEngine,
+};
 use log::info;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
+/// raft 集群里 follower 会把请求重定向到 leader，最多跟着跳几次
+const MAX_REDIRECTS: usize = 3;
+/// 导出时每次 scan 多少个 pair，服务器那边一次最多也只给这么多
+const EXPORT_PAGE: usize = 1000;
+/// 导入导出每过这么多个 pair 在 stderr 报告一次进度
+const PROGRESS_EVERY: u64 = 100_000;
+/// shell 里 `scan` 不给个数时列出多少个
+const SHELL_SCAN_LIMIT: u32 = 20;
+/// shell 的命令和用法，`help` 和 tab 补全都用这张表
+const SHELL_COMMANDS: &[(&str, &str)] = &[
+    ("get", "get KEY"),
+    ("set", "set KEY VALUE"),
+    ("rm", "rm KEY"),
+    ("mget", "mget KEY..."),
+    ("mset", "mset KEY VALUE..."),
+    ("mrm", "mrm KEY..."),
+    ("scan", "scan [AFTER [LIMIT]]"),
+    ("timing", "timing on|off"),
+    ("help", "help"),
+    ("exit", "exit"),
+];
+/// bench 预先写入 key 时一次 mset 多少个
+const PRELOAD_BATCH: usize = 1000;
+/// 延迟直方图把每个 2 的幂分成 2^6 格，误差不到 2%
+const SUB_BUCKET_BITS: u32 = 6;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
 
 #[derive(Parser, Debug)]
 #[clap(name = "kvs-client", about = "A kv store", long_about = "this is long about", author, version)]
@@ -14,6 +59,48 @@ const ADDRESS_FORMAT: &str = "IP:PORT";
 struct Opt {
     #[clap(name = "subcommand", subcommand)]
     command: Command,
+    #[clap(
+        long,
+        global = true,
+        value_name = "PEM_FILE",
+        help = "Connects over TLS, trusting servers signed by this CA"
+    )]
+    tls_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        value_name = "PEM_FILE",
+        help = "Sets the client certificate for servers requiring one",
+        requires_all = &["tls-ca", "tls-key"]
+    )]
+    tls_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        value_name = "PEM_FILE",
+        help = "Sets the private key of the client certificate",
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        global = true,
+        value_name = "NAME",
+        help = "Sets the name expected in the server certificate, localhost by default",
+        requires = "tls-ca"
+    )]
+    tls_server_name: Option<String>,
+    #[clap(long, global = true, help = "Authenticates as this user", requires = "password")]
+    user: Option<String>,
+    #[clap(long, global = true, help = "Sets the password of the user", requires = "user")]
+    password: Option<String>,
+    #[clap(
+        long,
+        global = true,
+        help = "Authenticates with a token",
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -58,6 +145,272 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[clap(name = "mget", about = "Get the values of several keys, one per line")]
+    MGet {
+        #[clap(name = "KEY", required = true, help = "String keys")]
+        keys: Vec<String>,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "mset", about = "Set several keys at once")]
+    MSet {
+        #[clap(name = "KEY VALUE", required = true, help = "Keys each followed by its value")]
+        pairs: Vec<String>,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(name = "mrm", about = "Remove several keys at once")]
+    MRemove {
+        #[clap(name = "KEY", required = true, help = "String keys")]
+        keys: Vec<String>,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "export",
+        about = "Write all the key/value pairs as JSON Lines or CSV"
+    )]
+    Export {
+        #[clap(name = "FILE", help = "Writes to this file instead of stdout")]
+        output: Option<PathBuf>,
+        #[clap(
+            arg_enum,
+            long,
+            default_value = "jsonl",
+            help = "Sets the format of the file"
+        )]
+        format: Format,
+        #[clap(
+            long,
+            value_name = "DIR",
+            help = "Reads the data directory of a stopped server instead of asking the server"
+        )]
+        dir: Option<PathBuf>,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "import",
+        about = "Set the key/value pairs of a JSON Lines or CSV file"
+    )]
+    Import {
+        #[clap(name = "FILE", help = "Reads this file instead of stdin")]
+        input: Option<PathBuf>,
+        #[clap(
+            arg_enum,
+            long,
+            default_value = "jsonl",
+            help = "Sets the format of the file"
+        )]
+        format: Format,
+        #[clap(
+            long,
+            value_name = "DIR",
+            help = "Writes to the data directory of a stopped server instead of to the server"
+        )]
+        dir: Option<PathBuf>,
+        #[clap(
+            long,
+            default_value = "1000",
+            validator = positive,
+            help = "Sets how many pairs are set at once"
+        )]
+        batch_size: usize,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "shell",
+        about = "Run commands on one connection, from a prompt or a script on stdin"
+    )]
+    Shell {
+        #[clap(long, help = "Doesn't print how long each request took")]
+        no_timing: bool,
+        #[clap(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+        )]
+        addr: SocketAddr,
+    },
+    #[clap(
+        name = "bench",
+        about = "Load the server from many connections and report the throughput and latencies"
+    )]
+    Bench(BenchOptions),
+}
+
+#[derive(Args, Debug)]
+struct BenchOptions {
+    #[clap(
+        long,
+        default_value = "4",
+        validator = positive,
+        help = "Sets how many connections send requests at the same time"
+    )]
+    connections: usize,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        validator = positive,
+        help = "Sets how long to send requests"
+    )]
+    duration: u64,
+    #[clap(
+        long,
+        value_name = "GET:SET:RM",
+        default_value = "80:15:5",
+        help = "Sets the weights of gets, sets and removes"
+    )]
+    mix: Mix,
+    #[clap(
+        long,
+        default_value = "10000",
+        validator = positive,
+        help = "Sets how many different keys are used"
+    )]
+    keys: usize,
+    #[clap(
+        arg_enum,
+        long,
+        default_value = "uniform",
+        help = "Sets how the key of each request is picked"
+    )]
+    distribution: Distribution,
+    #[clap(
+        long,
+        default_value = "0.99",
+        validator = non_negative,
+        help = "Sets the exponent of the zipfian distribution, higher is more skewed"
+    )]
+    zipf_exponent: f64,
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value = "100",
+        help = "Sets the size of the values set"
+    )]
+    value_size: usize,
+    #[clap(long, help = "Doesn't set every key before sending requests")]
+    no_preload: bool,
+    #[clap(
+        long,
+        help = "Sets the server address",
+        value_name = ADDRESS_FORMAT,
+        default_value = DEFAULT_LISTENING_ADDRESS,
+    )]
+    addr: SocketAddr,
+}
+
+/// The weights of each kind of request.
+#[derive(Debug, Clone, Copy)]
+struct Mix {
+    get: u32,
+    set: u32,
+    remove: u32,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Get = 0,
+    Set = 1,
+    Remove = 2,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl From<Format> for DumpFormat {
+    fn from(format: Format) -> DumpFormat {
+        match format {
+            Format::Jsonl => DumpFormat::JsonLines,
+            Format::Csv => DumpFormat::Csv,
+        }
+    }
+}
+
+fn positive(s: &str) -> std::result::Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("must be a positive number".to_owned()),
+    }
+}
+
+fn non_negative(s: &str) -> std::result::Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(n) if n.is_finite() && n >= 0.0 => Ok(()),
+        _ => Err("must be a number that is 0 or more".to_owned()),
+    }
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Mix, String> {
+        let invalid = || "expected the weights of gets, sets and removes, like 80:15:5".to_owned();
+        let weights = s
+            .split(':')
+            .map(str::parse)
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map_err(|_| invalid())?;
+        match weights[..] {
+            // 加起来溢出的话 `pick` 就没法算了
+            [get, set, remove] => match get.checked_add(set).and_then(|n| n.checked_add(remove)) {
+                Some(total) if total > 0 => Ok(Mix { get, set, remove }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Mix {
+    fn pick(self, rng: &mut impl Rng) -> Op {
+        let n = rng.gen_range(0..self.get + self.set + self.remove);
+        if n < self.get {
+            Op::Get
+        } else if n < self.get + self.set {
+            Op::Set
+        } else {
+            Op::Remove
+        }
+    }
 }
 
 fn main() {
@@ -68,24 +421,823 @@ fn main() {
     }
 }
 
+/// 每次连接都要用到的 TLS 和认证设置
+struct Connector {
+    tls: Option<ClientTls>,
+    credentials: Option<Credentials>,
+}
+
 fn run(opt: Opt) -> Result<()> {
+    let connector = Connector {
+        tls: client_tls(&opt)?,
+        credentials: credentials(&opt),
+    };
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
+            if let Some(value) = connector.with_leader(addr, |client| client.get(key.clone()))? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         },
         Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            connector.with_leader(addr, |client| client.set(key.clone(), value.clone()))?;
         },
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.remove(key)?;
+            connector.with_leader(addr, |client| client.remove(key.clone()))?;
+        },
+        Command::MGet { keys, addr } => {
+            for value in connector.with_leader(addr, |client| client.mget(keys.clone()))? {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+            }
+        },
+        Command::MSet { pairs, addr } => {
+            if pairs.len() % 2 != 0 {
+                return Err(KvsError::StringError(format!(
+                    "Missing the value of {}",
+                    pairs[pairs.len() - 1]
+                )));
+            }
+            let pairs: Vec<_> = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            connector.with_leader(addr, |client| client.mset(pairs.clone()))?;
+        },
+        Command::MRemove { keys, addr } => {
+            // 其他的 key 照样删掉，最后报告哪些 key 不存在
+            connector.with_leader(addr, |client| {
+                let removed = client.mremove(keys.clone())?;
+                let missing: Vec<_> = keys
+                    .iter()
+                    .zip(removed)
+                    .filter(|(_, removed)| !removed)
+                    .map(|(key, _)| key.as_str())
+                    .collect();
+                if missing.is_empty() {
+                    Ok(())
+                } else {
+                    Err(KvsError::StringError(format!(
+                        "Key not found: {}",
+                        missing.join(", ")
+                    )))
+                }
+            })?;
+        },
+        Command::Export {
+            output,
+            format,
+            dir,
+            addr,
+        } => {
+            let output: Box<dyn Write> = match &output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            let mut writer = DumpWriter::new(BufWriter::new(output), format.into())?;
+            let exported = match &dir {
+                Some(dir) => {
+                    // 只读不写，服务器还开着的话拒绝
+                    let _lock = DirLock::acquire(dir)?;
+                    if is_sled(dir)? {
+                        let engine = SledKvsEngine::new(sled::open(dir)?);
+                        export(&mut writer, |after| engine.scan_after(after, EXPORT_PAGE))
+                    } else {
+                        let engine = KvStore::open_read_only(dir)?;
+                        export(&mut writer, |after| engine.scan_after(after, EXPORT_PAGE))
+                    }
+                }
+                None => {
+                    // raft 集群里只有 leader 能读，跟着重定向
+                    let mut client = connector.connect(&addr.to_string())?;
+                    export(&mut writer, |after| {
+                        for _ in 0..MAX_REDIRECTS {
+                            match client.scan(after.clone(), EXPORT_PAGE as u32) {
+                                Err(KvsError::NotLeader(leader)) => {
+                                    client = connector.connect(&leader)?
+                                }
+                                res => return res,
+                            }
+                        }
+                        Err(KvsError::StringError("Too many redirects".to_owned()))
+                    })
+                }
+            }?;
+            writer.finish()?;
+            eprintln!("Exported {} pairs", exported);
+        },
+        Command::Import {
+            input,
+            format,
+            dir,
+            batch_size,
+            addr,
+        } => {
+            let input: Box<dyn Read> = match &input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(io::stdin()),
+            };
+            let reader = DumpReader::new(input, format.into());
+            let imported = match &dir {
+                Some(dir) => {
+                    fs::create_dir_all(dir)?;
+                    let _lock = DirLock::acquire(dir)?;
+                    if is_sled(dir)? {
+                        import_into(&SledKvsEngine::new(sled::open(dir)?), reader, batch_size)
+                    } else {
+                        // 新目录也写上 engine 文件，kvs-server 才不会用别的引擎打开它
+                        let marker = dir.join("engine");
+                        if !marker.exists() {
+                            fs::write(marker, "kvs")?;
+                        }
+                        import_into(&KvStore::open(dir)?, reader, batch_size)
+                    }
+                }
+                None => {
+                    // 一直用同一个连接，被重定向之后就留在 leader 上
+                    let mut client = connector.connect(&addr.to_string())?;
+                    import(reader, batch_size, |batch| {
+                        for _ in 0..MAX_REDIRECTS {
+                            match client.mset(batch.clone()) {
+                                Err(KvsError::NotLeader(leader)) => {
+                                    client = connector.connect(&leader)?
+                                }
+                                res => return res,
+                            }
+                        }
+                        Err(KvsError::StringError("Too many redirects".to_owned()))
+                    })
+                }
+            }?;
+            eprintln!("Imported {} pairs", imported);
+        },
+        Command::Shell { no_timing, addr } => {
+            let mut shell = Shell {
+                connector: &connector,
+                addr: addr.to_string(),
+                client: None,
+                timing: !no_timing,
+            };
+            // 先连一次，地址不对的话马上就能知道
+            shell.client = Some(connector.connect(&shell.addr)?);
+            if atty::is(atty::Stream::Stdin) {
+                shell.interactive()?;
+            } else {
+                shell.script(io::stdin().lock())?;
+            }
         },
+        Command::Bench(options) => bench(&connector, &options)?,
+    }
+    Ok(())
+}
+
+/// 跟 kvs-server 一样看数据目录里的 engine 文件，没有的话就是 kvs
+fn is_sled(dir: &Path) -> Result<bool> {
+    let marker = dir.join("engine");
+    if !marker.exists() {
+        return Ok(false);
+    }
+    match fs::read_to_string(marker)?.trim() {
+        "kvs" => Ok(false),
+        "sled" => Ok(true),
+        engine => Err(KvsError::StringError(format!(
+            "{} is stored with an unknown engine {}",
+            dir.display(),
+            engine
+        ))),
+    }
+}
+
+/// 一页一页地读，以上一页最后一个 key 为起点，读到空页为止
+fn export<W, F>(writer: &mut DumpWriter<W>, mut next_page: F) -> Result<u64>
+where
+    W: Write,
+    F: FnMut(Option<String>) -> Result<Vec<(String, String)>>,
+{
+    let mut after = None;
+    let mut exported = 0;
+    loop {
+        let page = next_page(after.take())?;
+        if page.is_empty() {
+            return Ok(exported);
+        }
+        for (key, value) in &page {
+            writer.write(key, value)?;
+        }
+        let before = exported;
+        exported += page.len() as u64;
+        progress("Exported", before, exported);
+        after = page.into_iter().last().map(|(key, _)| key);
+    }
+}
+
+fn import_into<E: KvsEngine, R: Read>(
+    engine: &E,
+    reader: DumpReader<R>,
+    batch_size: usize,
+) -> Result<u64> {
+    let imported = import(reader, batch_size, |batch| engine.set_many(batch))?;
+    engine.flush()?;
+    Ok(imported)
+}
+
+/// 攒够一批再写，前面写进去的批次在出错时不会撤回
+fn import<R, F>(reader: DumpReader<R>, batch_size: usize, mut write: F) -> Result<u64>
+where
+    R: Read,
+    F: FnMut(Vec<(String, String)>) -> Result<()>,
+{
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for pair in reader {
+        batch.push(pair?);
+        if batch.len() == batch_size {
+            let before = imported;
+            imported += batch.len() as u64;
+            write(mem::replace(&mut batch, Vec::with_capacity(batch_size)))?;
+            progress("Imported", before, imported);
+        }
+    }
+    if !batch.is_empty() {
+        imported += batch.len() as u64;
+        write(batch)?;
+    }
+    Ok(imported)
+}
+
+/// 每跨过一个 `PROGRESS_EVERY` 报告一次
+fn progress(verb: &str, before: u64, now: u64) {
+    if before / PROGRESS_EVERY != now / PROGRESS_EVERY {
+        eprintln!("{} {} pairs...", verb, now);
+    }
+}
+
+/// 一个连接上的一串命令
+struct Shell<'a> {
+    connector: &'a Connector,
+    addr: String,
+    /// 连接断了之后是 `None`，下一条命令再重连
+    client: Option<KvsClient>,
+    timing: bool,
+}
+
+impl Shell<'_> {
+    fn interactive(&mut self) -> Result<()> {
+        let mut editor = Editor::<ShellHelper>::new();
+        editor.set_helper(Some(ShellHelper));
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+        if let Some(history) = &history {
+            // 第一次用的时候还没有历史文件
+            let _ = editor.load_history(history);
+        }
+        loop {
+            let line = match editor.readline(&format!("{}> ", self.addr)) {
+                Ok(line) => line,
+                // Ctrl-C 只丢掉正在输入的这一行
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(KvsError::StringError(e.to_string())),
+            };
+            if !line.trim().is_empty() {
+                editor.add_history_entry(line.as_str());
+            }
+            match self.execute(&line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        if let Some(history) = &history {
+            if let Err(e) = editor.save_history(history) {
+                eprintln!("Failed to save the history: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// 出错的命令报告行号之后接着往下跑，最后再以失败退出
+    fn script(&mut self, input: impl BufRead) -> Result<()> {
+        let mut failed = 0;
+        for (number, line) in input.lines().enumerate() {
+            match self.execute(&line?) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("Line {}: {}", number + 1, e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(KvsError::StringError(format!("{} commands failed", failed)));
+        }
+        Ok(())
+    }
+
+    /// Runs one line, `false` when the shell should exit.
+    fn execute(&mut self, line: &str) -> Result<bool> {
+        if line.trim_start().starts_with('#') {
+            return Ok(true);
+        }
+        let words = split_words(line)?;
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.as_str(), args),
+            None => return Ok(true),
+        };
+        match (command, args) {
+            ("exit" | "quit", []) => return Ok(false),
+            ("help", []) => {
+                for (_, usage) in SHELL_COMMANDS {
+                    println!("{}", usage);
+                }
+            }
+            ("timing", [on]) if on == "on" || on == "off" => self.timing = on == "on",
+            _ => {
+                let start = Instant::now();
+                let output = self.request(command, args)?;
+                let elapsed = start.elapsed();
+                for line in output {
+                    println!("{}", line);
+                }
+                // 计时写到 stderr，脚本的输出里只有结果
+                if self.timing {
+                    eprintln!("({:.3} ms)", elapsed.as_secs_f64() * 1000.0);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn request(&mut self, command: &str, args: &[String]) -> Result<Vec<String>> {
+        let not_found = || "Key not found".to_owned();
+        match (command, args) {
+            ("get", [key]) => {
+                self.call(|client| Ok(vec![client.get(key.clone())?.unwrap_or_else(not_found)]))
+            }
+            ("set", [key, value]) => self.call(|client| {
+                client.set(key.clone(), value.clone())?;
+                Ok(vec!["OK".to_owned()])
+            }),
+            ("rm", [key]) => self.call(|client| {
+                client.remove(key.clone())?;
+                Ok(vec!["OK".to_owned()])
+            }),
+            ("mget", keys) if !keys.is_empty() => self.call(|client| {
+                let values = client.mget(keys.to_vec())?;
+                Ok(values
+                    .into_iter()
+                    .map(|value| value.unwrap_or_else(not_found))
+                    .collect())
+            }),
+            ("mset", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let pairs: Vec<_> = pairs
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                self.call(|client| {
+                    client.mset(pairs.clone())?;
+                    Ok(vec!["OK".to_owned()])
+                })
+            }
+            ("mrm", keys) if !keys.is_empty() => self.call(|client| {
+                let removed = client.mremove(keys.to_vec())?;
+                let removed = removed.into_iter().filter(|&removed| removed).count();
+                Ok(vec![format!("Removed {}", removed)])
+            }),
+            ("scan", args) if args.len() <= 2 => {
+                let limit = match args.get(1) {
+                    Some(limit) => limit
+                        .parse()
+                        .map_err(|_| KvsError::StringError(format!("Invalid limit {}", limit)))?,
+                    None => SHELL_SCAN_LIMIT,
+                };
+                self.call(|client| {
+                    let pairs = client.scan(args.first().cloned(), limit)?;
+                    Ok(pairs
+                        .into_iter()
+                        .map(|(key, value)| format!("{}\t{}", key, value))
+                        .collect())
+                })
+            }
+            _ => match SHELL_COMMANDS.iter().find(|(name, _)| *name == command) {
+                Some((_, usage)) => Err(KvsError::StringError(format!("Usage: {}", usage))),
+                None => Err(KvsError::StringError(format!(
+                    "Unknown command {}, try help",
+                    command
+                ))),
+            },
+        }
+    }
+
+    /// 请求跟着重定向去 leader，之后的命令也留在 leader 上
+    fn call<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        for _ in 0..MAX_REDIRECTS {
+            let client = match self.client.take() {
+                Some(client) => client,
+                None => self.connector.connect(&self.addr)?,
+            };
+            let client = self.client.insert(client);
+            match f(client) {
+                Err(KvsError::NotLeader(leader)) => {
+                    self.addr = leader;
+                    self.client = None;
+                }
+                // 服务器可能重启了，下一条命令重新连接
+                Err(KvsError::Io(e)) => {
+                    self.client = None;
+                    return Err(KvsError::Io(e));
+                }
+                res => return res,
+            }
+        }
+        Err(KvsError::StringError("Too many redirects".to_owned()))
+    }
+}
+
+/// 按空白分词，双引号里的空白不分开，反斜杠转义下一个字符
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                // `""` 是一个空字符串
+                word.get_or_insert_with(String::new);
+            }
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(c) => c,
+                    None => return Err(KvsError::StringError("Trailing backslash".to_owned())),
+                };
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(KvsError::StringError("Unclosed quote".to_owned()));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn bench(connector: &Connector, options: &BenchOptions) -> Result<()> {
+    let addr = options.addr.to_string();
+    if !options.no_preload {
+        eprintln!("Setting {} keys...", options.keys);
+        preload(connector.connect(&addr)?, options.keys, options.value_size)?;
+    }
+    // 连接先建好，握手和认证的时间不算进去
+    let clients = (0..options.connections)
+        .map(|_| connector.connect(&addr))
+        .collect::<Result<Vec<_>>>()?;
+    let keys = Arc::new(KeyPicker::new(options));
+
+    eprintln!(
+        "Sending requests from {} connections for {}s...",
+        options.connections, options.duration
+    );
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(options.duration);
+    let workers: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            let keys = keys.clone();
+            let (mix, value_size) = (options.mix, options.value_size);
+            thread::spawn(move || bench_connection(client, mix, &keys, value_size, deadline))
+        })
+        .collect();
+    let mut stats = BenchStats::new();
+    for worker in workers {
+        let worker = worker
+            .join()
+            .map_err(|_| KvsError::StringError("A bench connection panicked".to_owned()))?;
+        stats.merge(&worker);
+    }
+    stats.report(started.elapsed());
+    Ok(())
+}
+
+/// 先把每个 key 写一遍，不然 get 几乎都是 key 不存在
+fn preload(mut client: KvsClient, keys: usize, value_size: usize) -> Result<()> {
+    let value = random_value(value_size);
+    for start in (0..keys).step_by(PRELOAD_BATCH) {
+        let pairs = (start..keys.min(start + PRELOAD_BATCH))
+            .map(|i| (format!("key{}", i), value.clone()))
+            .collect();
+        client.mset(pairs)?;
     }
     Ok(())
+}
+
+fn bench_connection(
+    mut client: KvsClient,
+    mix: Mix,
+    keys: &KeyPicker,
+    value_size: usize,
+    deadline: Instant,
+) -> BenchStats {
+    let mut rng = rand::thread_rng();
+    let value = random_value(value_size);
+    let mut stats = BenchStats::new();
+    while Instant::now() < deadline {
+        let op = mix.pick(&mut rng);
+        let key = format!("key{}", keys.pick(&mut rng));
+        let start = Instant::now();
+        let res = match op {
+            Op::Get => client.get(key).map(drop),
+            Op::Set => client.set(key, value.clone()),
+            // 删掉不存在的 key 也是正常的响应
+            Op::Remove => match client.remove(key) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                res => res,
+            },
+        };
+        let elapsed = start.elapsed();
+        match res {
+            Ok(()) => stats.latencies[op as usize].record(elapsed),
+            // 连接断了，这个连接没法接着测，其他连接的结果照样报告
+            Err(KvsError::Io(e)) => {
+                stats.errors[op as usize] += 1;
+                eprintln!("A bench connection failed: {}", e);
+                break;
+            }
+            Err(_) => stats.errors[op as usize] += 1,
+        }
+    }
+    stats
+}
+
+fn random_value(size: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(size)
+        .map(char::from)
+        .collect()
+}
+
+/// Picks the key of each request, `key0` is the hottest one of the zipfian distribution.
+enum KeyPicker {
+    Uniform(usize),
+    /// 每个 key 的累积概率，二分查找一个随机数落在哪
+    Zipfian(Vec<f64>),
+}
+
+impl KeyPicker {
+    fn new(options: &BenchOptions) -> Self {
+        match options.distribution {
+            Distribution::Uniform => KeyPicker::Uniform(options.keys),
+            Distribution::Zipfian => {
+                let mut sum = 0.0;
+                let mut cdf: Vec<f64> = (1..=options.keys)
+                    .map(|rank| {
+                        sum += 1.0 / (rank as f64).powf(options.zipf_exponent);
+                        sum
+                    })
+                    .collect();
+                cdf.iter_mut().for_each(|p| *p /= sum);
+                KeyPicker::Zipfian(cdf)
+            }
+        }
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            KeyPicker::Uniform(keys) => rng.gen_range(0..*keys),
+            KeyPicker::Zipfian(cdf) => {
+                let p: f64 = rng.gen();
+                cdf.partition_point(|&c| c < p).min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+/// The latencies of the successful requests and the number of errors, by `Op`.
+struct BenchStats {
+    latencies: [Histogram; 3],
+    errors: [u64; 3],
+}
+
+impl BenchStats {
+    fn new() -> Self {
+        BenchStats {
+            latencies: [Histogram::new(), Histogram::new(), Histogram::new()],
+            errors: [0; 3],
+        }
+    }
+
+    fn merge(&mut self, other: &BenchStats) {
+        for (latencies, other) in self.latencies.iter_mut().zip(&other.latencies) {
+            latencies.merge(other);
+        }
+        for (errors, other) in self.errors.iter_mut().zip(&other.errors) {
+            *errors += other;
+        }
+    }
+
+    fn report(&self, elapsed: Duration) {
+        let mut all = Histogram::new();
+        for latencies in &self.latencies {
+            all.merge(latencies);
+        }
+        let errors: u64 = self.errors.iter().sum();
+        println!(
+            "{} requests in {:.2}s, {:.1} requests/s, {} errors",
+            all.count() + errors,
+            elapsed.as_secs_f64(),
+            (all.count() + errors) as f64 / elapsed.as_secs_f64(),
+            errors
+        );
+        println!(
+            "{:<4} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "op", "requests", "errors", "p50 ms", "p99 ms", "p999 ms", "max ms"
+        );
+        let ops = ["get", "set", "rm"];
+        for ((op, latencies), errors) in ops.iter().zip(&self.latencies).zip(self.errors) {
+            // 权重是 0 的请求不列出来
+            if latencies.count() + errors > 0 {
+                latencies.print_row(op, errors);
+            }
+        }
+        all.print_row("all", errors);
+    }
+}
+
+/// 固定大小的延迟直方图，跑多久都不会多占内存
+struct Histogram {
+    counts: Vec<u64>,
+    max: Duration,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            counts: vec![0; bucket(u64::MAX) + 1],
+            max: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        self.counts[bucket(latency.as_nanos() as u64)] += 1;
+        self.max = self.max.max(latency);
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.max = self.max.max(other.max);
+    }
+
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The lower bound of the bucket the `q` quantile falls in.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = ((q * self.count() as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(lower_bound(index));
+            }
+        }
+        self.max
+    }
+
+    fn print_row(&self, op: &str, errors: u64) {
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        println!(
+            "{:<4} {:>10} {:>8} {:>10.3} {:>10.3} {:>10.3} {:>10.3}",
+            op,
+            self.count(),
+            errors,
+            ms(self.quantile(0.5)),
+            ms(self.quantile(0.99)),
+            ms(self.quantile(0.999)),
+            ms(self.max)
+        );
+    }
+}
+
+/// 小于 `2 * SUB_BUCKETS` 纳秒的每个值一格，再往上按最高位的位置和后面几位分格
+fn bucket(nanos: u64) -> usize {
+    if nanos < 2 * SUB_BUCKETS {
+        return nanos as usize;
+    }
+    let shift = (63 - nanos.leading_zeros() - SUB_BUCKET_BITS) as u64;
+    (shift * SUB_BUCKETS + (nanos >> shift)) as usize
+}
+
+fn lower_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2 * SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    (index % SUB_BUCKETS + SUB_BUCKETS) << shift
+}
+
+/// 补全命令名，参数是 key，不补全
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = line.len() - line.trim_start().len();
+        let word = &line[start..];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = SHELL_COMMANDS
+            .iter()
+            .filter(|(name, _)| name.starts_with(word))
+            .map(|(name, usage)| Pair {
+                display: usage.to_string(),
+                replacement: format!("{} ", name),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn client_tls(opt: &Opt) -> Result<Option<ClientTls>> {
+    let ca = match &opt.tls_ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let mut tls = ClientTls::new(ca)?;
+    if let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) {
+        tls.set_identity(cert, key)?;
+    }
+    if let Some(name) = &opt.tls_server_name {
+        tls.set_server_name(name)?;
+    }
+    Ok(Some(tls))
+}
+
+fn credentials(opt: &Opt) -> Option<Credentials> {
+    match (&opt.user, &opt.password, &opt.token) {
+        (Some(user), Some(password), _) => Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        (_, _, Some(token)) => Some(Credentials::Token(token.clone())),
+        _ => None,
+    }
+}
+
+impl Connector {
+    fn connect(&self, addr: &str) -> Result<KvsClient> {
+        let mut client = match &self.tls {
+            Some(tls) => KvsClient::connect_tls(addr, tls)?,
+            None => KvsClient::connect(addr)?,
+        };
+        if let Some(credentials) = &self.credentials {
+            client.auth(credentials.clone())?;
+        }
+        Ok(client)
+    }
+
+    fn with_leader<T, F>(&self, addr: SocketAddr, f: F) -> Result<T>
+    where
+        F: Fn(&mut KvsClient) -> Result<T>,
+    {
+        let mut addr = addr.to_string();
+        for _ in 0..MAX_REDIRECTS {
+            let mut client = self.connect(&addr)?;
+            match f(&mut client) {
+                Err(KvsError::NotLeader(leader)) => addr = leader,
+                res => return res,
+            }
+        }
+        Err(KvsError::StringError("Too many redirects".to_owned()))
+    }
 }
\ No newline at end of file
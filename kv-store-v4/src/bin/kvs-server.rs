@@ -1,26 +1,65 @@
-use std::{env::current_dir, fs, net::SocketAddr, process::exit, str::FromStr};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::exit,
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
 
-use clap::{ArgEnum, Parser};
-use log::{error, info, warn, LevelFilter};
+use clap::{ArgEnum, Parser, Subcommand};
+use log::{debug, error, info, warn, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
+use kvs::raft::{RaftConfig, RaftEngine};
+use kvs::replication::Follower;
+#[cfg(feature = "sync-server")]
 use kvs::thread_pool::*;
 use kvs::*;
+#[cfg(feature = "sync-server")]
+use std::thread;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const PORT_FORMAT: &str = "IP:PORT";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+/// 迁移时每次从旧引擎读出多少个 key
+const MIGRATE_PAGE: usize = 1000;
+
+/// 跑在 tokio 上的服务，HTTP gateway、metrics 和 async 的服务器
+type Service = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
 
 #[derive(Parser, Debug)]
 #[clap(name = "kvs-server", author, version, about, long_about = None)]
 struct Opt {
+    #[clap(
+        long,
+        global = true,
+        value_name = "TOML_FILE",
+        help = "Reads the settings from this file, the flags override them"
+    )]
+    config: Option<PathBuf>,
+    #[clap(long, help = "Prints the effective settings as TOML and exits")]
+    print_config: bool,
     #[clap(
         short,
         long,
         value_name = PORT_FORMAT,
-        default_value = DEFAULT_LISTENING_ADDRESS,
-        help = "Sets the listening address",
+        help = "Sets the listening address [default: 127.0.0.1:4000]",
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        global = true,
+        value_name = "DIR",
+        help = "Sets the data directory [default: the working directory]"
+    )]
+    dir: Option<PathBuf>,
     #[clap(
         arg_enum,
         long,
@@ -28,15 +67,449 @@ struct Opt {
         value_name = "ENGINE_NAME"
     )]
     engine: Option<Engine>,
+    #[clap(
+        long,
+        value_name = PORT_FORMAT,
+        help = "Runs as a read-only replica of the given leader",
+    )]
+    replica_of: Option<SocketAddr>,
+    #[clap(
+        long,
+        value_name = "PEM_FILE",
+        help = "Replicates over TLS, trusting a leader certificate signed by this CA",
+        requires = "replica-of"
+    )]
+    leader_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Authenticates to the leader as this user",
+        requires_all = &["replica-of", "leader-password"]
+    )]
+    leader_user: Option<String>,
+    #[clap(
+        long,
+        help = "Sets the password of the leader user",
+        requires = "leader-user"
+    )]
+    leader_password: Option<String>,
+    #[clap(
+        long,
+        help = "Authenticates to the leader with a token",
+        requires = "replica-of",
+        conflicts_with = "leader-user"
+    )]
+    leader_token: Option<String>,
+    #[clap(
+        long,
+        value_name = PORT_FORMAT,
+        help = "Sets the address raft peers connect to",
+        requires = "peers",
+        conflicts_with = "replica-of",
+    )]
+    raft_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        value_name = PORT_FORMAT,
+        help = "Sets the raft addresses of the other nodes, separated by commas",
+        use_value_delimiter = true,
+        requires = "raft-addr",
+    )]
+    peers: Vec<SocketAddr>,
+    #[clap(long = "async", help = "Serves clients with the tokio based async server")]
+    async_server: bool,
+    #[clap(
+        arg_enum,
+        long,
+        help = "Sets the thread pool of the sync server [default: the-book]",
+        value_name = "POOL"
+    )]
+    pool: Option<PoolType>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Sets the number of pool threads, or tokio workers with --async [default: CPUs]"
+    )]
+    threads: Option<u32>,
+    #[clap(
+        long,
+        value_name = "PEM_FILE",
+        help = "Serves clients over TLS with this certificate chain",
+        requires = "tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PEM_FILE",
+        help = "Sets the private key of the TLS certificate",
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PEM_FILE",
+        help = "Requires clients to present a certificate signed by this CA",
+        requires = "tls-cert"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "JSON_FILE",
+        help = "Requires clients to authenticate as one of the users in this file"
+    )]
+    acl: Option<PathBuf>,
+    #[clap(long, value_name = "N", help = "Rejects connections beyond this many")]
+    max_connections: Option<usize>,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Closes connections idle for this long"
+    )]
+    idle_timeout: Option<u64>,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Closes connections taking longer to send a request or read a response"
+    )]
+    request_timeout: Option<u64>,
+    #[clap(long, value_name = "BYTES", help = "Rejects larger requests")]
+    max_request_size: Option<u32>,
+    #[clap(
+        long,
+        value_name = PORT_FORMAT,
+        help = "Also serves the HTTP gateway on this address, in plaintext",
+    )]
+    http_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        value_name = PORT_FORMAT,
+        help = "Serves Prometheus metrics on this address",
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(
+        arg_enum,
+        long,
+        default_value = "kvs",
+        help = "Sets the protocol clients speak, resp for Redis clients",
+        value_name = "PROTOCOL"
+    )]
+    protocol: ClientProtocol,
+    #[clap(
+        long,
+        default_value = "info",
+        help = "Sets the log level: off, error, warn, info, debug or trace",
+        value_name = "LEVEL"
+    )]
+    log_level: LevelFilter,
+    #[clap(
+        arg_enum,
+        long,
+        default_value = "text",
+        help = "Sets the log format, json writes one object per line",
+        value_name = "FORMAT"
+    )]
+    log_format: LogFormat,
+    #[clap(
+        long,
+        value_name = "MILLIS",
+        help = "Logs a warning for requests taking longer than this"
+    )]
+    slow_request_ms: Option<u64>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Appends every set and remove to this file, one JSON object per line"
+    )]
+    audit_log: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<ServerCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServerCommand {
+    // 服务器要先停下来，数据目录的锁被服务器拿着的话直接拒绝
+    #[clap(about = "Copies the data into a new directory with another engine")]
+    Migrate {
+        #[clap(
+            arg_enum,
+            long,
+            help = "Sets the engine of the data directory",
+            value_name = "ENGINE_NAME"
+        )]
+        from: Engine,
+        #[clap(
+            arg_enum,
+            long,
+            help = "Sets the engine to migrate to",
+            value_name = "ENGINE_NAME"
+        )]
+        to: Engine,
+        #[clap(
+            long,
+            value_name = "DIR",
+            help = "Sets the new data directory, which must not exist"
+        )]
+        out: PathBuf,
+    },
+    #[clap(about = "Reads a password from stdin and prints its hash for the ACL file")]
+    HashPassword,
 }
 
 #[allow(non_camel_case_types)]
-#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 enum Engine {
     kvs,
     sled,
 }
 
+/// The thread pools of the sync server.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PoolType {
+    Naive,
+    SharedQueue,
+    Rayon,
+    TheBook,
+}
+
+/// The settings of `--config`, with the flags applied by `Config::load`.
+///
+/// 文件里没有写的用默认值，相对路径相对于工作目录
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    addr: SocketAddr,
+    dir: PathBuf,
+    engine: Option<Engine>,
+    /// 只有同步服务器用线程池，async 的服务器把 `threads` 当作 tokio 的 worker 数
+    pool: PoolType,
+    threads: u32,
+    // TOML 的表要放在普通的值后面
+    storage: StorageConfig,
+    limits: LimitsConfig,
+}
+
+/// `KvStoreOptions` in the config file, sled syncs every write and compacts by itself.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageConfig {
+    write_batch: usize,
+    sync_writes: bool,
+    compaction_threshold: u64,
+}
+
+/// `Limits` in the config file, the timeouts are in seconds.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    max_connections: usize,
+    idle_timeout: u64,
+    request_timeout: u64,
+    max_request_size: u32,
+}
+
+/// 同步和 async 的服务器共用的设置
+struct ServerSettings {
+    read_only: bool,
+    tls: Option<ServerTls>,
+    acl: Option<Acl>,
+    limits: Limits,
+    protocol: Protocol,
+    metrics: Metrics,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientProtocol {
+    Kvs,
+    Resp,
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// 审计日志写到单独的文件里，其他的日志交给 env_logger
+struct Logger {
+    inner: env_logger::Logger,
+    audit: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.target() == AUDIT_TARGET {
+            return self.audit.is_some();
+        }
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() != AUDIT_TARGET {
+            return self.inner.log(record);
+        }
+        if let Some(audit) = &self.audit {
+            let mut file = audit.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", record.args()) {
+                eprintln!("Failed to write the audit log: {}", e);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Some(audit) = &self.audit {
+            let _ = audit.lock().unwrap().flush();
+        }
+    }
+}
+
+impl From<ClientProtocol> for Protocol {
+    fn from(protocol: ClientProtocol) -> Self {
+        match protocol {
+            ClientProtocol::Kvs => Protocol::Kvs,
+            ClientProtocol::Resp => Protocol::Resp,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `--config` if given and applies the flags on top.
+    fn load(opt: &Opt) -> Result<Config> {
+        let mut config: Config = match &opt.config {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+                KvsError::StringError(format!("Invalid config {}: {}", path.display(), e))
+            })?,
+            None => Config::default(),
+        };
+        if let Some(addr) = opt.addr {
+            config.addr = addr;
+        }
+        if let Some(dir) = &opt.dir {
+            config.dir = dir.clone();
+        }
+        if opt.engine.is_some() {
+            config.engine = opt.engine;
+        }
+        if let Some(pool) = opt.pool {
+            config.pool = pool;
+        }
+        if let Some(threads) = opt.threads {
+            config.threads = threads;
+        }
+        let limits = &mut config.limits;
+        if let Some(max) = opt.max_connections {
+            limits.max_connections = max;
+        }
+        if let Some(secs) = opt.idle_timeout {
+            limits.idle_timeout = secs;
+        }
+        if let Some(secs) = opt.request_timeout {
+            limits.request_timeout = secs;
+        }
+        if let Some(size) = opt.max_request_size {
+            limits.max_request_size = size;
+        }
+        if config.threads == 0 || config.storage.write_batch == 0 {
+            return Err(KvsError::StringError(
+                "threads and write_batch must be at least 1".to_owned(),
+            ));
+        }
+        config.limits.to_limits()?;
+        Ok(config)
+    }
+
+    fn to_toml(&self) -> Result<String> {
+        toml::to_string(self)
+            .map_err(|e| KvsError::StringError(format!("Failed to print config: {}", e)))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: DEFAULT_LISTENING_ADDRESS.parse().unwrap(),
+            dir: PathBuf::from("."),
+            engine: None,
+            pool: PoolType::TheBook,
+            threads: num_cpus::get() as u32,
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl StorageConfig {
+    fn options(&self) -> KvStoreOptions {
+        KvStoreOptions {
+            write_batch: self.write_batch,
+            sync_writes: self.sync_writes,
+            compaction_threshold: self.compaction_threshold,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        let options = KvStoreOptions::default();
+        StorageConfig {
+            write_batch: options.write_batch,
+            sync_writes: options.sync_writes,
+            compaction_threshold: options.compaction_threshold,
+        }
+    }
+}
+
+impl LimitsConfig {
+    fn to_limits(&self) -> Result<Limits> {
+        if self.idle_timeout == 0 || self.request_timeout == 0 {
+            return Err(KvsError::StringError(
+                "Timeouts must be at least one second".to_owned(),
+            ));
+        }
+        // 0 个连接的服务器谁都连不上，比 64 MiB 大的请求帧协议本身就不接受
+        if self.max_connections == 0 {
+            return Err(KvsError::StringError(
+                "max_connections must be at least 1".to_owned(),
+            ));
+        }
+        if self.max_request_size == 0 || self.max_request_size > MAX_PAYLOAD_LEN {
+            return Err(KvsError::StringError(format!(
+                "max_request_size must be between 1 and {}",
+                MAX_PAYLOAD_LEN
+            )));
+        }
+        Ok(Limits {
+            max_connections: self.max_connections,
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            request_timeout: Duration::from_secs(self.request_timeout),
+            max_request_size: self.max_request_size,
+        })
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        let limits = Limits::default();
+        LimitsConfig {
+            max_connections: limits.max_connections,
+            idle_timeout: limits.idle_timeout.as_secs(),
+            request_timeout: limits.request_timeout.as_secs(),
+            max_request_size: limits.max_request_size,
+        }
+    }
+}
+
+impl fmt::Display for PoolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PoolType::Naive => write!(f, "naive"),
+            PoolType::SharedQueue => write!(f, "shared-queue"),
+            PoolType::Rayon => write!(f, "rayon"),
+            PoolType::TheBook => write!(f, "the-book"),
+        }
+    }
+}
+
 impl std::fmt::Display for Engine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
@@ -59,19 +532,38 @@ impl FromStr for Engine {
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::parse();
-    let res = current_engine().and_then(|curr_engine| {
+    let opt = Opt::parse();
+    if let Err(e) = init_logger(&opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+    if let Some(ServerCommand::HashPassword) = &opt.command {
+        if let Err(e) = print_password_hash() {
+            error!("{}", e);
+            exit(1);
+        }
+        return;
+    }
+    let res = Config::load(&opt).and_then(|mut config| {
+        if let Some(ServerCommand::Migrate { from, to, out }) = &opt.command {
+            return migrate(&config, *from, *to, out);
+        }
+        let curr_engine = current_engine(&config.dir)?;
         // 用户没有输入，尝试从文件中找出
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
+        if config.engine.is_none() {
+            config.engine = curr_engine;
         }
         // 用户输入的引擎与文件中已有的不相同，不能随便切换引擎
-        if curr_engine.is_some() && opt.engine != curr_engine {
+        if curr_engine.is_some() && config.engine != curr_engine {
             error!("Wrong engine");
             exit(1);
         }
-        run(opt)
+        config.engine = Some(config.engine.unwrap_or(DEFAULT_ENGINE));
+        if opt.print_config {
+            print!("{}", config.to_toml()?);
+            return Ok(());
+        }
+        run(&opt, config)
     });
     if let Err(e) = res {
         error!("{}", e);
@@ -79,39 +571,451 @@ fn main() {
     }
 }
 
-fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+fn run(opt: &Opt, config: Config) -> Result<()> {
+    let engine = config.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Listening on {}", config.addr);
+
+    // 复制、raft 和 HTTP 网关的连接不能在服务端开了 TLS 的时候还是明文的
+    if opt.tls_cert.is_some() && opt.raft_addr.is_some() {
+        return Err(KvsError::Tls(
+            "Raft peers talk in plaintext, --tls-cert can't be used with --raft-addr".to_owned(),
+        ));
+    }
+    if opt.tls_cert.is_some() && opt.http_addr.is_some() {
+        return Err(KvsError::Tls(
+            "The HTTP gateway is plaintext only, --tls-cert can't be used with --http-addr"
+                .to_owned(),
+        ));
+    }
+    if opt.tls_cert.is_some() && opt.replica_of.is_some() && opt.leader_ca.is_none() {
+        return Err(KvsError::Tls(
+            "A TLS replica pulls from its leader over TLS too, --replica-of needs --leader-ca"
+                .to_owned(),
+        ));
+    }
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    fs::create_dir_all(&config.dir)?;
+    // 一直拿着锁，直到服务器退出
+    let _lock = DirLock::acquire(&config.dir)?;
+    fs::write(config.dir.join("engine"), format!("{}", engine))?;
 
-    let pool = TheBookThreadPool::new(num_cpus::get() as u32)?;
+    let raft = match opt.raft_addr {
+        Some(raft_addr) => {
+            info!("Raft on {}, peers: {:?}", raft_addr, opt.peers);
+            Some(RaftConfig {
+                addr: raft_addr,
+                peers: opt.peers.clone(),
+                client_addr: config.addr,
+                dir: config.dir.join("raft"),
+            })
+        }
+        None => None,
+    };
 
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, pool, opt.addr),
-        Engine::sled => run_with_engine(
-            SledKvsEngine::new(sled::open(current_dir()?)?),
-            pool,
-            opt.addr,
-        ),
+        Engine::kvs => {
+            let store = KvStore::open_with_options(&config.dir, config.storage.options())?;
+            if let Some(leader) = opt.replica_of {
+                info!("Replicating from {}", leader);
+                let mut follower = Follower::new(store.clone(), leader)?;
+                if let Some(ca) = &opt.leader_ca {
+                    follower.set_tls(ClientTls::new(ca)?);
+                }
+                if let Some(credentials) = leader_credentials(opt) {
+                    follower.set_credentials(credentials);
+                }
+                follower.spawn()?;
+            }
+            match raft {
+                Some(raft) => run_with_engine(RaftEngine::start(store, raft)?, opt, &config, false),
+                None => run_with_engine(store, opt, &config, opt.replica_of.is_some()),
+            }
+        }
+        Engine::sled => {
+            if opt.replica_of.is_some() {
+                return Err(KvsError::ReplicationUnsupported);
+            }
+            let engine = SledKvsEngine::new(sled::open(&config.dir)?);
+            match raft {
+                Some(raft) => {
+                    run_with_engine(RaftEngine::start(engine, raft)?, opt, &config, false)
+                }
+                None => run_with_engine(engine, opt, &config, false),
+            }
+        }
     }
 }
 
-fn run_with_engine<E: KvsEngine, P: ThreadPool>(
+fn run_with_engine<E: KvsEngine>(
     engine: E,
-    pool: P,
+    opt: &Opt,
+    config: &Config,
+    read_only: bool,
+) -> Result<()> {
+    let acl = match &opt.acl {
+        Some(path) => {
+            info!("Loading users from {}", path.display());
+            Some(Acl::load(path)?)
+        }
+        None => None,
+    };
+    let settings = ServerSettings {
+        read_only,
+        tls: server_tls(opt)?,
+        acl,
+        limits: config.limits.to_limits()?,
+        protocol: opt.protocol.into(),
+        metrics: Metrics::new(),
+    };
+    if let Some(millis) = opt.slow_request_ms {
+        settings
+            .metrics
+            .set_slow_request_threshold(Duration::from_millis(millis));
+    }
+    // 服务器之外的服务，和服务器一起关闭
+    let mut services: Vec<Service> = Vec::new();
+    let mut handles = Vec::new();
+    if let Some(addr) = opt.http_addr {
+        info!("HTTP gateway on {}", addr);
+        let mut gateway = HttpGateway::new(engine.clone());
+        gateway.set_read_only(read_only);
+        if let Some(acl) = &settings.acl {
+            gateway.set_acl(acl.clone());
+        }
+        gateway.set_limits(settings.limits);
+        gateway.set_metrics(settings.metrics.clone());
+        handles.push(gateway.shutdown_handle());
+        services.push(Box::pin(gateway.run(addr)));
+    }
+    if let Some(addr) = opt.metrics_addr {
+        info!("Metrics on {}", addr);
+        let metrics_server = MetricsServer::new(engine.clone(), settings.metrics.clone());
+        handles.push(metrics_server.shutdown_handle());
+        services.push(Box::pin(metrics_server.run(addr)));
+    }
+    // 没有编译同步服务器的话，只能用 async 的
+    #[cfg(feature = "sync-server")]
+    if !opt.async_server {
+        info!(
+            "Thread pool: {} with {} threads",
+            config.pool, config.threads
+        );
+        let threads = config.threads;
+        let addr = config.addr;
+        return match config.pool {
+            PoolType::Naive => {
+                let server = KvsServer::new(engine, NaiveThreadPool::new(threads)?);
+                run_sync(server, settings, services, handles, addr)
+            }
+            PoolType::SharedQueue => {
+                let server = KvsServer::new(engine, SharedQueueThreadPool::new(threads)?);
+                run_sync(server, settings, services, handles, addr)
+            }
+            PoolType::Rayon => {
+                let server = KvsServer::new(engine, RayonThreadPool::new(threads)?);
+                run_sync(server, settings, services, handles, addr)
+            }
+            PoolType::TheBook => {
+                let server = KvsServer::new(engine, TheBookThreadPool::new(threads)?);
+                run_sync(server, settings, services, handles, addr)
+            }
+        };
+    }
+
+    let mut server = AsyncKvsServer::new(engine);
+    server.set_read_only(settings.read_only);
+    if let Some(tls) = settings.tls {
+        server.set_tls(tls);
+    }
+    if let Some(acl) = settings.acl {
+        server.set_acl(acl);
+    }
+    server.set_limits(settings.limits);
+    server.set_protocol(settings.protocol);
+    server.set_metrics(settings.metrics);
+    handles.push(server.shutdown_handle());
+    handle_signals(handles.clone())?;
+    services.push(Box::pin(server.run(config.addr)));
+    // async 的服务器没有线程池，线程数用作 tokio 的 worker 数
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.threads as usize)
+        .enable_all()
+        .build()?;
+    rt.block_on(run_services(services, handles))
+}
+
+/// 线程池是泛型参数，每种线程池各实例化一份
+#[cfg(feature = "sync-server")]
+fn run_sync<E: KvsEngine, P: ThreadPool>(
+    mut server: KvsServer<E, P>,
+    settings: ServerSettings,
+    services: Vec<Service>,
+    mut handles: Vec<ShutdownHandle>,
     addr: SocketAddr,
 ) -> Result<()> {
-    let mut server = KvsServer::new(engine, pool);
-    server.run(addr)
+    server.set_read_only(settings.read_only);
+    if let Some(tls) = settings.tls {
+        server.set_tls(tls);
+    }
+    if let Some(acl) = settings.acl {
+        server.set_acl(acl);
+    }
+    server.set_limits(settings.limits);
+    server.set_protocol(settings.protocol);
+    server.set_metrics(settings.metrics);
+    handles.push(server.shutdown_handle());
+    handle_signals(handles.clone())?;
+    // 同步服务器没有 tokio 的 runtime，其他的服务在自己的线程里跑
+    let services = match services.is_empty() {
+        true => None,
+        false => {
+            let handles = handles.clone();
+            Some(thread::spawn(move || {
+                tokio::runtime::Runtime::new()
+                    .map_err(KvsError::from)
+                    .and_then(|rt| rt.block_on(run_services(services, handles)))
+            }))
+        }
+    };
+    let res = server.run(addr);
+    if res.is_err() {
+        for handle in &handles {
+            handle.shutdown();
+        }
+    }
+    let services = match services {
+        Some(services) => services
+            .join()
+            .unwrap_or_else(|_| Err(KvsError::StringError("Service thread panicked".to_owned()))),
+        None => Ok(()),
+    };
+    res.and(services)
+}
+
+/// Copies every key of `config.dir` into `out` with the engine `to`.
+///
+/// 先写到一个临时目录，数量核对无误、写好 engine 文件之后再整个改名成 `out`，
+/// 中途失败的话 `out` 不会出现。服务器还开着的话直接拒绝。kvs 的旧目录只读不写，
+/// sled 0.34 没有只读模式，只能读写打开，它可能会顺手整理自己的文件，但不会改动数据
+fn migrate(config: &Config, from: Engine, to: Engine, out: &Path) -> Result<()> {
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "The data is already stored with {}",
+            to
+        )));
+    }
+    let _lock = DirLock::acquire(&config.dir)?;
+    if let Some(engine) = current_engine(&config.dir)? {
+        if engine != from {
+            return Err(KvsError::StringError(format!(
+                "{} is stored with {}, not {}",
+                config.dir.display(),
+                engine,
+                from
+            )));
+        }
+    }
+    if out.exists() {
+        return Err(KvsError::StringError(format!(
+            "{} already exists",
+            out.display()
+        )));
+    }
+    let mut staging = out.as_os_str().to_owned();
+    staging.push(".migrating");
+    let staging = PathBuf::from(staging);
+    // 上次失败留下的临时目录
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    info!(
+        "Migrating {} from {} to {} in {}",
+        config.dir.display(),
+        from,
+        to,
+        out.display()
+    );
+    // 引擎在这一句结束时 drop，数据都落盘之后才改名
+    let copied = match from {
+        Engine::kvs => copy_all(
+            &KvStore::open_read_only(&config.dir)?,
+            &SledKvsEngine::new(sled::open(&staging)?),
+        ),
+        // sled 0.34 不能只读打开
+        Engine::sled => copy_all(
+            &SledKvsEngine::new(sled::open(&config.dir)?),
+            &KvStore::open_with_options(&staging, config.storage.options())?,
+        ),
+    }?;
+
+    let marker = staging.join("engine.tmp");
+    fs::write(&marker, format!("{}", to))?;
+    fs::rename(&marker, staging.join("engine"))?;
+    fs::rename(&staging, out)?;
+    info!("Migrated {} keys to {}", copied, out.display());
+    Ok(())
+}
+
+/// 分页读出所有的 key 写进新的引擎，核对数量之后返回复制了多少个
+fn copy_all<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<u64> {
+    let mut copied = 0;
+    let mut after = None;
+    loop {
+        let page = source.scan_after(after.take(), MIGRATE_PAGE)?;
+        let last = match page.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        copied += page.len() as u64;
+        target.set_many(page)?;
+        debug!("Copied {} keys", copied);
+        after = Some(last);
+    }
+    target.flush()?;
+
+    let (expected, found) = (source.stats()?.keys, target.stats()?.keys);
+    if expected != copied || found != copied {
+        return Err(KvsError::StringError(format!(
+            "Copied {} keys, but the source has {} and the target {}",
+            copied, expected, found
+        )));
+    }
+    Ok(copied)
+}
+
+/// Runs every service until all of them return, one failing shuts down the others.
+async fn run_services(services: Vec<Service>, handles: Vec<ShutdownHandle>) -> Result<()> {
+    let tasks: Vec<_> = services
+        .into_iter()
+        .map(|service| {
+            let handles = handles.clone();
+            tokio::spawn(async move {
+                let res = service.await;
+                if res.is_err() {
+                    for handle in &handles {
+                        handle.shutdown();
+                    }
+                }
+                res
+            })
+        })
+        .collect();
+    let mut res = Ok(());
+    for task in tasks {
+        let done = task
+            .await
+            .unwrap_or_else(|e| Err(KvsError::StringError(format!("Service failed: {}", e))));
+        // 只返回第一个错误，其他的多半是被它关掉的
+        if res.is_ok() {
+            res = done;
+        }
+    }
+    res
+}
+
+fn leader_credentials(opt: &Opt) -> Option<Credentials> {
+    match (&opt.leader_user, &opt.leader_password, &opt.leader_token) {
+        (Some(user), Some(password), _) => Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        (_, _, Some(token)) => Some(Credentials::Token(token.clone())),
+        _ => None,
+    }
+}
+
+/// 从标准输入读，密码不会留在 shell 的历史里
+fn print_password_hash() -> Result<()> {
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err(KvsError::StringError("Empty password".to_owned()));
+    }
+    println!("{}", hash_password(password)?);
+    Ok(())
+}
+
+fn server_tls(opt: &Opt) -> Result<Option<ServerTls>> {
+    let (cert, key) = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    info!("Serving over TLS");
+    let mut tls = ServerTls::new(cert, key)?;
+    if let Some(ca) = &opt.tls_client_ca {
+        info!("Client certificates are required");
+        tls.set_client_ca(ca)?;
+    }
+    Ok(Some(tls))
+}
+
+/// 每行日志带上所在的连接和请求，json 格式一行一个对象
+fn init_logger(opt: &Opt) -> Result<()> {
+    let mut builder = env_logger::builder();
+    builder.filter_level(opt.log_level);
+    match opt.log_format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let span = Span::current().map(|span| format!(" {}", span));
+            writeln!(
+                buf,
+                "[{} {:<5} {}{}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                span.unwrap_or_default(),
+                record.args()
+            )
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let span = Span::current().unwrap_or_default();
+            let line = json!({
+                "ts": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "conn": span.conn,
+                "req": span.req,
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+    let audit = match &opt.audit_log {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => None,
+    };
+    let inner = builder.build();
+    // 审计日志不受 --log-level 影响
+    let mut max_level = inner.filter();
+    if audit.is_some() {
+        max_level = max_level.max(LevelFilter::Info);
+    }
+    log::set_boxed_logger(Box::new(Logger { inner, audit }))
+        .map_err(|e| KvsError::StringError(format!("Failed to set logger: {}", e)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// 收到 SIGINT 或 SIGTERM 时关闭服务器，`run` 返回之后进程正常退出
+fn handle_signals(handles: Vec<ShutdownHandle>) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("Received shutdown signal");
+        for handle in &handles {
+            handle.shutdown();
+        }
+    })
+    .map_err(|e| KvsError::StringError(format!("Failed to set signal handler: {}", e)))
 }
 
-fn current_engine() -> Result<Option<Engine>> {
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
     // 尝试从engine文件中读取选择的engine类型
-    let engine = current_dir()?.join("engine");
+    let engine = dir.join("engine");
     if !engine.exists() {
         return Ok(None);
     }
@@ -1,50 +1,398 @@
-use std::{io::{BufReader, BufWriter, Write}, net::{TcpStream, ToSocketAddrs}};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
+use crossbeam::channel::{self, Sender};
+use log::warn;
 
-use crate::{Result, common::{Request, GetResponse, SetResponse, RemoveResponse}, KvsError};
+use crate::{Result, common::{Request, Response}, Credentials, KvsError};
+use crate::protocol::{self, Frame};
+use crate::replication::{LogPosition, LogRead};
+use crate::tls::{ClientTls, Stream};
+
+/// 流水线里最多有多少个请求在等响应，太多的话双方的 socket 缓冲区都写满会卡死
+const PIPELINE_WINDOW: usize = 128;
 
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    next_id: u32,
 }
 
 impl KvsClient {
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        KvsClient::with_stream(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// Connects over TLS, checking the server certificate against `tls`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, tls: &ClientTls) -> Result<Self> {
+        KvsClient::with_stream(Stream::Tls(tls.connect(TcpStream::connect(addr)?)?))
+    }
+
+    fn with_stream(mut stream: Stream) -> Result<Self> {
+        protocol::client_handshake(&mut stream)?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            next_id: 0,
         })
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        match GetResponse::deserialize(&mut self.reader)? {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(e) => Err(KvsError::StringError(e))
+        match self.call(Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(e) => Err(KvsError::StringError(e)),
+        match self.call(Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
         }
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
+        match self.call(Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Gets several keys in one round trip, the values come back in the order of `keys`.
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.call(Request::MGet { keys })? {
+            Response::Values(values) => Ok(values),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Sets several keys in one round trip.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        match self.call(Request::MSet { pairs })? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Removes several keys in one round trip, returning for each whether it existed.
+    pub fn mremove(&mut self, keys: Vec<String>) -> Result<Vec<bool>> {
+        match self.call(Request::MRemove { keys })? {
+            Response::Removed(removed) => Ok(removed),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Returns at most `limit` pairs whose key comes after `after`, in key order.
+    ///
+    /// Pass the last key of a page as `after` to get the next one, an empty page is the end.
+    /// The server may return fewer pairs than asked for.
+    pub fn scan(&mut self, after: Option<String>, limit: u32) -> Result<Vec<(String, String)>> {
+        match self.call(Request::Scan { after, limit })? {
+            Response::Pairs(pairs) => Ok(pairs),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Authenticates the connection, needed before anything else when the server has an ACL.
+    pub fn auth(&mut self, credentials: Credentials) -> Result<()> {
+        match self.call(Request::Auth(credentials))? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Asks the server for the log entries after `from`.
+    pub fn replicate(&mut self, from: LogPosition) -> Result<LogRead> {
+        match self.call(Request::Replicate { from })? {
+            Response::Log(read) => Ok(read),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Sends all requests of `pipeline` without waiting for each response.
+    ///
+    /// Returns one result per request, in order: the value for `get`, `None` for `set` and `remove`.
+    /// A failed request doesn't stop the ones after it.
+    pub fn execute(&mut self, pipeline: Pipeline) -> Result<Vec<Result<Option<String>>>> {
+        let first_id = self.next_id;
+        // 先全部编码好，太大的请求在发送任何请求之前就报错
+        let frames = pipeline
+            .requests
+            .iter()
+            .enumerate()
+            .map(|(i, req)| req.to_frame(first_id.wrapping_add(i as u32)))
+            .collect::<Result<Vec<_>>>()?;
+        self.next_id = first_id.wrapping_add(frames.len() as u32);
+        let mut results = Vec::with_capacity(frames.len());
+        let mut sent = 0;
+        if let Err(e) = self.exchange(&frames, &mut results, &mut sent) {
+            // 已经发出去的请求的响应要读掉，不然会被后面的请求读到
+            if sent > results.len() {
+                self.drain(frames[sent - 1].id);
+            }
+            return Err(e);
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|resp| match resp? {
+                Response::Value(value) => Ok(value),
+                Response::Ok => Ok(None),
+                resp => Err(unexpected(resp)),
+            })
+            .collect())
+    }
+
+    /// 最多 `PIPELINE_WINDOW` 个请求在等响应，`sent` 记录发出去了多少个
+    fn exchange(
+        &mut self,
+        frames: &[Frame],
+        results: &mut Vec<Result<Response>>,
+        sent: &mut usize,
+    ) -> Result<()> {
+        for frame in frames {
+            if *sent - results.len() == PIPELINE_WINDOW {
+                self.writer.flush()?;
+                results.push(self.receive(frames[results.len()].id)?);
+            }
+            frame.write(&mut self.writer)?;
+            *sent += 1;
+        }
         self.writer.flush()?;
-        match RemoveResponse::deserialize(&mut self.reader)? {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(e) => Err(KvsError::StringError(e)),
+        while results.len() < frames.len() {
+            results.push(self.receive(frames[results.len()].id)?);
+        }
+        Ok(())
+    }
+
+    /// 读掉响应直到 `last_id` 为止，连接已经坏了的话读不到，直接返回
+    fn drain(&mut self, last_id: u32) {
+        let _ = self.writer.flush();
+        while let Ok(Some(frame)) = Frame::read(&mut self.reader) {
+            if frame.id == last_id {
+                break;
+            }
         }
     }
-}
\ No newline at end of file
+
+    /// 发送一个请求并等待响应，响应的 id 必须和请求对得上
+    fn call(&mut self, req: Request) -> Result<Response> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        req.to_frame(id)?.write(&mut self.writer)?;
+        self.writer.flush()?;
+        self.receive(id)?
+    }
+
+    /// 外层的错误是连接出了问题，内层的是服务端对这个请求返回的错误，或者响应解不出来
+    fn receive(&mut self, id: u32) -> Result<Result<Response>> {
+        let frame = Frame::read(&mut self.reader)?
+            .ok_or_else(|| KvsError::Protocol("Connection closed".to_owned()))?;
+        if frame.id != id {
+            return Err(KvsError::Protocol(format!(
+                "Expected response {}, got {}",
+                id, frame.id
+            )));
+        }
+        Ok(Response::from_frame(&frame).and_then(into_result))
+    }
+}
+
+/// A batch of requests sent by `KvsClient::execute` in one go.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    requests: Vec<Request>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/// A client whose connection can be shared by many threads.
+///
+/// Requests are tagged with an id and the server answers them as soon as they're
+/// done, a background thread hands each response to the thread waiting for it.
+#[derive(Clone)]
+pub struct MultiplexedKvsClient {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    writer: Mutex<BufWriter<Stream>>,
+    pending: Arc<Mutex<Pending>>,
+    next_id: AtomicU32,
+}
+
+/// 还在等响应的请求，连接断开之后 `closed` 为 true
+#[derive(Default)]
+struct Pending {
+    waiting: HashMap<u32, Sender<Result<Response>>>,
+    closed: bool,
+}
+
+impl MultiplexedKvsClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        MultiplexedKvsClient::with_stream(Stream::Tcp(TcpStream::connect(addr)?))
+    }
+
+    /// Connects over TLS, checking the server certificate against `tls`.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, tls: &ClientTls) -> Result<Self> {
+        MultiplexedKvsClient::with_stream(Stream::Tls(tls.connect(TcpStream::connect(addr)?)?))
+    }
+
+    fn with_stream(mut stream: Stream) -> Result<Self> {
+        protocol::client_handshake(&mut stream)?;
+        Frame {
+            id: 0,
+            code: protocol::OP_MULTIPLEX,
+            payload: Vec::new(),
+        }
+        .write(&mut stream)?;
+        stream.flush()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        match Frame::read(&mut reader)? {
+            Some(frame) if frame.id == 0 => into_result(Response::from_frame(&frame)?)?,
+            _ => return Err(KvsError::Protocol("Multiplexing refused".to_owned())),
+        };
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let reader_pending = Arc::clone(&pending);
+        thread::Builder::new()
+            .name("kvs-client-reader".to_owned())
+            .spawn(move || dispatch(reader, &reader_pending))?;
+
+        Ok(MultiplexedKvsClient {
+            shared: Arc::new(Shared {
+                writer: Mutex::new(BufWriter::new(stream)),
+                pending,
+                next_id: AtomicU32::new(1),
+            }),
+        })
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.call(Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        match self.call(Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        match self.call(Request::Remove { key })? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    /// Authenticates the connection for every clone of this client.
+    pub fn auth(&self, credentials: Credentials) -> Result<()> {
+        match self.call(Request::Auth(credentials))? {
+            Response::Ok => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn call(&self, req: Request) -> Result<Response> {
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = channel::bounded(1);
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if pending.closed {
+                return Err(KvsError::Protocol("Connection closed".to_owned()));
+            }
+            pending.waiting.insert(id, sender);
+        }
+
+        let sent = {
+            let mut writer = self.shared.writer.lock().unwrap();
+            req.to_frame(id)
+                .and_then(|frame| frame.write(&mut *writer))
+                .and_then(|_| Ok(writer.flush()?))
+        };
+        if let Err(e) = sent {
+            self.shared.pending.lock().unwrap().waiting.remove(&id);
+            return Err(e);
+        }
+
+        // 连接断开时 sender 会被丢掉
+        let resp = receiver
+            .recv()
+            .map_err(|_| KvsError::Protocol("Connection closed".to_owned()))??;
+        into_result(resp)
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // 让后台的 reader 线程读到 EOF 退出
+        if let Ok(writer) = self.writer.lock() {
+            let _ = writer.get_ref().shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// 后台线程：把每个响应交给等它的那个线程
+fn dispatch(mut reader: BufReader<Stream>, pending: &Mutex<Pending>) {
+    while let Ok(Some(frame)) = Frame::read(&mut reader) {
+        let sender = pending.lock().unwrap().waiting.remove(&frame.id);
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(Response::from_frame(&frame));
+            }
+            None => warn!("Response to unknown request {}", frame.id),
+        }
+    }
+    let mut pending = pending.lock().unwrap();
+    pending.closed = true;
+    pending.waiting.clear();
+}
+
+pub(crate) fn into_result(resp: Response) -> Result<Response> {
+    match resp {
+        Response::Err(code, message) => Err(code.into_error(message)),
+        Response::Redirect(leader) => Err(KvsError::NotLeader(leader)),
+        Response::Denied(e) => Err(KvsError::PermissionDenied(e)),
+        Response::Busy => Err(KvsError::ServerBusy),
+        resp => Ok(resp),
+    }
+}
+
+pub(crate) fn unexpected(resp: Response) -> KvsError {
+    KvsError::Protocol(format!("Unexpected response {:?}", resp))
+}
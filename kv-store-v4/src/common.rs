@@ -1,10 +1,21 @@
 use serde::{Serialize, Deserialize};
 
+use crate::auth::Credentials;
+use crate::replication::{LogPosition, LogRead};
+use crate::{ErrorCode, KvsError};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
+    Replicate { from: LogPosition },
+    Auth(Credentials),
+    MGet { keys: Vec<String> },
+    MSet { pairs: Vec<(String, String)> },
+    MRemove { keys: Vec<String> },
+    /// At most `limit` pairs after the key `after`, in key order
+    Scan { after: Option<String>, limit: u32 },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,10 +28,129 @@ pub enum GetResponse {
 pub enum SetResponse {
     Ok(()),
     Err(String),
+    /// The server is a raft follower, retry on the leader
+    Redirect(String),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
+    Redirect(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicateResponse {
+    Ok(LogRead),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// The single response type of the framed protocol, whatever the request was.
+#[derive(Debug)]
+pub enum Response {
+    Ok,
+    Value(Option<String>),
+    /// The values of an `MGet`, in the order of its keys
+    Values(Vec<Option<String>>),
+    /// Whether each key of an `MRemove` existed
+    Removed(Vec<bool>),
+    /// The pairs of a `Scan`
+    Pairs(Vec<(String, String)>),
+    Log(LogRead),
+    Err(ErrorCode, String),
+    /// The server is a raft follower, retry on the leader
+    Redirect(String),
+    /// The connection isn't allowed to do this
+    Denied(String),
+    /// The server has too many connections, sent instead of serving a new one
+    Busy,
+}
+
+// 旧的 json 协议每种请求有自己的响应类型，从统一的 Response 转换过去
+// 旧客户端不认识新的变体，权限错误也当成普通的错误
+
+impl From<Response> for GetResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Value(value) => GetResponse::Ok(value),
+            Response::Err(_, e) => GetResponse::Err(e),
+            Response::Denied(e) => GetResponse::Err(denied(e)),
+            resp => GetResponse::Err(format!("Unexpected response {:?}", resp)),
+        }
+    }
+}
+
+impl From<Response> for SetResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Ok => SetResponse::Ok(()),
+            Response::Err(_, e) => SetResponse::Err(e),
+            Response::Denied(e) => SetResponse::Err(denied(e)),
+            Response::Redirect(leader) => SetResponse::Redirect(leader),
+            resp => SetResponse::Err(format!("Unexpected response {:?}", resp)),
+        }
+    }
+}
+
+impl From<Response> for RemoveResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Ok => RemoveResponse::Ok(()),
+            Response::Err(_, e) => RemoveResponse::Err(e),
+            Response::Denied(e) => RemoveResponse::Err(denied(e)),
+            Response::Redirect(leader) => RemoveResponse::Redirect(leader),
+            resp => RemoveResponse::Err(format!("Unexpected response {:?}", resp)),
+        }
+    }
+}
+
+impl From<Response> for ReplicateResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Log(read) => ReplicateResponse::Ok(read),
+            Response::Err(_, e) => ReplicateResponse::Err(e),
+            Response::Denied(e) => ReplicateResponse::Err(denied(e)),
+            resp => ReplicateResponse::Err(format!("Unexpected response {:?}", resp)),
+        }
+    }
+}
+
+impl From<Response> for AuthResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::Ok => AuthResponse::Ok(()),
+            Response::Err(_, e) => AuthResponse::Err(e),
+            Response::Denied(e) => AuthResponse::Err(denied(e)),
+            resp => AuthResponse::Err(format!("Unexpected response {:?}", resp)),
+        }
+    }
+}
+
+impl Response {
+    /// Reports `e` to the client.
+    pub fn from_error(e: &KvsError) -> Response {
+        match e {
+            KvsError::NotLeader(leader) => Response::Redirect(leader.clone()),
+            KvsError::PermissionDenied(e) => Response::Denied(e.clone()),
+            KvsError::ServerBusy => Response::Busy,
+            // 客户端会重新包一层，只发里面的信息
+            KvsError::Io(io) => Response::Err(ErrorCode::Io, format!("{}", io)),
+            KvsError::Protocol(message) => {
+                Response::Err(ErrorCode::InvalidRequest, message.clone())
+            }
+            // 转发别的服务器的错误时原样转发
+            KvsError::Remote { code, message } => Response::Err(*code, message.clone()),
+            e => Response::Err(e.code(), format!("{}", e)),
+        }
+    }
+}
+
+fn denied(e: String) -> String {
+    format!("{}", KvsError::PermissionDenied(e))
 }
@@ -0,0 +1,190 @@
+//! Export and import of key/value pairs, as JSON Lines or CSV.
+//!
+//! Every pair is a record with a `key`, a `value` and an `encoding`. Values with control
+//! characters, which most tools handle badly, are written in base64 with the `encoding`
+//! `base64`. Otherwise the `encoding` is left out in JSON and empty in CSV.
+
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// How the pairs are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line
+    JsonLines,
+    /// A `key,value,encoding` header and one row per pair
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Base64,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+    #[serde(default)]
+    encoding: Option<Encoding>,
+}
+
+/// 写的时候不用把 key 和 value 复制一份
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    key: &'a str,
+    value: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+}
+
+/// Writes pairs in a `DumpFormat`.
+pub struct DumpWriter<W: Write> {
+    inner: WriterInner<W>,
+}
+
+enum WriterInner<W: Write> {
+    JsonLines(W),
+    Csv(csv::Writer<W>),
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Starts a dump, CSV begins with the header.
+    pub fn new(writer: W, format: DumpFormat) -> Result<Self> {
+        let inner = match format {
+            DumpFormat::JsonLines => WriterInner::JsonLines(writer),
+            DumpFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                writer
+                    .write_record(["key", "value", "encoding"])
+                    .map_err(csv_error)?;
+                WriterInner::Csv(writer)
+            }
+        };
+        Ok(DumpWriter { inner })
+    }
+
+    pub fn write(&mut self, key: &str, value: &str) -> Result<()> {
+        let (value, encoding) = encode(value);
+        match &mut self.inner {
+            WriterInner::JsonLines(writer) => {
+                let record = RecordRef {
+                    key,
+                    value: &value,
+                    encoding,
+                };
+                serde_json::to_writer(&mut *writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            WriterInner::Csv(writer) => {
+                let encoding = match encoding {
+                    Some(Encoding::Base64) => "base64",
+                    None => "",
+                };
+                writer
+                    .write_record([key, &value, encoding])
+                    .map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the dump and returns the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        match self.inner {
+            WriterInner::JsonLines(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            WriterInner::Csv(writer) => writer
+                .into_inner()
+                .map_err(|e| KvsError::Io(e.into_error())),
+        }
+    }
+}
+
+/// Reads the pairs of a dump written by `DumpWriter`.
+pub struct DumpReader<R: Read> {
+    inner: ReaderInner<R>,
+}
+
+enum ReaderInner<R: Read> {
+    JsonLines {
+        lines: io::Lines<BufReader<R>>,
+        line: u64,
+    },
+    Csv(csv::DeserializeRecordsIntoIter<R, Record>),
+}
+
+impl<R: Read> DumpReader<R> {
+    pub fn new(reader: R, format: DumpFormat) -> Self {
+        let inner = match format {
+            DumpFormat::JsonLines => ReaderInner::JsonLines {
+                lines: BufReader::new(reader).lines(),
+                line: 0,
+            },
+            DumpFormat::Csv => {
+                ReaderInner::Csv(csv::Reader::from_reader(reader).into_deserialize())
+            }
+        };
+        DumpReader { inner }
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            ReaderInner::JsonLines { lines, line } => loop {
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                *line += 1;
+                // 允许空行，手写的文件末尾常常多一个
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str(&text).map_err(|e| {
+                    KvsError::StringError(format!("Invalid record on line {}: {}", line, e))
+                });
+                return Some(record.and_then(decode));
+            },
+            ReaderInner::Csv(records) => {
+                let record = records.next()?;
+                Some(record.map_err(csv_error).and_then(decode))
+            }
+        }
+    }
+}
+
+/// 有控制字符的 value 用 base64 写，tab 在 CSV 和 JSON 里都没问题
+fn encode(value: &str) -> (Cow<'_, str>, Option<Encoding>) {
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        (Cow::Owned(base64::encode(value)), Some(Encoding::Base64))
+    } else {
+        (Cow::Borrowed(value), None)
+    }
+}
+
+fn decode(record: Record) -> Result<(String, String)> {
+    match record.encoding {
+        None => Ok((record.key, record.value)),
+        Some(Encoding::Base64) => {
+            let value = base64::decode(&record.value).map_err(|e| {
+                KvsError::StringError(format!("Invalid base64 value of {}: {}", record.key, e))
+            })?;
+            Ok((record.key, String::from_utf8(value)?))
+        }
+    }
+}
+
+fn csv_error(e: csv::Error) -> KvsError {
+    KvsError::StringError(format!("CSV error: {}", e))
+}
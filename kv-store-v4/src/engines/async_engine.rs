@@ -0,0 +1,58 @@
+use std::future::Future;
+
+use tokio::task;
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// Wraps a `KvsEngine` for use in async code.
+///
+/// 引擎的读写都是阻塞的文件 IO，直接在 async 任务里调用会卡住 tokio 的工作线程，
+/// 所以每次调用都丢到 tokio 的阻塞线程池里执行。
+/// `KvStore` 不是 `Sync` 的，返回的 future 不能借用 `self`，所以这里没有用 `async fn`
+#[derive(Clone)]
+pub struct AsyncEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncEngine<E> {
+    pub fn new(engine: E) -> Self {
+        AsyncEngine { engine }
+    }
+
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> {
+        let res = self.run(move |engine| engine.set(key, value));
+        async move { res.await? }
+    }
+
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> {
+        let res = self.run(move |engine| engine.get(key));
+        async move { res.await? }
+    }
+
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> {
+        let res = self.run(move |engine| engine.remove(key));
+        async move { res.await? }
+    }
+
+    pub fn scan(&self, prefix: String) -> impl Future<Output = Result<Vec<(String, String)>>> {
+        let res = self.run(move |engine| engine.scan(prefix));
+        async move { res.await? }
+    }
+
+    /// Runs `f` with the engine on the blocking thread pool.
+    ///
+    /// `f` starts right away, even if the returned future is never polled.
+    pub fn run<F, R>(&self, f: F) -> impl Future<Output = Result<R>>
+    where
+        F: FnOnce(&E) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let handle = task::spawn_blocking(move || f(&engine));
+        async move {
+            handle
+                .await
+                .map_err(|e| KvsError::StringError(format!("Blocking task failed: {}", e)))
+        }
+    }
+}
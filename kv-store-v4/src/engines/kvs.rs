@@ -1,21 +1,55 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::ops::{Bound, Range};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
+use crossbeam::channel::{self, Receiver, Sender};
 use crossbeam_skiplist::SkipMap;
-use log::error;
+use log::{error, warn};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{KvsEngine, KvsError, Result};
+use crate::replication::{LogPosition, LogRead};
+use crate::{EngineStats, KvsEngine, KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// 每次复制最多返回的命令数量
+const MAX_LOG_BATCH: usize = 1024;
+/// 每次复制大约返回多少字节，离 `MAX_PAYLOAD_LEN` 留足余量，json 转义之后还会变大
+const MAX_LOG_BYTES: u64 = 4 * 1024 * 1024;
+/// writer 线程每次最多合并多少条写入，一起落盘
+const DEFAULT_WRITE_BATCH: usize = 128;
+
+/// How a `KvStore` batches, syncs and compacts its log.
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    /// The writer thread groups up to this many concurrent writes into a single flush,
+    /// 1 flushes after every command.
+    pub write_batch: usize,
+    /// Fsyncs the log after every batch, without it a crash of the machine may lose the
+    /// last writes.
+    pub sync_writes: bool,
+    /// Compacts the logs once this many bytes in them are stale.
+    pub compaction_threshold: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            write_batch: DEFAULT_WRITE_BATCH,
+            sync_writes: false,
+            compaction_threshold: COMPACTION_THRESHOLD,
+        }
+    }
+}
 
 /// The `KvStore` stores key/value pairs
 /// 这次是一个共享的引擎，每个引擎都有一个 reader 和 writer
@@ -30,12 +64,15 @@ pub struct KvStore {
     reader: KvStoreReader,
 
     /// 每次启动是就会新建一个 log 文件，Writer 只负责向这个新的文件写入
+    /// Writer 跑在单独的线程里，set/remove 通过 channel 把命令发过去，不再需要锁
     // writer: BufWriterWithPos<File>,
-    writer: Arc<Mutex<KvStoreWriter>>,
+    writer: Arc<WriterHandle>,
 
     /// 索引：这次使用 crossbeam 提供的 skipmap 实现无锁并发
     // index: BTreeMap<String, CommandPos>,
     index: Arc<SkipMap<String, CommandPos>>,
+    /// writer 里 `uncompacted` 的副本，给 `stats` 读
+    stale_bytes: Arc<AtomicU64>,
     // 都被封装进了 writer
     // current_gen: u64,
     // uncompacted: u64,
@@ -43,29 +80,41 @@ pub struct KvStore {
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_batch_size(path, DEFAULT_WRITE_BATCH)
+    }
+
+    /// Opens the store, letting the writer thread group up to `max_batch`
+    /// concurrent writes into a single flush.
+    ///
+    /// `max_batch` of 1 flushes after every command.
+    pub fn open_with_batch_size(path: impl Into<PathBuf>, max_batch: usize) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            write_batch: max_batch,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Opens the store with all of `options`, `write_batch` must be at least 1.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        if options.write_batch == 0 {
+            return Err(KvsError::StringError(
+                "write_batch must be at least 1".to_owned(),
+            ));
+        }
         // 加载日志目录
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
-        let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
-
-        // 初始化现有的所有日志文件，按照大小排序
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
-
-        // 为每个日志创建一个 Reader，顺便统计总可压缩数量
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
-        }
+        let (readers, uncompacted) = load_logs(&path, &index, true)?;
 
         // 获取最新的版本号 (还有即将创建的，所以要 +1)
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let current_gen = readers.keys().next_back().unwrap_or(&0) + 1;
 
         // 反正就是一个原子的 u64
         let safe_point = Arc::new(AtomicU64::new(0));
+        let stale_bytes = Arc::new(AtomicU64::new(uncompacted));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
@@ -80,23 +129,81 @@ impl KvStore {
             reader: reader.clone(),
             writer,
             current_gen,
+            end: 0,
             uncompacted,
+            stale_bytes: Arc::clone(&stale_bytes),
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            options,
         };
 
+        let (sender, receiver) = channel::unbounded();
+        let thread = thread::Builder::new()
+            .name("kvs-writer".to_owned())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(KvStore {
+            path,
+            reader,
+            writer: Arc::new(WriterHandle {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+            index,
+            stale_bytes,
+        })
+    }
+
+    /// Opens the store without a writer, to read the data of a stopped server.
+    ///
+    /// Nothing in the directory is changed, writes fail with `KvsError::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        let index = Arc::new(SkipMap::new());
+        // 不新建日志，结尾写了一半的命令也留着，下次正常打开时再截掉
+        let (readers, uncompacted) = load_logs(&path, &index, false)?;
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+        };
         Ok(KvStore {
             path,
             reader,
-            writer: Arc::new(Mutex::new(writer)),
+            writer: Arc::new(WriterHandle {
+                sender: None,
+                thread: None,
+            }),
             index,
+            stale_bytes: Arc::new(AtomicU64::new(uncompacted)),
         })
     }
+
+    /// Returns the key/value pairs together with the log position they correspond to.
+    ///
+    /// A large store only returns the first pairs as a `LogRead::PartialSnapshot`.
+    ///
+    /// 快照在 writer 线程里生成，保证快照和位置是一致的
+    pub fn snapshot(&self) -> Result<LogRead> {
+        let (reply, result) = channel::bounded(1);
+        self.writer.send(WriteOp::Snapshot(reply))?;
+        result.recv().map_err(|_| KvsError::WriterPanicked)?
+    }
+
+    /// Returns all keys currently in the store, in order.
+    pub fn keys(&self) -> Vec<String> {
+        self.index.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    /// Returns the directory the store lives in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
 impl KvsEngine for KvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+        self.writer.write(Command::set(key, value))
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
@@ -112,7 +219,122 @@ impl KvsEngine for KvStore {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+        self.writer.write(Command::remove(key))
+    }
+
+    /// 整批交给 writer 一起写，不会在第一个失败的地方停下，返回第一个错误
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let cmds = pairs
+            .into_iter()
+            .map(|(key, value)| Command::set(key, value))
+            .collect();
+        self.writer.write_many(cmds)?.into_iter().collect()
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let cmds = keys.into_iter().map(Command::remove).collect();
+        self.writer
+            .write_many(cmds)?
+            .into_iter()
+            .map(|res| match res {
+                Ok(()) => Ok(true),
+                Err(KvsError::KeyNotFound) => Ok(false),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> Result<()> {
+        let (reply, result) = channel::bounded(1);
+        self.writer.send(WriteOp::Sync(reply))?;
+        result.recv().map_err(|_| KvsError::WriterPanicked)?
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        // skipmap 里的 key 本来就是有序的，从 prefix 开始往后找
+        let mut pairs = Vec::new();
+        for entry in self.index.range(prefix.clone()..) {
+            if !entry.key().starts_with(&prefix) {
+                break;
+            }
+            if let Command::Set { key, value } = self.reader.read_command(*entry.value())? {
+                pairs.push((key, value));
+            } else {
+                return Err(KvsError::UnexpectedCommandType);
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_after(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        let mut pairs = Vec::new();
+        for entry in self.index.range((start, Bound::Unbounded)).take(limit) {
+            if let Command::Set { key, value } = self.reader.read_command(*entry.value())? {
+                pairs.push((key, value));
+            } else {
+                return Err(KvsError::UnexpectedCommandType);
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.index.len() as u64,
+            generations: sorted_gen_list(&self.path)?.len() as u64,
+            stale_bytes: self.stale_bytes.load(Ordering::Relaxed),
+        })
+    }
+
+    fn read_log(&self, from: LogPosition) -> Result<LogRead> {
+        let gen_list = sorted_gen_list(&self.path)?;
+        let start = if gen_list.contains(&from.gen) {
+            from
+        } else if from == LogPosition::default() && gen_list.first() == Some(&1) {
+            // 从来没有压缩过，新的 follower 直接从第一个日志开始读
+            LogPosition { gen: 1, pos: 0 }
+        } else {
+            // follower 的位置已经被压缩掉了，只能发送全量快照
+            return self.snapshot();
+        };
+
+        let mut commands = Vec::new();
+        let mut next = start;
+        let mut bytes = 0;
+        let full = |commands: &[Command], bytes: u64| {
+            commands.len() >= MAX_LOG_BATCH || bytes >= MAX_LOG_BYTES
+        };
+        for &gen in gen_list.iter().filter(|&&gen| gen >= start.gen) {
+            if gen != next.gen {
+                next = LogPosition { gen, pos: 0 };
+            }
+            let base = next.pos;
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&self.path, gen))?)?;
+            reader.seek(SeekFrom::Start(base))?;
+            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+            while !full(&commands, bytes) {
+                match stream.next() {
+                    Some(Ok(cmd)) => {
+                        commands.push(cmd);
+                        let pos = base + stream.byte_offset() as u64;
+                        bytes += pos - next.pos;
+                        next.pos = pos;
+                    }
+                    // 最后一条命令可能还没写完，下次再读
+                    Some(Err(e)) if e.is_eof() => return Ok(LogRead::Entries { commands, next }),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => break,
+                }
+            }
+            if full(&commands, bytes) {
+                break;
+            }
+        }
+        Ok(LogRead::Entries { commands, next })
     }
 }
 
@@ -176,60 +398,263 @@ impl Clone for KvStoreReader {
     }
 }
 
+/// 发给 writer 线程的请求，结果通过各自的 channel 传回来
+enum WriteOp {
+    Command(Command, Sender<Result<()>>),
+    Snapshot(Sender<Result<LogRead>>),
+    /// 把当前日志 fsync 到磁盘
+    Sync(Sender<Result<()>>),
+}
+
+/// 所有 `KvStore` 共享的 writer 线程的句柄
+///
+/// 最后一个 `KvStore` 被 drop 时关闭 channel，等 writer 把剩下的命令写完再返回
+/// 只读打开的时候 `sender` 一开始就是 `None`
+struct WriterHandle {
+    sender: Option<Sender<WriteOp>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WriterHandle {
+    fn send(&self, op: WriteOp) -> Result<()> {
+        self.sender
+            .as_ref()
+            .ok_or(KvsError::ReadOnly)?
+            .send(op)
+            .map_err(|_| KvsError::WriterPanicked)
+    }
+
+    fn write(&self, cmd: Command) -> Result<()> {
+        let (reply, result) = channel::bounded(1);
+        self.send(WriteOp::Command(cmd, reply))?;
+        // writer 处理这条命令时 panic 了，reply 会被直接 drop 掉
+        result.recv().map_err(|_| KvsError::WriterPanicked)?
+    }
+
+    /// 先把命令全部排进 channel 再等结果，writer 会把它们合并成尽量少的几次写入
+    fn write_many(&self, cmds: Vec<Command>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let (reply, result) = channel::bounded(1);
+            self.send(WriteOp::Command(cmd, reply))?;
+            results.push(result);
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| result.recv().unwrap_or(Err(KvsError::WriterPanicked)))
+            .collect())
+    }
+}
+
+impl Drop for WriterHandle {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Writer thread panicked");
+            }
+        }
+    }
+}
+
 struct KvStoreWriter {
     reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
     current_gen: u64,
+    /// 当前日志里最后一批完整写入的命令的结束位置，出错时截断回这里
+    end: u64,
     uncompacted: u64,
+    stale_bytes: Arc<AtomicU64>,
     path: Arc<PathBuf>,
     index: Arc<SkipMap<String, CommandPos>>,
+    options: KvStoreOptions,
 }
 
-/// writer 本身被 mutex 包裹，不需要 mut，调用 writer 前🔓
+/// writer 只在自己的线程里使用，同一时间只有一个写入者
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+    /// writer 线程的主循环：阻塞等第一条命令，再把 channel 里已经排队的命令一起取出来，
+    /// 合并成一次写入和一次 flush
+    fn run(mut self, receiver: Receiver<WriteOp>) {
+        let max_batch = self.options.write_batch;
+        let mut batch = Vec::with_capacity(max_batch);
+        while let Ok(op) = receiver.recv() {
+            let mut next = Some(op);
+            while let Some(op) = next.take() {
+                match op {
+                    WriteOp::Command(cmd, reply) => batch.push((cmd, reply)),
+                    WriteOp::Snapshot(reply) => {
+                        // 快照要包含前面已经收到的命令
+                        self.guarded(|writer| writer.write_batch(&mut batch));
+                        let _ = reply.send(self.snapshot());
+                    }
+                    WriteOp::Sync(reply) => {
+                        self.guarded(|writer| writer.write_batch(&mut batch));
+                        let _ = reply.send(self.sync());
+                    }
+                }
+                if batch.len() < max_batch {
+                    next = receiver.try_recv().ok();
+                }
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+            self.guarded(|writer| writer.write_batch(&mut batch));
         }
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// 处理一批命令时即使 panic 了，writer 线程也继续活着，不会像 mutex 一样中毒
+    fn guarded<F: FnOnce(&mut Self)>(&mut self, f: F) {
+        if panic::catch_unwind(AssertUnwindSafe(|| f(self))).is_err() {
+            error!("Writer panicked, dropping the unfinished batch");
+            self.recover();
+        }
+    }
+
+    /// 缓冲区里和日志末尾可能有写了一半的命令，都丢掉，把当前日志截断回 `self.end`
+    fn recover(&mut self) {
+        if let Err(e) = self.truncate(self.end) {
+            error!(
+                "Cannot truncate the log, switching to a new log file: {}",
+                e
+            );
+            let gen = self.current_gen + 1;
+            match new_log_file(&self.path, gen) {
+                Ok(writer) => {
+                    let old = mem::replace(&mut self.writer, writer);
+                    let _ = old.writer.into_parts();
+                    self.current_gen = gen;
+                    self.end = 0;
+                }
+                Err(e) => error!("Cannot create a new log file: {}", e),
+            }
         }
+    }
 
+    fn truncate(&mut self, end: u64) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(log_path(&self.path, self.current_gen))?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::End(0))?;
+        // 旧的 writer 不能 flush，缓冲区里的东西直接扔掉
+        let old = mem::replace(&mut self.writer, BufWriterWithPos::new(file)?);
+        let _ = old.writer.into_parts();
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+    fn write_batch(&mut self, batch: &mut Vec<(Command, Sender<Result<()>>)>) {
+        if batch.is_empty() {
+            return;
+        }
 
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).ok_or(KvsError::KeyNotFound)?;
-                // 原本有的 Insert 也被压缩
-                self.uncompacted += old_cmd.value().len;
-                // 新的写入的长度，这个长度是序列化实际写入的长度
-                self.uncompacted += self.writer.pos - pos;
+        // 先把整批命令序列化到内存里，一次写入文件
+        // 同一批里可能先 set 再 remove 同一个 key，判断 key 在不在要把前面的命令也算上
+        let start = self.writer.pos;
+        let mut buf = Vec::new();
+        let mut pending: HashMap<String, bool> = HashMap::new();
+        let mut written = Vec::with_capacity(batch.len());
+        for (cmd, reply) in batch.drain(..) {
+            let key = cmd.key();
+            if let Command::Remove { .. } = cmd {
+                let exists = match pending.get(key) {
+                    Some(&exists) => exists,
+                    None => self.index.contains_key(key),
+                };
+                if !exists {
+                    let _ = reply.send(Err(KvsError::KeyNotFound));
+                    continue;
+                }
             }
+            pending.insert(key.clone(), matches!(cmd, Command::Set { .. }));
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+            let offset = buf.len();
+            if let Err(e) = serde_json::to_writer(&mut buf, &cmd) {
+                buf.truncate(offset);
+                let _ = reply.send(Err(e.into()));
+                continue;
             }
+            let range = start + offset as u64..start + buf.len() as u64;
+            written.push((cmd, range, reply));
+        }
+        if written.is_empty() {
+            return;
+        }
 
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+        // 要求同步写的话，fsync 之后才算写入成功
+        let sync_writes = self.options.sync_writes;
+        if let Err(e) = self
+            .writer
+            .write_all(&buf)
+            .and_then(|_| self.writer.flush())
+            .and_then(|_| match sync_writes {
+                true => self.writer.writer.get_ref().sync_data(),
+                false => Ok(()),
+            })
+        {
+            // 可能已经写出去一部分了，截断回这一批开始的位置
+            self.recover();
+            for (_, _, reply) in written {
+                let _ = reply.send(Err(io::Error::new(e.kind(), e.to_string()).into()));
+            }
+            return;
         }
+
+        self.end = self.writer.pos;
+
+        // 落盘之后才能更新索引，不然 reader 会读到还在缓冲区里的位置
+        for (cmd, range, reply) in written {
+            match cmd {
+                Command::Set { key, .. } => {
+                    if let Some(old_cmd) = self.index.get(&key) {
+                        self.uncompacted += old_cmd.value().len;
+                    }
+                    self.index.insert(key, (self.current_gen, range).into());
+                }
+                Command::Remove { key } => {
+                    if let Some(old_cmd) = self.index.remove(&key) {
+                        // 原本有的 Insert 也被压缩
+                        self.uncompacted += old_cmd.value().len;
+                    }
+                    // 新的写入的长度，这个长度是序列化实际写入的长度
+                    self.uncompacted += range.end - range.start;
+                }
+            }
+            let _ = reply.send(Ok(()));
+        }
+
+        // 写入已经成功了，压缩失败只记录下来
+        if self.uncompacted > self.options.compaction_threshold {
+            if let Err(e) = self.compact() {
+                error!("Compaction failed: {}", e);
+            }
+        }
+        self.stale_bytes.store(self.uncompacted, Ordering::Relaxed);
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// 太大的快照只返回前面一部分，follower 接着用 scan 分页拉剩下的
+    fn snapshot(&self) -> Result<LogRead> {
+        let mut pairs = Vec::new();
+        let mut bytes = 0;
+        let next = LogPosition {
+            gen: self.current_gen,
+            pos: self.writer.pos,
+        };
+        for entry in self.index.iter() {
+            if bytes >= MAX_LOG_BYTES {
+                return Ok(LogRead::PartialSnapshot { pairs, next });
+            }
+            if let Command::Set { key, value } = self.reader.read_command(*entry.value())? {
+                bytes += (key.len() + value.len()) as u64;
+                pairs.push((key, value));
+            } else {
+                return Err(KvsError::UnexpectedCommandType);
+            }
+        }
+        Ok(LogRead::Snapshot { pairs, next })
     }
 
     // 向索引中写入数据，但是不会更新 reader，read 会判断有没有 reader，没有再加上
@@ -241,22 +666,21 @@ impl KvStoreWriter {
         // 压缩玩要新开一个日志
         self.current_gen += 2;
         self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.end = 0;
 
-        // 新建压缩日志
+        // 新建压缩日志，索引要指向压缩日志里的新位置
         let mut new_pos = 0;
         for entry in self.index.iter() {
-            // let len = self.reader.read_and(*entry.value(), |entry_reader| {
-            //     Ok(io::copy(&mut entry_reader, &mut self.writer)?)
-            // });
-            let cmd = self.reader.read_command(*entry.value())?;
-            serde_json::to_writer(&mut compaction_writer, &cmd)?;
+            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
+                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+            })?;
             self.index.insert(
                 entry.key().clone(),
-                (compaction_gen, new_pos..self.writer.pos).into(),
+                (compaction_gen, new_pos..new_pos + len).into(),
             );
-            new_pos += self.writer.pos - new_pos;
+            new_pos += len;
         }
-        self.writer.flush()?;
+        compaction_writer.flush()?;
 
         // 关闭之前版本的 handle
         // 这个过程只有在 compact 时发生，刚开始为 0，压缩时 store 为 compaction_gen
@@ -282,6 +706,32 @@ impl KvStoreWriter {
 }
 
 /// 读取目录中的所有文件，找出这些文件的版本号，然后排序
+/// 为每个日志创建一个 Reader 并建立索引，返回 reader 和总的可压缩数量
+///
+/// `repair` 的时候截掉上次崩溃时只写了一半的最后一条命令
+fn load_logs(
+    path: &Path,
+    index: &SkipMap<String, CommandPos>,
+    repair: bool,
+) -> Result<(BTreeMap<u64, BufReaderWithPos<File>>, u64)> {
+    let mut readers = BTreeMap::new();
+    let mut uncompacted = 0;
+    for gen in sorted_gen_list(path)? {
+        let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+        let (stale, end) = load(gen, &mut reader, index)?;
+        uncompacted += stale;
+        if repair {
+            let file = OpenOptions::new().write(true).open(log_path(path, gen))?;
+            if file.metadata()?.len() > end {
+                warn!("Dropping a torn command at the end of {}.log", gen);
+                file.set_len(end)?;
+            }
+        }
+        readers.insert(gen, reader);
+    }
+    Ok((readers, uncompacted))
+}
+
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<PathBuf> { Ok(res?.path()) })
@@ -304,7 +754,8 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
 }
 
 /// Load the whole log file and store value locations in the index map.
-/// Return how many butes can be saved after a compation.
+/// Return how many butes can be saved after a compation, and where the last complete
+/// command ends.
 ///
 /// load 会加载所有日志的索引
 /// 我们这里没有单独的索引文件，因为数据文件使用 serde 序列化了 Command 结构体，而是直接从数据文件中遍历所有数据组合出索引文件
@@ -314,7 +765,7 @@ fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
     index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
+) -> Result<(u64, u64)> {
     // 加载某个版本的日志文件
     let mut pos = reader.seek(SeekFrom::Start(0))?;
 
@@ -325,7 +776,13 @@ fn load(
     // 以此向索引中添加 command 记录，并统计可压缩数量
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // 只有文件末尾写了一半的命令可以跳过
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        match cmd {
             // 如果是插入就将 key 加入到索引
             Command::Set { key, .. } => {
                 // 如果有重复插入的动作就意味着日志可以被压缩的数量 +1
@@ -349,7 +806,7 @@ fn load(
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    Ok((uncompacted, pos))
 }
 
 fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
@@ -365,8 +822,10 @@ fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
 }
 
 /// Command 相关
-#[derive(Deserialize, Serialize, Debug)]
-enum Command {
+///
+/// A command as it is stored in the log, also shipped to replicas as-is.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
     Set { key: String, value: String },
     Remove { key: String },
 }
@@ -379,6 +838,12 @@ impl Command {
     fn remove(key: String) -> Self {
         Command::Remove { key }
     }
+
+    fn key(&self) -> &String {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+        }
+    }
 }
 
 /// Represents the position and length of a json-seralizaed command in the log file
@@ -1,8 +1,13 @@
+mod async_engine;
 mod kvs;
+mod sharded;
 mod sled;
 
-use crate::Result;
-pub use self::kvs::KvStore;
+use crate::replication::{LogPosition, LogRead};
+use crate::{KvsError, Result};
+pub use self::async_engine::AsyncEngine;
+pub use self::kvs::{Command, KvStore, KvStoreOptions};
+pub use self::sharded::ShardedKvStore;
 pub use self::sled::SledKvsEngine;
 
 pub trait KvsEngine: Clone + Send + 'static {
@@ -13,4 +18,74 @@ pub trait KvsEngine: Clone + Send + 'static {
 
     fn remove(&self, key: String) -> Result<()>;
 
-}
\ No newline at end of file
+    /// Sets every pair, an error means some of them may not have been set.
+    ///
+    /// Engines which can write several keys at once override this, the default sets them one by one
+    /// and stops at the first failure.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Removes every key, returning for each whether it existed.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| match self.remove(key) {
+                Ok(()) => Ok(true),
+                Err(KvsError::KeyNotFound) => Ok(false),
+                Err(e) => Err(e),
+            })
+            .collect()
+    }
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key order.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>>;
+
+    /// Returns at most `limit` pairs whose key comes after `after`, or from the first key, in
+    /// key order.
+    ///
+    /// Walks all the keys a page at a time. The default scans everything and keeps a page of it,
+    /// engines with an ordered index override this.
+    fn scan_after(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .scan(String::new())?
+            .into_iter()
+            .filter(|(key, _)| after.as_ref().map_or(true, |after| key > after))
+            .take(limit)
+            .collect())
+    }
+
+    /// Writes out anything buffered and syncs it to disk, called when the server shuts down.
+    fn flush(&self) -> Result<()>;
+
+    /// Reports how much the engine stores, for the metrics endpoint.
+    ///
+    /// The default counts the keys with a full scan, engines which know better override this.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.scan(String::new())?.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    /// Reads the commands appended to the log after `from`, used by replicas to catch up.
+    ///
+    /// Engines without a replayable log can't act as a replication leader.
+    fn read_log(&self, from: LogPosition) -> Result<LogRead> {
+        let _ = from;
+        Err(KvsError::ReplicationUnsupported)
+    }
+
+}
+
+/// What an engine stores, see `KvsEngine::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    pub keys: u64,
+    /// Log files on disk, 0 for engines without a log
+    pub generations: u64,
+    /// Bytes taken by overwritten or removed commands, until the next compaction
+    pub stale_bytes: u64,
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{EngineStats, KvStore, KvsEngine, KvsError, Result};
+
+/// The file recording how many shards a directory was created with.
+const SHARDS_FILE: &str = "shards";
+
+/// A `KvsEngine` spreading keys over several independent `KvStore`s.
+///
+/// 每个 shard 都是一个单独的目录，有自己的 writer 线程和压缩，
+/// 不同 shard 的写入可以并行。
+/// `KvStore` 的 reader 不能跨线程共享，所以这里直接 clone 每个 shard
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+}
+
+impl ShardedKvStore {
+    /// Opens `shards` stores under `path`, in the subdirectories `shard-0` .. `shard-N`.
+    ///
+    /// The shard count can't change once the directory is created,
+    /// since it decides which shard every key lives in.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        if shards == 0 {
+            return Err(KvsError::StringError(
+                "A sharded store needs at least one shard".to_owned(),
+            ));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let shards_path = path.join(SHARDS_FILE);
+        if shards_path.exists() {
+            let existing: usize = fs::read_to_string(&shards_path)?
+                .trim()
+                .parse()
+                .map_err(|_| KvsError::StringError("Invalid shards file".to_owned()))?;
+            if existing != shards {
+                return Err(KvsError::StringError(format!(
+                    "Store has {} shards, cannot open it with {}",
+                    existing, shards
+                )));
+            }
+        } else {
+            fs::write(&shards_path, shards.to_string())?;
+        }
+
+        let shards = (0..shards)
+            .map(|i| KvStore::open(path.join(format!("shard-{}", i))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvStore { shards })
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[(fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize]
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        for shard in &self.shards {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan(prefix.clone())?);
+        }
+        // 每个 shard 内部已经有序，而且 key 不会重复，排个序合并起来
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+
+    /// 每个 shard 取一页，合并之后前 `limit` 个就是整体的一页
+    fn scan_after(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan_after(after.clone(), limit)?);
+        }
+        pairs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut total = EngineStats::default();
+        for shard in &self.shards {
+            let stats = shard.stats()?;
+            total.keys += stats.keys;
+            total.generations += stats.generations;
+            total.stale_bytes += stats.stale_bytes;
+        }
+        Ok(total)
+    }
+}
+
+/// 分片用的哈希必须在不同版本、不同进程之间保持稳定，所以不用标准库的 `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
@@ -1,6 +1,8 @@
-use sled::{Db, Tree};
+use std::ops::Bound;
 
-use crate::{KvsEngine, KvsError, Result};
+use sled::{Batch, Db, Tree};
+
+use crate::{EngineStats, KvsEngine, KvsError, Result};
 
 #[derive(Clone)]
 pub struct SledKvsEngine(Db);
@@ -35,4 +37,62 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    /// sled 的 batch 是原子的，所有的 key 一起写入
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let tree: &Tree = &self.0;
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let tree: &Tree = &self.0;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// sled 自己管理空间，只报 key 的数量
+    fn stats(&self) -> Result<EngineStats> {
+        let tree: &Tree = &self.0;
+        Ok(EngineStats {
+            keys: tree.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        tree.scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn scan_after(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        tree.range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
 }
@@ -28,11 +28,119 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// The engine has no log to replicate from
+    #[fail(display = "Replication is not supported by this engine")]
+    ReplicationUnsupported,
+    /// Writing to a read-only replica
+    #[fail(display = "Read-only replica")]
+    ReadOnly,
+    /// Sent a request to a raft follower, the leader is at the given address
+    #[fail(display = "Not leader, the leader is {}", _0)]
+    NotLeader(String),
+    /// The raft cluster has no leader at the moment
+    #[fail(display = "No leader elected")]
+    NoLeader,
+    /// The peer broke the wire protocol
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// The peer sent a frame longer than the limit, `id` is the request it belongs to
+    #[fail(display = "Frame too large: {} bytes", len)]
+    FrameTooLarge { id: u32, len: u32 },
+    /// The server has as many connections as it accepts, retry later
+    #[fail(display = "Server busy")]
+    ServerBusy,
+    /// The writer thread panicked while handling the write
+    #[fail(display = "Writer panicked")]
+    WriterPanicked,
+    /// The connection isn't authenticated or the user may not access the key
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
+    /// Loading the certificates or setting up the TLS connection failed
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+    /// An I/O or protocol error the server reported, the connection itself is still fine
+    #[fail(display = "Server error: {}", message)]
+    Remote { code: ErrorCode, message: String },
+    /// The server found its data corrupted
+    #[fail(display = "Data corrupted: {}", _0)]
+    Corruption(String),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
 }
 
+/// What kind of error a server reports, so that the client can give back the same `KvsError`.
+///
+/// Permission and leader errors, and a busy server, have their own responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Other = 0,
+    KeyNotFound = 1,
+    Io = 2,
+    Corruption = 3,
+    ReadOnly = 4,
+    NoLeader = 5,
+    Unsupported = 6,
+    InvalidRequest = 7,
+}
+
+impl KvsError {
+    /// The code a server reports this error with.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Serde(_)
+            | KvsError::UnexpectedCommandType
+            | KvsError::ReaderNotFound
+            | KvsError::Utf8(_)
+            | KvsError::Corruption(_) => ErrorCode::Corruption,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::NoLeader => ErrorCode::NoLeader,
+            KvsError::ReplicationUnsupported => ErrorCode::Unsupported,
+            KvsError::Protocol(_) | KvsError::FrameTooLarge { .. } => ErrorCode::InvalidRequest,
+            KvsError::Remote { code, .. } => *code,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// The code sent as `code` on the wire, `None` for codes this version doesn't know.
+    pub fn from_u8(code: u8) -> Option<ErrorCode> {
+        match code {
+            0 => Some(ErrorCode::Other),
+            1 => Some(ErrorCode::KeyNotFound),
+            2 => Some(ErrorCode::Io),
+            3 => Some(ErrorCode::Corruption),
+            4 => Some(ErrorCode::ReadOnly),
+            5 => Some(ErrorCode::NoLeader),
+            6 => Some(ErrorCode::Unsupported),
+            7 => Some(ErrorCode::InvalidRequest),
+            _ => None,
+        }
+    }
+
+    /// Turns an error reported by a server back into a `KvsError`.
+    ///
+    /// 服务端发过来的只有错误信息，带着的 io::Error 之类的没法还原。
+    /// 服务端的 IO 和协议错误不能变成 `Io` 和 `Protocol`，那两个表示这边的连接坏了
+    pub fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::Io | ErrorCode::InvalidRequest => KvsError::Remote {
+                code: self,
+                message,
+            },
+            ErrorCode::Corruption => KvsError::Corruption(message),
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::NoLeader => KvsError::NoLeader,
+            ErrorCode::Unsupported => KvsError::ReplicationUnsupported,
+            ErrorCode::Other => KvsError::StringError(message),
+        }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
@@ -57,5 +165,11 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(format!("{}", err))
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,296 @@
+//! Request handling shared by the servers and the gateways in front of the engine.
+
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::auth::{Access, Session};
+use crate::common::{
+    AuthResponse, GetResponse, RemoveResponse, ReplicateResponse, Request, Response, SetResponse,
+};
+use crate::metrics::{Metrics, RequestType};
+use crate::protocol::{self, Frame};
+use crate::trace;
+use crate::{ErrorCode, KvsEngine, KvsError, Result};
+
+/// 关闭时等正在处理的请求完成的默认时间
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// 拒绝连接时最多等客户端多久，拒绝是在一个线程里挨个做的
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// 一次 `Scan` 最多返回多少个 pair，响应不会太大
+pub(crate) const MAX_SCAN_LIMIT: u32 = 1000;
+/// 一次 `Scan` 的 key 和 value 大约最多多少字节，离 `MAX_PAYLOAD_LEN` 留足余量，json 转义之后还会变大
+const MAX_SCAN_BYTES: usize = 4 * 1024 * 1024;
+
+/// Protects a server from too many, slow or stuck clients.
+///
+/// The timeouts must not be zero.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Connections beyond this many are answered with `ServerBusy` and closed.
+    pub max_connections: usize,
+    /// How long a connection may stay idle between two requests.
+    pub idle_timeout: Duration,
+    /// How long reading a request, or writing a response, may take.
+    pub request_timeout: Duration,
+    /// The largest request payload accepted, larger requests get an error and the connection is closed.
+    ///
+    /// The framed protocol never accepts more than `MAX_PAYLOAD_LEN`.
+    pub max_request_size: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 1024,
+            idle_timeout: Duration::from_secs(300),
+            request_timeout: Duration::from_secs(30),
+            max_request_size: protocol::MAX_PAYLOAD_LEN,
+        }
+    }
+}
+
+/// What clients speak to a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// The framed kvs protocol, or JSON for older clients.
+    Kvs,
+    /// The Redis protocol, see the `resp` module. Only over plain TCP.
+    Resp,
+}
+
+/// RESP 只支持明文的连接
+pub(crate) fn check_protocol(protocol: Protocol, tls: bool) -> Result<()> {
+    if protocol == Protocol::Resp && tls {
+        return Err(KvsError::Tls("RESP is only served over plain TCP".to_owned()));
+    }
+    Ok(())
+}
+
+/// The reply to a rejected connection in the protocol its first byte tells.
+///
+/// 旧协议的每种响应都有 `Err`，随便用一个编码出来都一样
+pub(crate) fn busy_reply(first: u8) -> Result<Vec<u8>> {
+    if first == protocol::MAGIC[0] {
+        protocol::busy()
+    } else if first == b'*' {
+        // redis 客户端的命令都是数组
+        Ok(b"-ERR max number of clients reached\r\n".to_vec())
+    } else {
+        Ok(serde_json::to_vec(&GetResponse::Err(format!(
+            "{}",
+            KvsError::ServerBusy
+        )))?)
+    }
+}
+
+/// Encodes a response, answering with an error instead when it's too large to send.
+pub(crate) fn encode(resp: &Response, id: u32) -> Result<Frame> {
+    match resp.to_frame(id) {
+        Err(KvsError::FrameTooLarge { id, len }) => {
+            warn!("Response {} of {} bytes is too large", id, len);
+            Response::Err(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "Response too large: {} bytes, at most {} can be sent",
+                    len,
+                    protocol::MAX_PAYLOAD_LEN
+                ),
+            )
+            .to_frame(id)
+        }
+        res => res,
+    }
+}
+
+/// 超过大小的请求没法跳过，回复之后就关闭连接
+pub(crate) fn too_large(id: u32, len: u32, limits: &Limits) -> Response {
+    warn!("Request {} of {} bytes is too large", id, len);
+    Response::Err(
+        ErrorCode::InvalidRequest,
+        format!(
+            "Request too large: {} bytes, at most {} are accepted",
+            len,
+            limits.max_request_size.min(protocol::MAX_PAYLOAD_LEN)
+        ),
+    )
+}
+
+/// 解不出来的请求是客户端的问题，不算数据损坏
+fn invalid_request(e: KvsError) -> Response {
+    match e {
+        KvsError::Protocol(message) => Response::Err(ErrorCode::InvalidRequest, message),
+        e => Response::Err(ErrorCode::InvalidRequest, format!("{}", e)),
+    }
+}
+
+/// Handles an `Auth` frame, `None` for every other request.
+///
+/// 认证改变的是连接的状态，在读请求的地方直接处理，不用经过 engine
+pub(crate) fn authenticate(
+    session: &mut Session,
+    frame: &Frame,
+    metrics: &Metrics,
+) -> Option<Response> {
+    if frame.code != protocol::OP_AUTH {
+        return None;
+    }
+    let started = Instant::now();
+    let resp = match Request::from_frame(frame) {
+        Ok(Request::Auth(credentials)) => session.authenticate(&credentials),
+        Ok(req) => Response::Err(
+            ErrorCode::InvalidRequest,
+            format!("Expected credentials, got {:?}", req),
+        ),
+        Err(e) => invalid_request(e),
+    };
+    metrics.record(RequestType::Auth, started, &resp);
+    Some(resp)
+}
+
+/// 帧的长度是已知的，不认识的请求回复错误就好，后面的帧不会错位
+pub(crate) fn respond<E: KvsEngine>(
+    engine: &E,
+    frame: &Frame,
+    read_only: bool,
+    access: &Access,
+    metrics: &Metrics,
+) -> Response {
+    match Request::from_frame(frame) {
+        Ok(req) => {
+            debug!("Receive request {}: {:?}", frame.id, req);
+            handle(engine, req, read_only, access, metrics)
+        }
+        Err(e) => invalid_request(e),
+    }
+}
+
+/// Handles a request of the JSON protocol, encoding the response the way older clients expect.
+pub(crate) fn respond_json<E: KvsEngine>(
+    engine: &E,
+    req: Request,
+    read_only: bool,
+    session: &mut Session,
+    metrics: &Metrics,
+) -> Result<Vec<u8>> {
+    let handle = |req| handle(engine, req, read_only, session.access(), metrics);
+    let resp = match req {
+        Request::Get { .. } => serde_json::to_vec(&GetResponse::from(handle(req))),
+        Request::Set { .. } => serde_json::to_vec(&SetResponse::from(handle(req))),
+        Request::Remove { .. } => serde_json::to_vec(&RemoveResponse::from(handle(req))),
+        Request::Replicate { .. } => serde_json::to_vec(&ReplicateResponse::from(handle(req))),
+        Request::Auth(credentials) => {
+            let started = Instant::now();
+            let resp = session.authenticate(&credentials);
+            metrics.record(RequestType::Auth, started, &resp);
+            serde_json::to_vec(&AuthResponse::from(resp))
+        }
+        // 旧协议没有对应的响应类型，所有的响应都能这样表示错误
+        Request::MGet { .. }
+        | Request::MSet { .. }
+        | Request::MRemove { .. }
+        | Request::Scan { .. } => serde_json::to_vec(&GetResponse::Err(
+            "Multi-key and scan requests need the framed protocol".to_owned(),
+        )),
+    };
+    Ok(resp?)
+}
+
+/// 旧协议的请求没有长度，读到上限还没读完就是太大了
+pub(crate) fn json_too_large(limits: &Limits) -> String {
+    warn!("JSON request larger than {} bytes", limits.max_request_size);
+    format!(
+        "Request too large, at most {} bytes are accepted",
+        limits.max_request_size
+    )
+}
+
+/// Handles a request and records it in `metrics`.
+pub(crate) fn handle<E: KvsEngine>(
+    engine: &E,
+    req: Request,
+    read_only: bool,
+    access: &Access,
+    metrics: &Metrics,
+) -> Response {
+    let started = Instant::now();
+    let ty = RequestType::of(&req);
+    let audit = trace::audit_entry(&req, access);
+    let resp = execute(engine, req, read_only, access);
+    metrics.record(ty, started, &resp);
+    if let Some(audit) = audit {
+        trace::audit(audit, &resp);
+    }
+    resp
+}
+
+fn execute<E: KvsEngine>(engine: &E, req: Request, read_only: bool, access: &Access) -> Response {
+    if let Err(e) = access.check(&req) {
+        debug!("Permission denied: {}", e);
+        return Response::Denied(e);
+    }
+    match req {
+        Request::Get { key } => match engine.get(key) {
+            Ok(value) => Response::Value(value),
+            Err(e) => Response::from_error(&e),
+        },
+        Request::Set { .. }
+        | Request::Remove { .. }
+        | Request::MSet { .. }
+        | Request::MRemove { .. }
+            if read_only =>
+        {
+            Response::from_error(&KvsError::ReadOnly)
+        }
+        Request::Set { key, value } => done(engine.set(key, value)),
+        Request::Remove { key } => done(engine.remove(key)),
+        // 读不经过 writer 线程，一个一个读就好
+        Request::MGet { keys } => match keys.into_iter().map(|key| engine.get(key)).collect() {
+            Ok(values) => Response::Values(values),
+            Err(e) => Response::from_error(&e),
+        },
+        Request::MSet { pairs } => done(engine.set_many(pairs)),
+        Request::MRemove { keys } => match engine.remove_many(keys) {
+            Ok(removed) => Response::Removed(removed),
+            Err(e) => Response::from_error(&e),
+        },
+        Request::Scan { after, limit } => {
+            match engine.scan_after(after, limit.min(MAX_SCAN_LIMIT) as usize) {
+                Ok(mut pairs) => {
+                    cap_scan_bytes(&mut pairs);
+                    Response::Pairs(pairs)
+                }
+                Err(e) => Response::from_error(&e),
+            }
+        }
+        Request::Replicate { from } => match engine.read_log(from) {
+            Ok(read) => Response::Log(read),
+            Err(e) => Response::from_error(&e),
+        },
+        // 连接的状态只有读请求的地方能改
+        Request::Auth(_) => Response::Err(
+            ErrorCode::InvalidRequest,
+            "Unexpected Auth request".to_owned(),
+        ),
+    }
+}
+
+/// Cuts a page of scanned pairs at about `MAX_SCAN_BYTES`.
+///
+/// 超过预算就截断，但至少返回一个 pair，客户端才能接着翻页
+pub(crate) fn cap_scan_bytes(pairs: &mut Vec<(String, String)>) {
+    let mut bytes = 0;
+    let end = pairs.iter().position(|(key, value)| {
+        let full = bytes >= MAX_SCAN_BYTES;
+        bytes += key.len() + value.len();
+        full
+    });
+    pairs.truncate(end.unwrap_or(pairs.len()));
+}
+
+fn done(res: Result<()>) -> Response {
+    match res {
+        Ok(()) => Response::Ok,
+        Err(e) => Response::from_error(&e),
+    }
+}
@@ -0,0 +1,521 @@
+//! An HTTP/JSON gateway to the engine, for services without a kvs client.
+//!
+//! - `GET /keys/{key}` returns the value as the body, `404` if there's none
+//! - `PUT /keys/{key}` sets the key to the body
+//! - `DELETE /keys/{key}` removes the key, `404` if there's none
+//! - `GET /keys?prefix=...&limit=...&after=...` returns a page of the matching pairs, in key order,
+//!   as `{"pairs": {...}, "next": ...}`. Pass `next` as `after` for the next page, it's `null`
+//!   on the last one. `limit` defaults to and is capped at 1000 pairs, large values make pages
+//!   smaller
+//! - `GET /health` and `GET /stats`
+//!
+//! Keys are percent-encoded, everything after `/keys/` is the key, so `/keys/app/key1` and
+//! `/keys/app%2Fkey1` are the same key. Errors are returned as `{"error": "..."}`.
+//!
+//! With an `Acl`, requests to `/keys` must send `Authorization: Bearer <token>`, the gateway
+//! doesn't accept passwords.
+
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request as HttpRequest, Response as HttpResponse, Server, StatusCode};
+use log::debug;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::time;
+
+use crate::auth::{Access, Session};
+use crate::common::{Request, Response};
+use crate::metrics::{Metrics, RequestType};
+use crate::handler::{self, Limits};
+use crate::trace::Span;
+use crate::{
+    Acl, AsyncEngine, Credentials, ErrorCode, KvsEngine, KvsError, Result, ShutdownHandle,
+};
+
+/// Serves the REST API described in the module docs, next to the native protocol.
+pub struct HttpGateway<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+}
+
+/// 每个请求一份，`KvStore` 不是 `Sync` 的，不能放在 `Arc` 里共享，
+/// 处理请求的 future 要拿着它的所有权，不能借用
+#[derive(Clone)]
+struct Gateway<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+    stats: Arc<Stats>,
+}
+
+/// What `/stats` reports.
+struct Stats {
+    started: Instant,
+    gets: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    scans: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+}
+
+impl<E: KvsEngine> HttpGateway<E> {
+    pub fn new(engine: E) -> Self {
+        HttpGateway {
+            engine: AsyncEngine::new(engine),
+            read_only: false,
+            acl: None,
+            limits: Limits::default(),
+            metrics: Metrics::new(),
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// A read-only gateway rejects `PUT` and `DELETE`, used for replicas.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Requires a bearer token, and limits each user to the keys `acl` allows.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(acl);
+    }
+
+    /// Bodies larger than `max_request_size` are rejected, and reading a request may take at
+    /// most `request_timeout`. The other limits don't apply to HTTP.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Records the requests in `metrics`, usually the ones of the native server.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Returns a handle which makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let incoming = AddrIncoming::from_listener(listener).map_err(http_error)?;
+        let gateway = Gateway {
+            engine: self.engine,
+            read_only: self.read_only,
+            acl: self.acl,
+            limits: self.limits,
+            metrics: self.metrics,
+            stats: Arc::new(Stats::new()),
+        };
+        let make_service = make_service_fn(move |_| {
+            let gateway = gateway.clone();
+            // 连接的任务是 hyper 起的，span 只能包在每个请求的 future 外面
+            let span = Span::connection();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let gateway = gateway.clone();
+                    span.request()
+                        .scope(async move { Ok::<_, Infallible>(gateway.route(req).await) })
+                }))
+            }
+        });
+        let shutdown = self.shutdown;
+        Server::builder(incoming)
+            .http1_header_read_timeout(self.limits.request_timeout)
+            .serve(make_service)
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await
+            .map_err(http_error)
+    }
+}
+
+impl<E: KvsEngine> Gateway<E> {
+    async fn route(self, req: HttpRequest<Body>) -> HttpResponse<Body> {
+        debug!("HTTP request: {} {}", req.method(), req.uri());
+        let stats = Arc::clone(&self.stats);
+        let path = req.uri().path().to_owned();
+        let resp = match (req.method(), path.as_str()) {
+            (&Method::GET, "/health") => json_response(StatusCode::OK, json!({ "status": "ok" })),
+            (&Method::GET, "/stats") => {
+                json_response(StatusCode::OK, stats.to_json(self.read_only))
+            }
+            (&Method::GET, "/keys") => self.scan(req).await,
+            (_, "/keys") => method_not_allowed("GET"),
+            (_, path) => match path.strip_prefix("/keys/") {
+                Some(key) => self.key(req, key).await,
+                None => error_response(StatusCode::NOT_FOUND, "Not found"),
+            },
+        };
+        stats.count_status(resp.status());
+        resp
+    }
+
+    async fn key(self, req: HttpRequest<Body>, key: &str) -> HttpResponse<Body> {
+        let key = match percent_decode(key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return error_response(StatusCode::BAD_REQUEST, "Invalid key"),
+        };
+        let access = match self.access(&req) {
+            Ok(access) => access,
+            Err(e) => return unauthorized(e),
+        };
+        let request = match *req.method() {
+            Method::GET => {
+                self.stats.gets.fetch_add(1, Ordering::Relaxed);
+                Request::Get { key }
+            }
+            Method::PUT => {
+                self.stats.puts.fetch_add(1, Ordering::Relaxed);
+                match read_body(req.into_body(), self.limits).await {
+                    Ok(value) => Request::Set { key, value },
+                    Err((status, e)) => return error_response(status, &e),
+                }
+            }
+            Method::DELETE => {
+                self.stats.deletes.fetch_add(1, Ordering::Relaxed);
+                Request::Remove { key }
+            }
+            _ => return method_not_allowed("GET, PUT, DELETE"),
+        };
+        let read_only = self.read_only;
+        let metrics = self.metrics.clone();
+        let span = Span::current().unwrap_or_default();
+        let resp = self
+            .engine
+            .run(move |engine| {
+                let _span = span.enter();
+                handler::handle(engine, request, read_only, &access, &metrics)
+            })
+            .await
+            .unwrap_or_else(|e| Response::from_error(&e));
+        match resp {
+            Response::Value(Some(value)) => HttpResponse::builder()
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(value))
+                .unwrap(),
+            Response::Value(None) => error_response(StatusCode::NOT_FOUND, "Key not found"),
+            Response::Ok => empty_response(StatusCode::NO_CONTENT),
+            resp => from_response(resp),
+        }
+    }
+
+    async fn scan(self, req: HttpRequest<Body>) -> HttpResponse<Body> {
+        self.stats.scans.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let query = req.uri().query().unwrap_or("");
+        let prefix = match percent_decode(query_param(query, "prefix").unwrap_or(""), true) {
+            Some(prefix) => prefix,
+            None => return error_response(StatusCode::BAD_REQUEST, "Invalid prefix"),
+        };
+        let after = match query_param(query, "after").map(|after| percent_decode(after, true)) {
+            Some(Some(after)) => Some(after),
+            Some(None) => return error_response(StatusCode::BAD_REQUEST, "Invalid after"),
+            None => None,
+        };
+        let limit = match query_param(query, "limit").map(str::parse::<u32>) {
+            Some(Ok(limit)) if limit > 0 => limit.min(handler::MAX_SCAN_LIMIT),
+            Some(_) => return error_response(StatusCode::BAD_REQUEST, "Invalid limit"),
+            None => handler::MAX_SCAN_LIMIT,
+        };
+        let access = match self.access(&req) {
+            Ok(access) => access,
+            Err(e) => return unauthorized(e),
+        };
+        // 扫出来的 key 都以 prefix 开头，能读 prefix 就能读所有结果
+        if let Err(e) = access.check_key(&prefix, false) {
+            let resp = Response::Denied(e);
+            self.metrics.record(RequestType::Scan, started, &resp);
+            return from_response(resp);
+        }
+        let span = Span::current().unwrap_or_default();
+        let page = self
+            .engine
+            .run(move |engine| {
+                let _span = span.enter();
+                scan_page(engine, &prefix, after, limit as usize)
+            })
+            .await
+            .and_then(|page| page);
+        match page {
+            Ok((pairs, next)) => {
+                self.metrics
+                    .record(RequestType::Scan, started, &Response::Ok);
+                let pairs: serde_json::Map<_, _> = pairs
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::String(value)))
+                    .collect();
+                json_response(StatusCode::OK, json!({ "pairs": pairs, "next": next }))
+            }
+            Err(e) => {
+                let resp = Response::from_error(&e);
+                self.metrics.record(RequestType::Scan, started, &resp);
+                from_response(resp)
+            }
+        }
+    }
+
+    /// 每个 HTTP 请求单独认证，认证失败返回原因
+    fn access(&self, req: &HttpRequest<Body>) -> std::result::Result<Access, &'static str> {
+        if self.acl.is_none() {
+            return Ok(Access::All);
+        }
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = match token {
+            Some(token) => token.trim().to_owned(),
+            None => return Err("Missing bearer token"),
+        };
+        let mut session = Session::new(self.acl.clone());
+        match session.authenticate(&Credentials::Token(token)) {
+            Response::Ok => Ok(session.access().clone()),
+            _ => Err("Invalid credentials"),
+        }
+    }
+}
+
+/// A page of the pairs whose key starts with `prefix`, and the key to pass as `after` if there are more.
+///
+/// 和 RESP 的 SCAN 一样，第一页从前缀开始：前缀本身单独查，其他带这个前缀的 key 都排在它后面
+fn scan_page<E: KvsEngine>(
+    engine: &E,
+    prefix: &str,
+    after: Option<String>,
+    limit: usize,
+) -> Result<(Vec<(String, String)>, Option<String>)> {
+    let mut pairs = Vec::new();
+    let start = match after {
+        Some(after) if after.as_str() >= prefix => Some(after),
+        _ if prefix.is_empty() => None,
+        _ => {
+            if let Some(value) = engine.get(prefix.to_owned())? {
+                pairs.push((prefix.to_owned(), value));
+            }
+            Some(prefix.to_owned())
+        }
+    };
+    let page = engine.scan_after(start, limit)?;
+    let mut done = page.len() < limit;
+    for (key, value) in page {
+        if !key.starts_with(prefix) {
+            done = true;
+            break;
+        }
+        pairs.push((key, value));
+    }
+    if pairs.len() > limit {
+        pairs.truncate(limit);
+        done = false;
+    }
+    let len = pairs.len();
+    handler::cap_scan_bytes(&mut pairs);
+    done &= pairs.len() == len;
+    let next = match pairs.last() {
+        Some((key, _)) if !done => Some(key.clone()),
+        _ => None,
+    };
+    Ok((pairs, next))
+}
+
+/// The raw value of a query parameter, the first one if it's repeated.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|param| {
+        param
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+    })
+}
+
+/// Reads a `PUT` body, at most `max_request_size` bytes of UTF-8, or the status to fail with.
+async fn read_body(
+    mut body: Body,
+    limits: Limits,
+) -> std::result::Result<String, (StatusCode, String)> {
+    let max = limits.max_request_size as u64;
+    if body.size_hint().lower() > max {
+        return Err(too_large(max));
+    }
+    let read = async {
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            // 没有 Content-Length 的分块请求只能边读边检查
+            if (buf.len() + chunk.len()) as u64 > max {
+                return Err(too_large(max));
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf)
+    };
+    let buf = match time::timeout(limits.request_timeout, read).await {
+        Ok(buf) => buf?,
+        Err(_) => return Err((StatusCode::REQUEST_TIMEOUT, "Request timed out".to_owned())),
+    };
+    String::from_utf8(buf).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "The value must be UTF-8".to_owned(),
+        )
+    })
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            gets: AtomicU64::new(0),
+            puts: AtomicU64::new(0),
+            deletes: AtomicU64::new(0),
+            scans: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+        }
+    }
+
+    fn count_status(&self, status: StatusCode) {
+        // 找不到 key 是正常的结果，不算错误
+        if status.is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        } else if status.is_client_error() && status != StatusCode::NOT_FOUND {
+            self.client_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn to_json(&self, read_only: bool) -> Value {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "read_only": read_only,
+            "requests": {
+                "get": load(&self.gets),
+                "put": load(&self.puts),
+                "delete": load(&self.deletes),
+                "scan": load(&self.scans),
+            },
+            "client_errors": load(&self.client_errors),
+            "server_errors": load(&self.server_errors),
+        })
+    }
+}
+
+/// Maps the responses `handler::handle` fails with to an HTTP status.
+fn from_response(resp: Response) -> HttpResponse<Body> {
+    match resp {
+        Response::Err(code, message) => {
+            let status = match code {
+                ErrorCode::KeyNotFound => StatusCode::NOT_FOUND,
+                ErrorCode::ReadOnly => StatusCode::FORBIDDEN,
+                ErrorCode::NoLeader => StatusCode::SERVICE_UNAVAILABLE,
+                ErrorCode::Unsupported => StatusCode::NOT_IMPLEMENTED,
+                ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+                ErrorCode::Io | ErrorCode::Corruption | ErrorCode::Other => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            };
+            error_response(status, &code.into_error(message).to_string())
+        }
+        // leader 的地址是原生协议的端口，HTTP 客户端用不了
+        Response::Redirect(leader) => error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &KvsError::NotLeader(leader).to_string(),
+        ),
+        Response::Denied(e) => error_response(
+            StatusCode::FORBIDDEN,
+            &KvsError::PermissionDenied(e).to_string(),
+        ),
+        Response::Busy => error_response(StatusCode::SERVICE_UNAVAILABLE, "Server busy"),
+        resp => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("Unexpected response {:?}", resp),
+        ),
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> HttpResponse<Body> {
+    HttpResponse::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> HttpResponse<Body> {
+    json_response(status, json!({ "error": message }))
+}
+
+fn empty_response(status: StatusCode) -> HttpResponse<Body> {
+    HttpResponse::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn unauthorized(message: &str) -> HttpResponse<Body> {
+    let mut resp = error_response(StatusCode::UNAUTHORIZED, message);
+    resp.headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    resp
+}
+
+fn method_not_allowed(allow: &'static str) -> HttpResponse<Body> {
+    let mut resp = error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    resp.headers_mut()
+        .insert(header::ALLOW, HeaderValue::from_static(allow));
+    resp
+}
+
+fn too_large(max: u64) -> (StatusCode, String) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("Request too large, the limit is {} bytes", max),
+    )
+}
+
+fn http_error(e: hyper::Error) -> KvsError {
+    KvsError::StringError(format!("HTTP gateway failed: {}", e))
+}
+
+/// 解码 URL 里的 `%XX`，查询参数里的 `+` 是空格
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
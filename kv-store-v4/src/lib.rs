@@ -1,13 +1,47 @@
 mod error;
+mod auth;
 mod common;
+mod protocol;
+mod handler;
+#[cfg(feature = "sync-server")]
 mod server;
 mod client;
 mod engines;
+mod async_server;
+mod async_client;
+mod shutdown;
+mod tls;
+mod http;
+mod resp;
+mod metrics;
+mod trace;
+mod dump;
+mod pool;
+mod lock;
 
-pub use client::KvsClient;
+pub use client::{KvsClient, MultiplexedKvsClient, Pipeline};
+pub use pool::{KvsClientPool, PooledClient};
+#[cfg(feature = "sync-server")]
 pub use server::KvsServer;
-pub use error::{KvsError, Result};
-pub use engines::{KvsEngine, KvStore, SledKvsEngine};
+pub use handler::{Limits, Protocol};
+pub use protocol::MAX_PAYLOAD_LEN;
+pub use async_server::AsyncKvsServer;
+pub use async_client::AsyncKvsClient;
+pub use shutdown::ShutdownHandle;
+pub use auth::{hash_password, Acl, Credentials};
+pub use tls::{ClientTls, ServerTls};
+pub use http::HttpGateway;
+pub use metrics::{Metrics, MetricsServer};
+pub use trace::{Span, AUDIT_TARGET};
+pub use dump::{DumpFormat, DumpReader, DumpWriter};
+pub use lock::DirLock;
+pub use error::{ErrorCode, KvsError, Result};
+pub use engines::{
+    AsyncEngine, Command, EngineStats, KvsEngine, KvStore, KvStoreOptions, ShardedKvStore,
+    SledKvsEngine,
+};
 
+pub mod raft;
+pub mod replication;
 pub mod thread_pool;
-pub use  thread_pool::NaiveThreadPool;
\ No newline at end of file
+pub use  thread_pool::NaiveThreadPool;
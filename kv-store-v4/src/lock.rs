@@ -0,0 +1,35 @@
+//! A lock file that keeps other processes out of a data directory.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+/// 锁文件的名字，进程退出的时候系统会释放锁，不用担心留下一个过期的锁
+const LOCK_FILE: &str = "LOCK";
+
+/// Holds the lock of a data directory until dropped.
+///
+/// A running `kvs-server` holds it, so that offline tools can't touch the data underneath.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing if another process such as a running server holds it.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(KvsError::StringError(format!(
+                "{} is in use, stop the server first",
+                dir.display()
+            ))),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Server metrics in the Prometheus text format.
+//!
+//! A `Metrics` registry is shared by the servers and the HTTP gateway, `MetricsServer` serves it
+//! on `GET /metrics` together with the `EngineStats` of the engine:
+//!
+//! - `kvs_requests_total{type}` and `kvs_request_duration_seconds{type}`, a histogram
+//! - `kvs_request_errors_total{type,code}`
+//! - `kvs_connections_active` and `kvs_connections_rejected_total`
+//! - `kvs_pool_queue_depth`, connections accepted but not picked up by a pool thread yet
+//! - `kvs_engine_keys`, `kvs_engine_generations` and `kvs_engine_stale_bytes`
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::header;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request as HttpRequest, Response as HttpResponse, Server, StatusCode};
+use log::{error, warn};
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+use crate::common::{Request, Response};
+use crate::{AsyncEngine, EngineStats, ErrorCode, KvsEngine, KvsError, Result, ShutdownHandle};
+
+/// 延迟直方图的上界，单位是秒
+const BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// The `type` label of the request metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestType {
+    Get,
+    Set,
+    Remove,
+    MGet,
+    MSet,
+    MRemove,
+    Replicate,
+    Auth,
+    Scan,
+}
+
+impl RequestType {
+    const ALL: [RequestType; 9] = [
+        RequestType::Get,
+        RequestType::Set,
+        RequestType::Remove,
+        RequestType::MGet,
+        RequestType::MSet,
+        RequestType::MRemove,
+        RequestType::Replicate,
+        RequestType::Auth,
+        RequestType::Scan,
+    ];
+
+    pub(crate) fn of(req: &Request) -> RequestType {
+        match req {
+            Request::Get { .. } => RequestType::Get,
+            Request::Set { .. } => RequestType::Set,
+            Request::Remove { .. } => RequestType::Remove,
+            Request::MGet { .. } => RequestType::MGet,
+            Request::MSet { .. } => RequestType::MSet,
+            Request::MRemove { .. } => RequestType::MRemove,
+            Request::Replicate { .. } => RequestType::Replicate,
+            Request::Auth(_) => RequestType::Auth,
+            Request::Scan { .. } => RequestType::Scan,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RequestType::Get => "get",
+            RequestType::Set => "set",
+            RequestType::Remove => "remove",
+            RequestType::MGet => "mget",
+            RequestType::MSet => "mset",
+            RequestType::MRemove => "mremove",
+            RequestType::Replicate => "replicate",
+            RequestType::Auth => "auth",
+            RequestType::Scan => "scan",
+        }
+    }
+}
+
+/// The counters of a server, cheap to clone and share between threads.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: [Histogram; RequestType::ALL.len()],
+    /// 按请求类型和错误码分开计数，出错的请求不多，用锁就够了
+    errors: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    connections: AtomicI64,
+    rejected: AtomicU64,
+    queued: AtomicI64,
+    /// 0 的时候不记慢请求
+    slow_micros: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// 每个桶只记落在自己范围里的次数，输出的时候再累加，最后一个是 `+Inf`
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+/// Keeps a gauge incremented while it lives.
+pub(crate) struct GaugeGuard {
+    metrics: Metrics,
+    gauge: fn(&Registry) -> &AtomicI64,
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics.inner).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Logs a warning for every request taking longer than `threshold`, zero turns it off.
+    pub fn set_slow_request_threshold(&self, threshold: Duration) {
+        self.inner
+            .slow_micros
+            .store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a request which started at `started` and was answered with `resp`.
+    pub(crate) fn record(&self, ty: RequestType, started: Instant, resp: &Response) {
+        let elapsed = started.elapsed();
+        let histogram = &self.inner.requests[ty as usize];
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.count.fetch_add(1, Ordering::Relaxed);
+        histogram
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        let slow = self.inner.slow_micros.load(Ordering::Relaxed);
+        if slow > 0 && elapsed.as_micros() as u64 >= slow {
+            warn!("Slow {} request took {:?}", ty.name(), elapsed);
+        }
+
+        if let Some(code) = error_code(resp) {
+            *self
+                .inner
+                .errors
+                .lock()
+                .unwrap()
+                .entry((ty.name(), code))
+                .or_insert(0) += 1;
+        }
+    }
+
+    /// Counts a connection as active until the guard is dropped.
+    pub(crate) fn connection(&self) -> GaugeGuard {
+        self.track(|registry| &registry.connections)
+    }
+
+    /// Counts a connection waiting for a pool thread until the guard is dropped.
+    pub(crate) fn queued(&self) -> GaugeGuard {
+        self.track(|registry| &registry.queued)
+    }
+
+    pub(crate) fn rejected(&self) {
+        self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn track(&self, gauge: fn(&Registry) -> &AtomicI64) -> GaugeGuard {
+        gauge(&self.inner).fetch_add(1, Ordering::Relaxed);
+        GaugeGuard {
+            metrics: self.clone(),
+            gauge,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format, the engine ones only with `engine`.
+    pub fn render(&self, engine: Option<EngineStats>) -> String {
+        let registry = &*self.inner;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        // 写进 String 不会失败
+        let mut out = String::new();
+
+        describe(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests handled, by type.",
+        );
+        for ty in RequestType::ALL {
+            let histogram = &registry.requests[ty as usize];
+            let _ = writeln!(
+                out,
+                "kvs_requests_total{{type=\"{}\"}} {}",
+                ty.name(),
+                load(&histogram.count)
+            );
+        }
+
+        describe(
+            &mut out,
+            "kvs_request_errors_total",
+            "counter",
+            "Requests answered with an error, by type and error code.",
+        );
+        for ((ty, code), count) in registry.errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "kvs_request_errors_total{{type=\"{}\",code=\"{}\"}} {}",
+                ty, code, count
+            );
+        }
+
+        describe(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time taken to handle a request, by type.",
+        );
+        for ty in RequestType::ALL {
+            let histogram = &registry.requests[ty as usize];
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += load(bucket);
+                let le = match BUCKETS.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                    ty.name(),
+                    le,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{type=\"{}\"}} {}",
+                ty.name(),
+                load(&histogram.sum_micros) as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{type=\"{}\"}} {}",
+                ty.name(),
+                load(&histogram.count)
+            );
+        }
+
+        gauge(
+            &mut out,
+            "kvs_connections_active",
+            "Connections being served or waiting for a thread.",
+            registry.connections.load(Ordering::Relaxed),
+        );
+        describe(
+            &mut out,
+            "kvs_connections_rejected_total",
+            "counter",
+            "Connections rejected because the server was full.",
+        );
+        let _ = writeln!(
+            out,
+            "kvs_connections_rejected_total {}",
+            load(&registry.rejected)
+        );
+        gauge(
+            &mut out,
+            "kvs_pool_queue_depth",
+            "Connections waiting for a thread of the pool.",
+            registry.queued.load(Ordering::Relaxed),
+        );
+
+        if let Some(engine) = engine {
+            gauge(
+                &mut out,
+                "kvs_engine_keys",
+                "Keys stored by the engine.",
+                engine.keys as i64,
+            );
+            gauge(
+                &mut out,
+                "kvs_engine_generations",
+                "Log files of the engine.",
+                engine.generations as i64,
+            );
+            gauge(
+                &mut out,
+                "kvs_engine_stale_bytes",
+                "Bytes of the log the next compaction frees.",
+                engine.stale_bytes as i64,
+            );
+        }
+        out
+    }
+}
+
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    describe(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// The `code` label of a failed request, `None` if it succeeded.
+///
+/// 找不到 key 也算错误，`Get` 找不到返回的是 `Value(None)`，不会算进来
+fn error_code(resp: &Response) -> Option<&'static str> {
+    match resp {
+        Response::Err(code, _) => Some(match code {
+            ErrorCode::Other => "other",
+            ErrorCode::KeyNotFound => "key_not_found",
+            ErrorCode::Io => "io",
+            ErrorCode::Corruption => "corruption",
+            ErrorCode::ReadOnly => "read_only",
+            ErrorCode::NoLeader => "no_leader",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::InvalidRequest => "invalid_request",
+        }),
+        Response::Redirect(_) => Some("not_leader"),
+        Response::Denied(_) => Some("permission_denied"),
+        Response::Busy => Some("busy"),
+        _ => None,
+    }
+}
+
+/// Serves `GET /metrics` for Prometheus to scrape.
+pub struct MetricsServer<E: KvsEngine> {
+    engine: AsyncEngine<E>,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+}
+
+impl<E: KvsEngine> MetricsServer<E> {
+    /// Serves `metrics`, and the stats of `engine` read on every scrape.
+    pub fn new(engine: E, metrics: Metrics) -> Self {
+        MetricsServer {
+            engine: AsyncEngine::new(engine),
+            metrics,
+            shutdown: ShutdownHandle::new(),
+        }
+    }
+
+    /// Returns a handle which makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let incoming = AddrIncoming::from_listener(listener).map_err(http_error)?;
+        let engine = self.engine;
+        let metrics = self.metrics;
+        let make_service = make_service_fn(move |_| {
+            let engine = engine.clone();
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let resp = scrape(&engine, &metrics, req);
+                    async move { Ok::<_, Infallible>(resp.await) }
+                }))
+            }
+        });
+        let shutdown = self.shutdown;
+        Server::builder(incoming)
+            .serve(make_service)
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await
+            .map_err(http_error)
+    }
+}
+
+/// 和 `AsyncEngine` 一样，返回的 future 不能借用 engine
+fn scrape<E: KvsEngine>(
+    engine: &AsyncEngine<E>,
+    metrics: &Metrics,
+    req: HttpRequest<Body>,
+) -> impl std::future::Future<Output = HttpResponse<Body>> {
+    let found = req.method() == Method::GET && req.uri().path() == "/metrics";
+    let stats = found.then(|| engine.run(|engine| engine.stats()));
+    let metrics = metrics.clone();
+    async move {
+        let stats = match stats {
+            Some(stats) => stats.await,
+            None => {
+                return HttpResponse::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        // engine 出错的时候其他的指标照样返回
+        let stats = match stats.and_then(|stats| stats) {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                error!("Cannot read engine stats: {}", e);
+                None
+            }
+        };
+        HttpResponse::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render(stats)))
+            .unwrap()
+    }
+}
+
+fn http_error(e: hyper::Error) -> KvsError {
+    KvsError::StringError(format!("Metrics server failed: {}", e))
+}
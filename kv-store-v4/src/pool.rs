@@ -0,0 +1,358 @@
+//! A pool of `KvsClient` connections shared by many threads.
+//!
+//! Connections are opened when needed, up to the size of the pool, and reused after.
+//! A connection that fails is closed together with the idle ones, which most likely lost
+//! their server too, and the next checkout reconnects with exponential backoff. A connection
+//! the server turned away as busy is closed alone, and reads wait with the same backoff before
+//! trying again.
+
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::{ClientTls, Credentials, KvsClient, KvsError, Result};
+
+const DEFAULT_SIZE: usize = 8;
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A thread-safe pool of connections to one server.
+///
+/// Gets, multi-key gets and scans are retried on a new connection when the connection is
+/// lost, writes are not since the server may have applied them.
+pub struct KvsClientPool {
+    addrs: Vec<SocketAddr>,
+    tls: Option<ClientTls>,
+    credentials: Option<Credentials>,
+    size: usize,
+    health_check_interval: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_retries: u32,
+    checkout_timeout: Duration,
+    state: Mutex<State>,
+    /// 有连接还回来或者关掉时通知等着的线程
+    released: Condvar,
+}
+
+struct State {
+    idle: Vec<Idle>,
+    /// 所有的连接，包括借出去的和正在建立的
+    open: usize,
+}
+
+struct Idle {
+    client: KvsClient,
+    since: Instant,
+}
+
+/// A connection checked out of a `KvsClientPool`, returned to it when dropped.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl KvsClientPool {
+    /// Creates an empty pool, no connection is opened until one is needed.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(KvsError::StringError("No address to connect to".to_owned()));
+        }
+        Ok(KvsClientPool {
+            addrs,
+            tls: None,
+            credentials: None,
+            size: DEFAULT_SIZE,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_retries: DEFAULT_MAX_RETRIES,
+            checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Sets the most connections open at once, 8 by default.
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size.max(1);
+    }
+
+    /// Connects over TLS, checking the server certificate against `tls`.
+    pub fn set_tls(&mut self, tls: ClientTls) {
+        self.tls = Some(tls);
+    }
+
+    /// Authenticates every new connection with `credentials`.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Checks that a connection idle for longer than this still works before handing it out.
+    pub fn set_health_check_interval(&mut self, interval: Duration) {
+        self.health_check_interval = interval;
+    }
+
+    /// Sets the wait before the first reconnect, doubled after each failure up to `max`.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+    }
+
+    /// Sets how many times connecting, and a get on a lost connection, are retried.
+    pub fn set_max_retries(&mut self, retries: u32) {
+        self.max_retries = retries;
+    }
+
+    /// Sets how long a checkout waits for a connection when they're all in use.
+    pub fn set_checkout_timeout(&mut self, timeout: Duration) {
+        self.checkout_timeout = timeout;
+    }
+
+    /// The number of connections open, in use or idle.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// Takes a connection for several requests in a row.
+    pub fn checkout(&self) -> Result<PooledClient<'_>> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                let mut client = idle.client;
+                // 闲置久了的连接可能已经被服务器或者中间的设备断开
+                if idle.since.elapsed() < self.health_check_interval {
+                    return Ok(self.wrap(client));
+                }
+                match client.get(String::new()) {
+                    Err(e) if is_connection_error(&e) => {
+                        debug!("Closing a broken pooled connection: {}", e);
+                        self.close(true);
+                    }
+                    _ => return Ok(self.wrap(client)),
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            if state.open < self.size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(client) => Ok(self.wrap(client)),
+                    Err(e) => {
+                        self.close(false);
+                        Err(e)
+                    }
+                };
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(KvsError::StringError(
+                    "Timed out waiting for a pooled connection".to_owned(),
+                ));
+            }
+            state = self.released.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Gets the value of a key, retried when the connection is lost.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.retry(|client| client.get(key.clone()))
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.once(|client| client.set(key, value))
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.once(|client| client.remove(key))
+    }
+
+    /// Gets several keys in one round trip, retried when the connection is lost.
+    pub fn mget(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.retry(|client| client.mget(keys.clone()))
+    }
+
+    /// Sets several keys in one round trip.
+    pub fn mset(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.once(|client| client.mset(pairs))
+    }
+
+    /// Removes several keys in one round trip, returning for each whether it existed.
+    pub fn mremove(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.once(|client| client.mremove(keys))
+    }
+
+    /// Returns at most `limit` pairs after the key `after`, retried when the connection is lost.
+    pub fn scan(&self, after: Option<String>, limit: u32) -> Result<Vec<(String, String)>> {
+        self.retry(|client| client.scan(after.clone(), limit))
+    }
+
+    fn once<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        let mut client = self.checkout()?;
+        let res = f(&mut client);
+        if let Err(e) = &res {
+            if is_connection_error(e) {
+                client.discard_after(e);
+            }
+        }
+        res
+    }
+
+    /// 只给幂等的读请求用，写请求可能已经在服务器上生效了
+    fn retry<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut KvsClient) -> Result<T>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            let mut client = self.checkout()?;
+            match f(&mut client) {
+                Err(e) if is_connection_error(&e) => {
+                    client.discard_after(&e);
+                    if retries == self.max_retries {
+                        return Err(e);
+                    }
+                    // 马上重试只会再被拒一次，等别的连接断开
+                    if let KvsError::ServerBusy = e {
+                        warn!("Server busy, retrying in {:?}", backoff);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(self.max_backoff);
+                    } else {
+                        warn!("Connection lost, retrying: {}", e);
+                    }
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// 连不上的时候按指数退避重试
+    fn connect(&self) -> Result<KvsClient> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match self.try_connect() {
+                Ok(client) => return Ok(client),
+                Err(e) if retries < self.max_retries && is_connection_error(&e) => {
+                    warn!(
+                        "Failed to connect to {}, retrying in {:?}: {}",
+                        self.addrs[0], backoff, e
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn try_connect(&self) -> Result<KvsClient> {
+        let mut client = match &self.tls {
+            Some(tls) => KvsClient::connect_tls(&self.addrs[..], tls)?,
+            None => KvsClient::connect(&self.addrs[..])?,
+        };
+        if let Some(credentials) = &self.credentials {
+            client.auth(credentials.clone())?;
+        }
+        Ok(client)
+    }
+
+    fn wrap(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Forgets a closed connection, and the idle ones too when `lost` says the server went away.
+    fn close(&self, lost: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        if lost {
+            // 空闲连接在锁外面关掉
+            let idle = mem::take(&mut state.idle);
+            state.open -= idle.len();
+            drop(state);
+            drop(idle);
+            self.released.notify_all();
+        } else {
+            self.released.notify_one();
+        }
+    }
+}
+
+impl PooledClient<'_> {
+    /// Closes the connection instead of returning it, for one that failed.
+    ///
+    /// The idle connections of the pool are closed too, they likely lost the server as well.
+    pub fn discard(self) {
+        self.close(true);
+    }
+
+    /// 服务器忙只是拒绝了这一个连接，别的连接还能用
+    fn discard_after(self, e: &KvsError) {
+        self.close(!matches!(e, KvsError::ServerBusy));
+    }
+
+    fn close(mut self, lost: bool) {
+        if self.client.take().is_some() {
+            self.pool.close(lost);
+        }
+    }
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.state.lock().unwrap().idle.push(Idle {
+                client,
+                since: Instant::now(),
+            });
+            self.pool.released.notify_one();
+        }
+    }
+}
+
+/// 这些错误说明连接已经不能用了，服务器返回的错误都是 `Remote` 之类的，不影响连接。
+/// 服务器忙的时候会在回复之后关掉连接
+fn is_connection_error(e: &KvsError) -> bool {
+    matches!(
+        e,
+        KvsError::Io(_) | KvsError::Protocol(_) | KvsError::ServerBusy
+    )
+}
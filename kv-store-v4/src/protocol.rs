@@ -0,0 +1,530 @@
+//! The framed binary protocol between `KvsClient` and `KvsServer`.
+//!
+//! A connection starts with a handshake: the client sends `MAGIC` followed by
+//! the version it speaks, the server answers the same 4 bytes with the version
+//! it accepted. After that every message is a frame:
+//!
+//! ```text
+//! | version: u8 | request id: u32 | code: u8 | payload length: u32 | payload |
+//! ```
+//!
+//! Integers are big endian. For requests `code` is the opcode, for responses it
+//! is the status. Strings in the payload are prefixed with their `u32` length,
+//! lists with their `u32` number of items.
+//!
+//! Responses carry the id of their request. They come back in order, unless
+//! the client sent `OP_MULTIPLEX`, after which the server handles requests
+//! concurrently and answers each as soon as it's done. It works on a bounded
+//! number of requests per connection at a time and stops reading while all of
+//! them are in flight.
+//!
+//! A server with an ACL refuses every request with `STATUS_DENIED` until the
+//! connection authenticated itself with `OP_AUTH`.
+//!
+//! `STATUS_ERR` carries the message followed by an `ErrorCode` byte, servers
+//! older than the codes leave it out.
+//!
+//! A server with as many connections as it accepts answers the handshake
+//! right away, sends a `STATUS_BUSY` frame with id 0 and closes the connection.
+//!
+//! Connections whose first byte isn't `MAGIC[0]` are served with the old
+//! streaming JSON protocol.
+
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::auth::Credentials;
+use crate::common::{Request, Response};
+use crate::replication::LogPosition;
+use crate::{ErrorCode, KvsError, Result};
+
+/// The first bytes sent by a client speaking the framed protocol.
+pub const MAGIC: &[u8; 3] = b"KVS";
+/// The protocol version implemented here.
+pub const VERSION: u8 = 1;
+/// The largest payload read by `Frame::read`.
+///
+/// 长度是对方发过来的，先检查一下，防止错位时分配一大块内存
+pub const MAX_PAYLOAD_LEN: u32 = 64 * 1024 * 1024;
+const HEADER_LEN: usize = 10;
+
+const OP_GET: u8 = 1;
+const OP_SET: u8 = 2;
+const OP_REMOVE: u8 = 3;
+const OP_REPLICATE: u8 = 4;
+/// Switches the connection to multiplexed mode, where responses may come back out of order.
+pub const OP_MULTIPLEX: u8 = 5;
+/// Authenticates the connection, the payload starts with one of the `AUTH_*` kinds.
+pub(crate) const OP_AUTH: u8 = 6;
+const OP_MGET: u8 = 7;
+const OP_MSET: u8 = 8;
+const OP_MREMOVE: u8 = 9;
+const OP_SCAN: u8 = 10;
+
+const AUTH_PASSWORD: u8 = 0;
+const AUTH_TOKEN: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_VALUE: u8 = 1;
+const STATUS_NO_VALUE: u8 = 2;
+const STATUS_LOG: u8 = 3;
+const STATUS_ERR: u8 = 4;
+const STATUS_REDIRECT: u8 = 5;
+const STATUS_DENIED: u8 = 6;
+const STATUS_BUSY: u8 = 7;
+const STATUS_VALUES: u8 = 8;
+const STATUS_REMOVED: u8 = 9;
+const STATUS_PAIRS: u8 = 10;
+
+/// Sends the client side of the handshake and checks the server's answer.
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    stream.write_all(&hello())?;
+    stream.flush()?;
+    let mut answer = [0; 4];
+    stream.read_exact(&mut answer)?;
+    check_answer(&answer)
+}
+
+/// Reads the client's handshake and answers it.
+///
+/// 版本不支持时也会回复，带上服务端的版本，然后返回错误关闭连接
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> Result<()> {
+    let mut hello = [0; 4];
+    stream.read_exact(&mut hello)?;
+    check_magic(&hello)?;
+    stream.write_all(&self::hello())?;
+    stream.flush()?;
+    check_version(&hello)
+}
+
+/// The async version of `client_handshake`.
+pub async fn client_handshake_async<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<()> {
+    stream.write_all(&hello()).await?;
+    stream.flush().await?;
+    let mut answer = [0; 4];
+    stream.read_exact(&mut answer).await?;
+    check_answer(&answer)
+}
+
+/// The async version of `server_handshake`.
+pub async fn server_handshake_async<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<()> {
+    let mut hello = [0; 4];
+    stream.read_exact(&mut hello).await?;
+    check_magic(&hello)?;
+    stream.write_all(&self::hello()).await?;
+    stream.flush().await?;
+    check_version(&hello)
+}
+
+/// What a busy server sends before closing a framed connection.
+///
+/// 不等客户端的握手，客户端发完握手就在读回复，接着读第一个请求的响应
+pub(crate) fn busy() -> Result<Vec<u8>> {
+    let mut buf = hello().to_vec();
+    Response::Busy.to_frame(0)?.write(&mut buf)?;
+    Ok(buf)
+}
+
+fn hello() -> [u8; 4] {
+    [MAGIC[0], MAGIC[1], MAGIC[2], VERSION]
+}
+
+fn check_magic(hello: &[u8; 4]) -> Result<()> {
+    if &hello[..3] != MAGIC {
+        return Err(KvsError::Protocol("Invalid handshake".to_owned()));
+    }
+    Ok(())
+}
+
+fn check_version(hello: &[u8; 4]) -> Result<()> {
+    if hello[3] != VERSION {
+        return Err(KvsError::Protocol(format!(
+            "Unsupported version {}",
+            hello[3]
+        )));
+    }
+    Ok(())
+}
+
+fn check_answer(answer: &[u8; 4]) -> Result<()> {
+    check_magic(answer)?;
+    if answer[3] != VERSION {
+        return Err(KvsError::Protocol(format!(
+            "Server doesn't support version {}",
+            VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// A single message, either a request or a response.
+#[derive(Debug)]
+pub struct Frame {
+    pub id: u32,
+    pub code: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// 对方读的时候不接受超过 `MAX_PAYLOAD_LEN` 的帧，发之前就检查
+    fn new(id: u32, code: u8, payload: Vec<u8>) -> Result<Frame> {
+        let len = u32::try_from(payload.len()).unwrap_or(u32::MAX);
+        if len > MAX_PAYLOAD_LEN {
+            return Err(KvsError::FrameTooLarge { id, len });
+        }
+        Ok(Frame { id, code, payload })
+    }
+
+    /// Reads the next frame, `None` when the connection was closed between frames.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+        Frame::read_limited(reader, MAX_PAYLOAD_LEN)
+    }
+
+    /// Reads the next frame, failing with `FrameTooLarge` if its payload is longer than `max_len`.
+    ///
+    /// `max_len` can't raise the limit above `MAX_PAYLOAD_LEN`.
+    pub fn read_limited<R: Read>(reader: &mut R, max_len: u32) -> Result<Option<Frame>> {
+        let mut header = [0; HEADER_LEN];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        reader.read_exact(&mut header[1..])?;
+        let (id, code, len) = parse_header(&header, max_len.min(MAX_PAYLOAD_LEN))?;
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Frame { id, code, payload }))
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header())?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    /// The async version of `read`.
+    pub async fn read_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        Frame::read_async_limited(reader, MAX_PAYLOAD_LEN).await
+    }
+
+    /// The async version of `read_limited`.
+    pub async fn read_async_limited<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max_len: u32,
+    ) -> Result<Option<Frame>> {
+        let mut header = [0; HEADER_LEN];
+        match reader.read_exact(&mut header[..1]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        reader.read_exact(&mut header[1..]).await?;
+        let (id, code, len) = parse_header(&header, max_len.min(MAX_PAYLOAD_LEN))?;
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Frame { id, code, payload }))
+    }
+
+    /// The async version of `write`.
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header()).await?;
+        writer.write_all(&self.payload).await?;
+        Ok(())
+    }
+
+    fn header(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0] = VERSION;
+        header[1..5].copy_from_slice(&self.id.to_be_bytes());
+        header[5] = self.code;
+        header[6..].copy_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        header
+    }
+}
+
+/// Returns the request id, code and payload length of a frame header.
+fn parse_header(header: &[u8; HEADER_LEN], max_len: u32) -> Result<(u32, u8, usize)> {
+    if header[0] != VERSION {
+        return Err(KvsError::Protocol(format!(
+            "Unexpected version {}",
+            header[0]
+        )));
+    }
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    if len > max_len {
+        return Err(KvsError::FrameTooLarge { id, len });
+    }
+    Ok((id, header[5], len as usize))
+}
+
+impl Request {
+    /// Encodes a request, failing with `FrameTooLarge` if it's longer than `MAX_PAYLOAD_LEN`.
+    pub fn to_frame(&self, id: u32) -> Result<Frame> {
+        let mut payload = Vec::new();
+        let code = match self {
+            Request::Get { key } => {
+                put_str(&mut payload, key);
+                OP_GET
+            }
+            Request::Set { key, value } => {
+                put_str(&mut payload, key);
+                put_str(&mut payload, value);
+                OP_SET
+            }
+            Request::Remove { key } => {
+                put_str(&mut payload, key);
+                OP_REMOVE
+            }
+            Request::Replicate { from } => {
+                payload.extend_from_slice(&from.gen.to_be_bytes());
+                payload.extend_from_slice(&from.pos.to_be_bytes());
+                OP_REPLICATE
+            }
+            Request::Auth(Credentials::Password { user, password }) => {
+                payload.push(AUTH_PASSWORD);
+                put_str(&mut payload, user);
+                put_str(&mut payload, password);
+                OP_AUTH
+            }
+            Request::Auth(Credentials::Token(token)) => {
+                payload.push(AUTH_TOKEN);
+                put_str(&mut payload, token);
+                OP_AUTH
+            }
+            Request::MGet { keys } => {
+                put_len(&mut payload, keys.len());
+                keys.iter().for_each(|key| put_str(&mut payload, key));
+                OP_MGET
+            }
+            Request::MSet { pairs } => {
+                put_len(&mut payload, pairs.len());
+                for (key, value) in pairs {
+                    put_str(&mut payload, key);
+                    put_str(&mut payload, value);
+                }
+                OP_MSET
+            }
+            Request::MRemove { keys } => {
+                put_len(&mut payload, keys.len());
+                keys.iter().for_each(|key| put_str(&mut payload, key));
+                OP_MREMOVE
+            }
+            // 第一页没有 after，用一个字节标记
+            Request::Scan { after, limit } => {
+                match after {
+                    Some(after) => {
+                        payload.push(1);
+                        put_str(&mut payload, after);
+                    }
+                    None => payload.push(0),
+                }
+                payload.extend_from_slice(&limit.to_be_bytes());
+                OP_SCAN
+            }
+        };
+        Frame::new(id, code, payload)
+    }
+
+    /// Decodes a request, an unknown opcode is an error but the connection stays usable.
+    pub fn from_frame(frame: &Frame) -> Result<Request> {
+        let mut payload = &frame.payload[..];
+        let req = match frame.code {
+            OP_GET => Request::Get {
+                key: get_str(&mut payload)?,
+            },
+            OP_SET => Request::Set {
+                key: get_str(&mut payload)?,
+                value: get_str(&mut payload)?,
+            },
+            OP_REMOVE => Request::Remove {
+                key: get_str(&mut payload)?,
+            },
+            OP_REPLICATE => Request::Replicate {
+                from: LogPosition {
+                    gen: get_u64(&mut payload)?,
+                    pos: get_u64(&mut payload)?,
+                },
+            },
+            OP_AUTH => Request::Auth(match take(&mut payload, 1)?[0] {
+                AUTH_PASSWORD => Credentials::Password {
+                    user: get_str(&mut payload)?,
+                    password: get_str(&mut payload)?,
+                },
+                AUTH_TOKEN => Credentials::Token(get_str(&mut payload)?),
+                kind => {
+                    return Err(KvsError::Protocol(format!(
+                        "Unknown credentials kind {}",
+                        kind
+                    )))
+                }
+            }),
+            OP_MGET => Request::MGet {
+                keys: get_list(&mut payload, get_str)?,
+            },
+            OP_MSET => Request::MSet {
+                pairs: get_list(&mut payload, |payload| {
+                    Ok((get_str(payload)?, get_str(payload)?))
+                })?,
+            },
+            OP_MREMOVE => Request::MRemove {
+                keys: get_list(&mut payload, get_str)?,
+            },
+            OP_SCAN => Request::Scan {
+                after: match take(&mut payload, 1)?[0] {
+                    0 => None,
+                    _ => Some(get_str(&mut payload)?),
+                },
+                limit: get_u32(&mut payload)?,
+            },
+            code => return Err(KvsError::Protocol(format!("Unknown opcode {}", code))),
+        };
+        Ok(req)
+    }
+}
+
+impl Response {
+    /// Encodes a response, failing with `FrameTooLarge` if it's longer than `MAX_PAYLOAD_LEN`.
+    pub fn to_frame(&self, id: u32) -> Result<Frame> {
+        let mut payload = Vec::new();
+        let code = match self {
+            Response::Ok => STATUS_OK,
+            Response::Value(Some(value)) => {
+                put_str(&mut payload, value);
+                STATUS_VALUE
+            }
+            Response::Value(None) => STATUS_NO_VALUE,
+            Response::Values(values) => {
+                put_len(&mut payload, values.len());
+                for value in values {
+                    match value {
+                        Some(value) => {
+                            payload.push(1);
+                            put_str(&mut payload, value);
+                        }
+                        None => payload.push(0),
+                    }
+                }
+                STATUS_VALUES
+            }
+            Response::Removed(removed) => {
+                put_len(&mut payload, removed.len());
+                payload.extend(removed.iter().map(|&removed| removed as u8));
+                STATUS_REMOVED
+            }
+            Response::Pairs(pairs) => {
+                put_len(&mut payload, pairs.len());
+                for (key, value) in pairs {
+                    put_str(&mut payload, key);
+                    put_str(&mut payload, value);
+                }
+                STATUS_PAIRS
+            }
+            // 复制的数据结构比较复杂，payload 里直接放 json
+            Response::Log(read) => {
+                serde_json::to_writer(&mut payload, read)?;
+                STATUS_LOG
+            }
+            Response::Err(code, message) => {
+                put_str(&mut payload, message);
+                payload.push(*code as u8);
+                STATUS_ERR
+            }
+            Response::Redirect(leader) => {
+                put_str(&mut payload, leader);
+                STATUS_REDIRECT
+            }
+            Response::Denied(e) => {
+                put_str(&mut payload, e);
+                STATUS_DENIED
+            }
+            Response::Busy => STATUS_BUSY,
+        };
+        Frame::new(id, code, payload)
+    }
+
+    pub fn from_frame(frame: &Frame) -> Result<Response> {
+        let mut payload = &frame.payload[..];
+        let resp = match frame.code {
+            STATUS_OK => Response::Ok,
+            STATUS_VALUE => Response::Value(Some(get_str(&mut payload)?)),
+            STATUS_NO_VALUE => Response::Value(None),
+            STATUS_VALUES => Response::Values(get_list(&mut payload, |payload| {
+                match take(payload, 1)?[0] {
+                    0 => Ok(None),
+                    _ => Ok(Some(get_str(payload)?)),
+                }
+            })?),
+            STATUS_REMOVED => Response::Removed(get_list(&mut payload, |payload| {
+                Ok(take(payload, 1)?[0] != 0)
+            })?),
+            STATUS_PAIRS => Response::Pairs(get_list(&mut payload, |payload| {
+                Ok((get_str(payload)?, get_str(payload)?))
+            })?),
+            STATUS_LOG => Response::Log(serde_json::from_slice(payload)?),
+            STATUS_ERR => {
+                let message = get_str(&mut payload)?;
+                // 旧的服务端没有错误码，不认识的码也当作 Other
+                let code = payload
+                    .first()
+                    .and_then(|&code| ErrorCode::from_u8(code))
+                    .unwrap_or(ErrorCode::Other);
+                Response::Err(code, message)
+            }
+            STATUS_REDIRECT => Response::Redirect(get_str(&mut payload)?),
+            STATUS_DENIED => Response::Denied(get_str(&mut payload)?),
+            STATUS_BUSY => Response::Busy,
+            code => return Err(KvsError::Protocol(format!("Unknown status {}", code))),
+        };
+        Ok(resp)
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_len(buf, s.len());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// 放不进 u32 的长度写成 `u32::MAX`，这时 payload 一定超过了 `MAX_PAYLOAD_LEN`，`Frame::new` 会拒绝
+fn put_len(buf: &mut Vec<u8>, len: usize) {
+    let len = u32::try_from(len).unwrap_or(u32::MAX);
+    buf.extend_from_slice(&len.to_be_bytes());
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(KvsError::Protocol("Truncated payload".to_owned()));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn get_str(buf: &mut &[u8]) -> Result<String> {
+    let len = get_u32(buf)? as usize;
+    Ok(String::from_utf8(take(buf, len)?.to_vec())?)
+}
+
+fn get_u32(buf: &mut &[u8]) -> Result<u32> {
+    let bytes = take(buf, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// 个数是对方发过来的，不能直接拿来预分配
+fn get_list<T>(buf: &mut &[u8], mut get: impl FnMut(&mut &[u8]) -> Result<T>) -> Result<Vec<T>> {
+    let len = take(buf, 4)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+    (0..len).map(|_| get(buf)).collect()
+}
+
+fn get_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(buf, 8)?);
+    Ok(u64::from_be_bytes(bytes))
+}
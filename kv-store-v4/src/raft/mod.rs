@@ -0,0 +1,160 @@
+//! A raft cluster mode for `kvs-server`.
+//!
+//! `Set` and `Remove` are appended to a replicated raft log and only applied
+//! to the local `KvsEngine` once a majority of nodes have stored them.
+//! Followers answer reads and writes with the address of the current leader.
+//! The leader serves a read from its engine once a majority confirmed it is
+//! still the leader and every entry committed before the read is applied.
+//!
+//! The raft log is kept in full, there is no snapshotting yet. The index of the
+//! last entry applied to the engine is saved with the raft state, so a restarted
+//! node only applies the entries after it.
+
+mod node;
+mod rpc;
+mod storage;
+
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use self::node::Node;
+use self::rpc::Message;
+use crate::{Command, EngineStats, KvsEngine, KvsError, Result};
+
+/// A raft log entry, `None` is the no-op a new leader appends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    term: u64,
+    command: Option<Command>,
+}
+
+/// Configuration of a raft node.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    /// The address raft messages are received on, also the id of the node.
+    pub addr: SocketAddr,
+    /// The raft addresses of the other nodes.
+    pub peers: Vec<SocketAddr>,
+    /// The address clients use to reach this node, sent to followers for redirects.
+    pub client_addr: SocketAddr,
+    /// Where the raft log and state are stored.
+    pub dir: PathBuf,
+}
+
+/// A `KvsEngine` which replicates writes through raft before applying them to `E`.
+#[derive(Clone)]
+pub struct RaftEngine<E: KvsEngine> {
+    engine: E,
+    node: Arc<Node>,
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// Starts the raft node: listens for peers and spawns the peer, election and apply threads.
+    pub fn start(engine: E, config: RaftConfig) -> Result<RaftEngine<E>> {
+        let listener = TcpListener::bind(config.addr)?;
+        let peers = config.peers.clone();
+        let node = Arc::new(Node::new(config)?);
+
+        for peer in peers {
+            let peer_node = Arc::clone(&node);
+            thread::Builder::new()
+                .name(format!("raft-peer-{}", peer))
+                .spawn(move || peer_node.run_peer(peer))?;
+        }
+
+        let rpc_node = Arc::clone(&node);
+        thread::Builder::new()
+            .name("raft-rpc".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let node = Arc::clone(&rpc_node);
+                    match stream {
+                        Ok(stream) => {
+                            thread::spawn(move || {
+                                if let Err(e) = serve_peer(&node, stream) {
+                                    error!("Error on serving raft peer: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => error!("Raft connection failed: {}", e),
+                    }
+                }
+            })?;
+        let ticker_node = Arc::clone(&node);
+        thread::Builder::new()
+            .name("raft-ticker".to_owned())
+            .spawn(move || ticker_node.run_ticker())?;
+        let applier_node = Arc::clone(&node);
+        let applier_engine = engine.clone();
+        thread::Builder::new()
+            .name("raft-applier".to_owned())
+            .spawn(move || applier_node.run_applier(applier_engine))?;
+
+        Ok(RaftEngine { engine, node })
+    }
+
+    /// Returns whether this node is the leader right now.
+    pub fn is_leader(&self) -> bool {
+        self.node.is_leader()
+    }
+
+    /// Returns the client address of the leader this node knows about.
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.node.leader()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.node.propose(Command::Set { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.node.read_barrier()?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        // 删除不存在的 key 要报错，提交之前先在 leader 本地检查一下
+        if self.node.is_leader() && self.engine.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.node.propose(Command::Remove { key })
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.node.read_barrier()?;
+        self.engine.scan(prefix)
+    }
+
+    fn scan_after(&self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.node.read_barrier()?;
+        self.engine.scan_after(after, limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+}
+
+fn serve_peer(node: &Node, tcp: TcpStream) -> Result<()> {
+    let mut writer = BufWriter::new(&tcp);
+    let messages = Deserializer::from_reader(BufReader::new(&tcp)).into_iter::<Message>();
+    for msg in messages {
+        let reply = node.handle(msg?)?;
+        serde_json::to_writer(&mut writer, &reply)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
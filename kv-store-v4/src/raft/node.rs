@@ -0,0 +1,648 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::slice;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, Receiver, Sender};
+use log::{debug, error, info};
+use rand::Rng;
+
+use super::rpc::{self, AppendEntries, AppendEntriesReply, Message, Reply, RequestVote, RequestVoteReply};
+use super::storage::{HardState, Storage};
+use super::{Entry, RaftConfig};
+use crate::{Command, KvsEngine, KvsError, Result};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+const ELECTION_TIMEOUT_MIN_MS: u64 = 300;
+const ELECTION_TIMEOUT_MAX_MS: u64 = 600;
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// 一次 AppendEntries 最多带多少条日志
+const MAX_APPEND_ENTRIES: usize = 256;
+/// 写请求等待提交的最长时间
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 应用日志失败之后，隔多久重试
+const APPLY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<SocketAddr>,
+    /// log[i - 1] 是第 i 条日志，index 从 1 开始
+    log: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    /// 上次应用日志失败的原因，重试成功之前不再接受读写
+    apply_error: Option<String>,
+    /// 当前 leader 的客户端地址
+    leader: Option<SocketAddr>,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    votes: usize,
+    next_index: HashMap<SocketAddr, u64>,
+    match_index: HashMap<SocketAddr, u64>,
+    /// 正在发送 AppendEntries 的 peer，避免同一个 peer 同时有多个请求
+    in_flight: HashSet<SocketAddr>,
+    /// 每次广播 AppendEntries 加一，读请求用它确认自己还是 leader
+    round: u64,
+    /// 每个 peer 回复过的最新一轮
+    acked_round: HashMap<SocketAddr, u64>,
+    storage: Storage,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        if index == 0 {
+            0
+        } else {
+            self.log[index as usize - 1].term
+        }
+    }
+
+    fn hard_state(&self) -> HardState {
+        HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            applied: self.last_applied,
+        }
+    }
+
+    /// 发现了更大的 term，退回 follower
+    fn step_down(&mut self, term: u64) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.storage.save_state(&self.hard_state())?;
+        }
+        if self.role == Role::Leader {
+            self.leader = None;
+        }
+        self.role = Role::Follower;
+        Ok(())
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN_MS..ELECTION_TIMEOUT_MAX_MS);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+}
+
+/// A request waiting to be sent by the thread of a peer.
+enum Outgoing {
+    RequestVote(RequestVote),
+    /// `round` 是这次广播的轮数，回复用来确认 leader
+    AppendEntries {
+        request: AppendEntries,
+        round: u64,
+    },
+}
+
+/// 发给一个 peer 的请求排队，由这个 peer 的线程在同一个连接上按顺序发出
+struct Outbox {
+    sender: Sender<Outgoing>,
+    receiver: Receiver<Outgoing>,
+}
+
+/// The raft state machine shared by the rpc, peer, ticker and apply threads.
+pub struct Node {
+    config: RaftConfig,
+    state: Mutex<State>,
+    /// commit index 或者 last applied 变化时通知
+    changed: Condvar,
+    outboxes: HashMap<SocketAddr, Outbox>,
+}
+
+impl Node {
+    pub fn new(config: RaftConfig) -> Result<Node> {
+        let (storage, hard_state, log) = Storage::open(&config.dir)?;
+        info!(
+            "Raft node {} starts at term {} with {} log entries, {} applied",
+            config.addr,
+            hard_state.term,
+            log.len(),
+            hard_state.applied
+        );
+        if hard_state.applied > log.len() as u64 {
+            return Err(KvsError::Corruption(format!(
+                "Raft state says {} entries were applied but the log has {}",
+                hard_state.applied,
+                log.len()
+            )));
+        }
+        let mut state = State {
+            role: Role::Follower,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            log,
+            // 应用过的日志一定已经提交了，之后的等 leader 告诉我们
+            commit_index: hard_state.applied,
+            last_applied: hard_state.applied,
+            apply_error: None,
+            leader: None,
+            election_deadline: Instant::now(),
+            heartbeat_due: Instant::now(),
+            votes: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            in_flight: HashSet::new(),
+            round: 0,
+            acked_round: HashMap::new(),
+            storage,
+        };
+        state.reset_election_deadline();
+        let outboxes = config
+            .peers
+            .iter()
+            .map(|&peer| {
+                let (sender, receiver) = channel::unbounded();
+                (peer, Outbox { sender, receiver })
+            })
+            .collect();
+        Ok(Node {
+            config,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            outboxes,
+        })
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.config.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state.lock().unwrap().role == Role::Leader
+    }
+
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().leader
+    }
+
+    fn not_leader(state: &State) -> KvsError {
+        match state.leader {
+            Some(leader) => KvsError::NotLeader(leader.to_string()),
+            None => KvsError::NoLeader,
+        }
+    }
+
+    /// 引擎写不进去，本地的数据落后于日志，这时候读写都不可信
+    fn check_applying(state: &State) -> Result<()> {
+        match &state.apply_error {
+            Some(e) => Err(KvsError::StringError(format!(
+                "Raft log can't be applied: {}",
+                e
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends a command to the log and waits until it is applied to the local engine.
+    pub fn propose(&self, command: Command) -> Result<()> {
+        let (index, term) = {
+            let mut state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(Self::not_leader(&state));
+            }
+            Self::check_applying(&state)?;
+            let entry = Entry {
+                term: state.term,
+                command: Some(command),
+            };
+            state.storage.append(slice::from_ref(&entry))?;
+            state.log.push(entry);
+            self.advance_commit_index(&mut state);
+            (state.last_index(), state.term)
+        };
+        self.broadcast_append_entries();
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.last_applied >= index {
+                // 日志可能在等待期间被新的 leader 覆盖了
+                return if state.last_index() >= index && state.term_at(index) == term {
+                    Ok(())
+                } else {
+                    Err(Self::not_leader(&state))
+                };
+            }
+            if state.term != term {
+                return Err(Self::not_leader(&state));
+            }
+            // 日志已经提交了，重试成功之后还是会写进引擎
+            Self::check_applying(&state)?;
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::StringError("Timed out waiting for commit".to_owned()));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Waits until a read of the local engine sees every write committed before the call.
+    ///
+    /// 先确认多数节点还承认这个 leader，被隔离的旧 leader 不能返回过期的值
+    pub fn read_barrier(&self) -> Result<()> {
+        let (term, round) = {
+            let state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return Err(Self::not_leader(&state));
+            }
+            (state.term, state.round + 1)
+        };
+        self.broadcast_append_entries();
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut read_index = None;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.role != Role::Leader || state.term != term {
+                return Err(Self::not_leader(&state));
+            }
+            Self::check_applying(&state)?;
+            // 新 leader 的空日志提交之前，还不知道之前的任期提交到了哪里
+            if read_index.is_none() && state.term_at(state.commit_index) == term {
+                read_index = Some(state.commit_index);
+            }
+            let acked = 1 + state.acked_round.values().filter(|&&r| r >= round).count();
+            if let Some(index) = read_index {
+                if acked >= self.quorum() && state.last_applied >= index {
+                    return Ok(());
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::StringError(
+                    "Timed out confirming the leader".to_owned(),
+                ));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Drives elections and heartbeats, never returns.
+    pub fn run_ticker(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK_INTERVAL);
+            let now = Instant::now();
+            let (role, election_due, heartbeat_due) = {
+                let state = self.state.lock().unwrap();
+                (
+                    state.role,
+                    now >= state.election_deadline,
+                    now >= state.heartbeat_due,
+                )
+            };
+            match role {
+                Role::Leader if heartbeat_due => self.broadcast_append_entries(),
+                Role::Follower | Role::Candidate if election_due => {
+                    if let Err(e) = self.start_election() {
+                        error!("Failed to start election: {}", e);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Applies committed entries to the engine, never returns.
+    ///
+    /// A batch that fails to apply or flush is retried, the node refuses requests meanwhile.
+    pub fn run_applier<E: KvsEngine>(self: Arc<Self>, engine: E) {
+        loop {
+            let (first, entries) = {
+                let mut state = self.state.lock().unwrap();
+                while state.commit_index <= state.last_applied {
+                    state = self.changed.wait(state).unwrap();
+                }
+                let first = state.last_applied + 1;
+                let entries: Vec<Entry> =
+                    state.log[first as usize - 1..state.commit_index as usize].to_vec();
+                (first, entries)
+            };
+            let last = first + entries.len() as u64 - 1;
+            // 重试时整批重新应用一遍，set 和 remove 重放多次结果一样
+            let applied = entries
+                .into_iter()
+                .enumerate()
+                .try_for_each(|(i, entry)| match entry.command {
+                    Some(command) => apply(&engine, command)
+                        .map_err(|e| format!("Failed to apply entry {}: {}", first + i as u64, e)),
+                    None => Ok(()),
+                })
+                // 引擎落盘之后才能记下应用到了哪里，否则重启会漏掉日志
+                .and_then(|()| {
+                    engine
+                        .flush()
+                        .map_err(|e| format!("Failed to flush the engine: {}", e))
+                });
+            let mut state = self.state.lock().unwrap();
+            match applied {
+                Ok(()) => {
+                    state.last_applied = last;
+                    state.apply_error = None;
+                    if let Err(e) = state.storage.save_state(&state.hard_state()) {
+                        error!("Failed to save raft state: {}", e);
+                    }
+                    self.changed.notify_all();
+                }
+                Err(e) => {
+                    error!("{}, retrying in {:?}", e, APPLY_RETRY_INTERVAL);
+                    state.apply_error = Some(e);
+                    self.changed.notify_all();
+                    drop(state);
+                    thread::sleep(APPLY_RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Sends the requests queued for `peer` over one connection and handles the replies,
+    /// never returns.
+    pub fn run_peer(self: Arc<Self>, peer: SocketAddr) {
+        let mut connection = rpc::Connection::new(peer);
+        for outgoing in self.outboxes[&peer].receiver.iter() {
+            match outgoing {
+                Outgoing::RequestVote(request) => {
+                    let term = request.term;
+                    match connection.call(&Message::RequestVote(request)) {
+                        Ok(Reply::RequestVote(reply)) => self.on_vote_reply(term, reply),
+                        Ok(reply) => error!("Unexpected reply from {}: {:?}", peer, reply),
+                        Err(e) => debug!("RequestVote to {} failed: {}", peer, e),
+                    }
+                }
+                Outgoing::AppendEntries { request, round } => {
+                    let term = request.term;
+                    let prev_log_index = request.prev_log_index;
+                    let sent = request.entries.len() as u64;
+                    let reply = connection.call(&Message::AppendEntries(request));
+                    let mut state = self.state.lock().unwrap();
+                    state.in_flight.remove(&peer);
+                    match reply {
+                        Ok(Reply::AppendEntries(reply)) => self.on_append_reply(
+                            &mut state,
+                            peer,
+                            term,
+                            round,
+                            prev_log_index + sent,
+                            reply,
+                        ),
+                        Ok(reply) => error!("Unexpected reply from {}: {:?}", peer, reply),
+                        Err(e) => debug!("AppendEntries to {} failed: {}", peer, e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// 请求放进 peer 的队列，接收端在 `outboxes` 里，发送不会失败
+    fn send(&self, peer: SocketAddr, outgoing: Outgoing) {
+        let _ = self.outboxes[&peer].sender.send(outgoing);
+    }
+
+    fn start_election(&self) -> Result<()> {
+        let request = {
+            let mut state = self.state.lock().unwrap();
+            state.role = Role::Candidate;
+            state.term += 1;
+            state.voted_for = Some(self.config.addr);
+            state.votes = 1;
+            state.leader = None;
+            state.storage.save_state(&state.hard_state())?;
+            state.reset_election_deadline();
+            info!("{} starts election for term {}", self.config.addr, state.term);
+            RequestVote {
+                term: state.term,
+                candidate: self.config.addr,
+                last_log_index: state.last_index(),
+                last_log_term: state.term_at(state.last_index()),
+            }
+        };
+        // 单节点集群自己就是多数
+        if self.quorum() == 1 {
+            self.become_leader(&mut self.state.lock().unwrap(), request.term);
+            return Ok(());
+        }
+        for &peer in &self.config.peers {
+            self.send(peer, Outgoing::RequestVote(request.clone()));
+        }
+        Ok(())
+    }
+
+    fn on_vote_reply(&self, term: u64, reply: RequestVoteReply) {
+        let mut state = self.state.lock().unwrap();
+        if reply.term > state.term {
+            if let Err(e) = state.step_down(reply.term) {
+                error!("Failed to save raft state: {}", e);
+            }
+            return;
+        }
+        if state.role != Role::Candidate || state.term != term || !reply.vote_granted {
+            return;
+        }
+        state.votes += 1;
+        if state.votes >= self.quorum() {
+            self.become_leader(&mut state, term);
+        }
+    }
+
+    fn become_leader(&self, state: &mut State, term: u64) {
+        info!("{} becomes leader for term {}", self.config.addr, term);
+        state.role = Role::Leader;
+        state.leader = Some(self.config.client_addr);
+        let next = state.last_index() + 1;
+        for &peer in &self.config.peers {
+            state.next_index.insert(peer, next);
+            state.match_index.insert(peer, 0);
+        }
+        // 新 leader 只能通过提交自己任期内的日志来提交之前的日志，所以先追加一条空日志
+        let noop = Entry {
+            term,
+            command: None,
+        };
+        if let Err(e) = state.storage.append(slice::from_ref(&noop)) {
+            error!("Failed to append to raft log: {}", e);
+        }
+        state.log.push(noop);
+        state.heartbeat_due = Instant::now();
+        self.advance_commit_index(state);
+    }
+
+    fn broadcast_append_entries(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return;
+        }
+        state.heartbeat_due = Instant::now() + HEARTBEAT_INTERVAL;
+        state.round += 1;
+        let round = state.round;
+        for &peer in &self.config.peers {
+            if !state.in_flight.insert(peer) {
+                continue;
+            }
+            let next = state.next_index[&peer];
+            let prev_log_index = next - 1;
+            let end = state.log.len().min(prev_log_index as usize + MAX_APPEND_ENTRIES);
+            let request = AppendEntries {
+                term: state.term,
+                leader: self.config.addr,
+                leader_client_addr: self.config.client_addr,
+                prev_log_index,
+                prev_log_term: state.term_at(prev_log_index),
+                entries: state.log[prev_log_index as usize..end].to_vec(),
+                leader_commit: state.commit_index,
+            };
+            self.send(peer, Outgoing::AppendEntries { request, round });
+        }
+    }
+
+    fn on_append_reply(
+        &self,
+        state: &mut State,
+        peer: SocketAddr,
+        term: u64,
+        round: u64,
+        last_sent: u64,
+        reply: AppendEntriesReply,
+    ) {
+        if reply.term > state.term {
+            if let Err(e) = state.step_down(reply.term) {
+                error!("Failed to save raft state: {}", e);
+            }
+            return;
+        }
+        if state.role != Role::Leader || state.term != term {
+            return;
+        }
+        // 日志对不上也说明 peer 承认这个任期的 leader
+        state.acked_round.insert(peer, round);
+        self.changed.notify_all();
+        if reply.success {
+            state.match_index.insert(peer, last_sent);
+            state.next_index.insert(peer, last_sent + 1);
+            self.advance_commit_index(state);
+        } else {
+            let next = state.next_index[&peer];
+            let next = (next - 1).min(reply.last_index + 1).max(1);
+            state.next_index.insert(peer, next);
+        }
+    }
+
+    /// 多数节点都已经复制了的、当前任期的日志就可以提交了
+    fn advance_commit_index(&self, state: &mut State) {
+        let mut index = state.last_index();
+        while index > state.commit_index {
+            if state.term_at(index) == state.term {
+                let replicas = 1 + state.match_index.values().filter(|&&m| m >= index).count();
+                if replicas >= self.quorum() {
+                    state.commit_index = index;
+                    self.changed.notify_all();
+                    break;
+                }
+            }
+            index -= 1;
+        }
+    }
+
+    /// Handles a message from another node.
+    pub fn handle(&self, msg: Message) -> Result<Reply> {
+        let mut state = self.state.lock().unwrap();
+        match msg {
+            Message::RequestVote(request) => {
+                if request.term > state.term {
+                    state.step_down(request.term)?;
+                }
+                let last_index = state.last_index();
+                let last_term = state.term_at(last_index);
+                let up_to_date = request.last_log_term > last_term
+                    || (request.last_log_term == last_term && request.last_log_index >= last_index);
+                let can_vote =
+                    state.voted_for.is_none() || state.voted_for == Some(request.candidate);
+                let vote_granted = request.term == state.term && can_vote && up_to_date;
+                if vote_granted {
+                    state.voted_for = Some(request.candidate);
+                    state.storage.save_state(&state.hard_state())?;
+                    state.reset_election_deadline();
+                }
+                Ok(Reply::RequestVote(RequestVoteReply {
+                    term: state.term,
+                    vote_granted,
+                }))
+            }
+            Message::AppendEntries(request) => {
+                if request.term < state.term {
+                    return Ok(Reply::AppendEntries(AppendEntriesReply {
+                        term: state.term,
+                        success: false,
+                        last_index: state.last_index(),
+                    }));
+                }
+                state.step_down(request.term)?;
+                state.leader = Some(request.leader_client_addr);
+                state.reset_election_deadline();
+
+                let prev = request.prev_log_index;
+                let last_new = prev + request.entries.len() as u64;
+                if prev > state.last_index() || state.term_at(prev) != request.prev_log_term {
+                    let hint = state.last_index().min(prev.saturating_sub(1));
+                    return Ok(Reply::AppendEntries(AppendEntriesReply {
+                        term: state.term,
+                        success: false,
+                        last_index: hint,
+                    }));
+                }
+
+                let mut appended = Vec::new();
+                for (i, entry) in request.entries.into_iter().enumerate() {
+                    let index = prev + 1 + i as u64;
+                    if index <= state.last_index() {
+                        if state.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // 冲突了，删掉这条以及之后的所有日志
+                        state.log.truncate(index as usize - 1);
+                        let state = &mut *state;
+                        state.storage.replace(&state.log)?;
+                    }
+                    appended.push(entry.clone());
+                    state.log.push(entry);
+                }
+                state.storage.append(&appended)?;
+
+                if request.leader_commit > state.commit_index {
+                    state.commit_index = request.leader_commit.min(last_new);
+                    self.changed.notify_all();
+                }
+                Ok(Reply::AppendEntries(AppendEntriesReply {
+                    term: state.term,
+                    success: true,
+                    last_index: state.last_index(),
+                }))
+            }
+        }
+    }
+}
+
+fn apply<E: KvsEngine>(engine: &E, command: Command) -> Result<()> {
+    match command {
+        Command::Set { key, value } => engine.set(key, value),
+        // 重启后会重放日志，key 可能已经被删掉了
+        Command::Remove { key } => match engine.remove(key) {
+            Err(KvsError::KeyNotFound) => Ok(()),
+            res => res,
+        },
+    }
+}
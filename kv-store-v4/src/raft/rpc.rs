@@ -0,0 +1,104 @@
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+
+use super::Entry;
+use crate::{KvsError, Result};
+
+/// 节点之间的 rpc 超时，挂掉的节点不能拖慢心跳
+const RPC_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote(RequestVote),
+    AppendEntries(AppendEntries),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    RequestVote(RequestVoteReply),
+    AppendEntries(AppendEntriesReply),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestVote {
+    pub term: u64,
+    pub candidate: SocketAddr,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestVoteReply {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntries {
+    pub term: u64,
+    pub leader: SocketAddr,
+    /// 客户端用来连接 leader 的地址，follower 会把写请求重定向过去
+    pub leader_client_addr: SocketAddr,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendEntriesReply {
+    pub term: u64,
+    pub success: bool,
+    /// 成功时是已经匹配的最后一个 index，失败时是 leader 下一次可以尝试的 index 的提示
+    pub last_index: u64,
+}
+
+type Replies = StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, Reply>;
+
+/// A connection to a peer, opened on the first call and kept for the next ones.
+pub struct Connection {
+    peer: SocketAddr,
+    stream: Option<(BufWriter<TcpStream>, Replies)>,
+}
+
+impl Connection {
+    pub fn new(peer: SocketAddr) -> Connection {
+        Connection { peer, stream: None }
+    }
+
+    /// Sends one message and waits for the reply, reconnecting next time if anything fails.
+    pub fn call(&mut self, msg: &Message) -> Result<Reply> {
+        let res = self.try_call(msg);
+        // 超时之后回复可能还会到，连接里的数据对不上了，只能断开重连
+        if res.is_err() {
+            self.stream = None;
+        }
+        res
+    }
+
+    fn try_call(&mut self, msg: &Message) -> Result<Reply> {
+        if self.stream.is_none() {
+            let tcp = TcpStream::connect_timeout(&self.peer, RPC_TIMEOUT)?;
+            tcp.set_read_timeout(Some(RPC_TIMEOUT))?;
+            tcp.set_write_timeout(Some(RPC_TIMEOUT))?;
+            tcp.set_nodelay(true)?;
+            let replies = Deserializer::from_reader(BufReader::new(tcp.try_clone()?)).into_iter();
+            self.stream = Some((BufWriter::new(tcp), replies));
+        }
+        let (writer, replies) = self.stream.as_mut().unwrap();
+        serde_json::to_writer(&mut *writer, msg)?;
+        writer.flush()?;
+        match replies.next() {
+            Some(reply) => Ok(reply?),
+            None => Err(KvsError::StringError(format!(
+                "{} closed the connection",
+                self.peer
+            ))),
+        }
+    }
+}
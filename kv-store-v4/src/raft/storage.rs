@@ -0,0 +1,117 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::Entry;
+use crate::Result;
+
+/// 需要在投票/追加日志之前落盘的状态
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<SocketAddr>,
+    /// 已经应用到引擎并且落盘的最后一条日志，重启后从它之后开始应用
+    #[serde(default)]
+    pub applied: u64,
+}
+
+/// Persists the raft state in `<dir>/state` and the log in `<dir>/entries`.
+pub struct Storage {
+    state_path: PathBuf,
+    entries_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl Storage {
+    /// Opens the storage, returns it with the state and log saved before.
+    pub fn open(dir: &Path) -> Result<(Storage, HardState, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+        let state_path = dir.join("state");
+        let entries_path = dir.join("entries");
+
+        let state = if state_path.exists() {
+            serde_json::from_slice(&fs::read(&state_path)?)?
+        } else {
+            HardState::default()
+        };
+        let mut entries = Vec::new();
+        if entries_path.exists() {
+            let reader = BufReader::new(File::open(&entries_path)?);
+            for entry in Deserializer::from_reader(reader).into_iter::<Entry>() {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    // 最后一条可能只写了一半，丢掉就好，还没有回复过 leader
+                    Err(e) if e.is_eof() => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        // 把可能写了一半的尾巴也去掉
+        let writer = rewrite(&entries_path, &entries)?;
+
+        Ok((
+            Storage {
+                state_path,
+                entries_path,
+                writer,
+            },
+            state,
+            entries,
+        ))
+    }
+
+    /// 返回之前状态已经落盘，崩溃重启之后不会在同一个任期再投一次票
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        let tmp = self.state_path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(state)?)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.state_path)?;
+        sync_dir(&self.state_path)
+    }
+
+    /// 返回之前日志已经落盘，之后才能回复 leader
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.writer, entry)?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the whole log, used when a conflicting suffix is dropped.
+    pub fn replace(&mut self, entries: &[Entry]) -> Result<()> {
+        self.writer = rewrite(&self.entries_path, entries)?;
+        Ok(())
+    }
+}
+
+fn rewrite(path: &Path, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for entry in entries {
+        serde_json::to_writer(&mut writer, entry)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)?;
+    Ok(BufWriter::new(OpenOptions::new().append(true).open(path)?))
+}
+
+/// rename 之后还要 sync 所在的目录，新的目录项才算落盘
+fn sync_dir(path: &Path) -> Result<()> {
+    // 只有 unix 能打开目录来 sync
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Asynchronous leader-follower replication.
+//!
+//! A follower keeps polling the leader for the commands appended after its last
+//! applied [`LogPosition`] and replays them into its own `KvStore`. When the
+//! leader has already compacted past that position, it answers with a full
+//! snapshot instead. A snapshot too large for one response is continued with
+//! scans, the commands written meanwhile are replayed from the snapshot's
+//! position afterwards.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientTls, Command, Credentials, KvStore, KvsClient, KvsEngine, KvsError, Result};
+
+/// 没有新日志时，再次拉取的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 出错后重连 leader 的间隔
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// 分页拉快照时每页的 pair 数量
+const SNAPSHOT_PAGE: u32 = 1000;
+
+/// The file in the follower's data directory recording the applied position.
+const POSITION_FILE: &str = "replica";
+
+/// A position in the leader's log: the byte offset `pos` inside the log file `gen`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogPosition {
+    pub gen: u64,
+    pub pos: u64,
+}
+
+/// What a leader sends back to a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LogRead {
+    /// The commands after the requested position, and the position following the last of them.
+    Entries {
+        commands: Vec<Command>,
+        next: LogPosition,
+    },
+    /// The requested position is gone, the follower has to reload everything.
+    Snapshot {
+        pairs: Vec<(String, String)>,
+        next: LogPosition,
+    },
+    /// The first pairs of a snapshot, in key order, the follower scans the rest after the last.
+    PartialSnapshot {
+        pairs: Vec<(String, String)>,
+        next: LogPosition,
+    },
+}
+
+/// Pulls the log of a leader into a local `KvStore`.
+pub struct Follower {
+    store: KvStore,
+    leader: SocketAddr,
+    tls: Option<ClientTls>,
+    credentials: Option<Credentials>,
+    position: LogPosition,
+    position_path: PathBuf,
+}
+
+impl Follower {
+    /// Creates a follower, resuming from the position saved in the store directory.
+    pub fn new(store: KvStore, leader: SocketAddr) -> Result<Follower> {
+        let position_path = store.path().join(POSITION_FILE);
+        let position = if position_path.exists() {
+            serde_json::from_slice(&fs::read(&position_path)?)?
+        } else {
+            LogPosition::default()
+        };
+        Ok(Follower {
+            store,
+            leader,
+            tls: None,
+            credentials: None,
+            position,
+            position_path,
+        })
+    }
+
+    /// Connects to the leader over TLS, checking its certificate against `tls`.
+    pub fn set_tls(&mut self, tls: ClientTls) {
+        self.tls = Some(tls);
+    }
+
+    /// Authenticates to a leader with an ACL, the user needs to read the empty prefix.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// The last leader position applied to the local store.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+
+    /// Fetches one batch from the leader and applies it, returns how many entries were applied.
+    pub fn sync_once(&mut self, client: &mut KvsClient) -> Result<usize> {
+        let (next, applied) = match client.replicate(self.position)? {
+            LogRead::Entries { commands, next } => {
+                let applied = commands.len();
+                for cmd in commands {
+                    self.apply(cmd)?;
+                }
+                (next, applied)
+            }
+            LogRead::Snapshot { pairs, next } => {
+                info!("Loading snapshot of {} keys from {}", pairs.len(), self.leader);
+                let applied = pairs.len();
+                self.load_snapshot(pairs)?;
+                (next, applied)
+            }
+            LogRead::PartialSnapshot { pairs, next } => {
+                info!("Loading a large snapshot from {}", self.leader);
+                let applied = self.load_paged_snapshot(client, pairs)?;
+                (next, applied)
+            }
+        };
+        if next != self.position {
+            self.save_position(next)?;
+        }
+        if applied > 0 {
+            debug!("Applied {} entries from {}, now at {:?}", applied, self.leader, next);
+        }
+        Ok(applied)
+    }
+
+    /// Keeps polling the leader over one connection, reconnecting after an error.
+    pub fn run(mut self) {
+        let mut client = None;
+        loop {
+            let res = match &mut client {
+                Some(client) => self.sync_once(client),
+                None => self
+                    .connect()
+                    .and_then(|new| self.sync_once(client.insert(new))),
+            };
+            match res {
+                Ok(0) => thread::sleep(POLL_INTERVAL),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Replication from {} failed: {}", self.leader, e);
+                    // 连接可能已经坏了，下次重新连
+                    client = None;
+                    thread::sleep(RECONNECT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Runs the follower on a background thread.
+    pub fn spawn(self) -> Result<JoinHandle<()>> {
+        Ok(thread::Builder::new()
+            .name("replication".to_owned())
+            .spawn(move || self.run())?)
+    }
+
+    fn connect(&self) -> Result<KvsClient> {
+        let mut client = match &self.tls {
+            Some(tls) => KvsClient::connect_tls(self.leader, tls)?,
+            None => KvsClient::connect(self.leader)?,
+        };
+        if let Some(credentials) = &self.credentials {
+            client.auth(credentials.clone())?;
+        }
+        Ok(client)
+    }
+
+    fn apply(&self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Set { key, value } => self.store.set(key, value),
+            // 重放时 key 可能已经被删掉了
+            Command::Remove { key } => match self.store.remove(key) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                res => res,
+            },
+        }
+    }
+
+    fn load_snapshot(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let keys: HashSet<&String> = pairs.iter().map(|(key, _)| key).collect();
+        for key in self.store.keys() {
+            if !keys.contains(&key) {
+                self.apply(Command::Remove { key })?;
+            }
+        }
+        for (key, value) in pairs {
+            self.store.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// 翻页的时候 leader 还在写，读到的不是同一时刻的数据，之后从快照的位置重放日志就一致了
+    fn load_paged_snapshot(
+        &self,
+        client: &mut KvsClient,
+        mut pairs: Vec<(String, String)>,
+    ) -> Result<usize> {
+        let mut keys = HashSet::new();
+        while let Some((last, _)) = pairs.last() {
+            let after = last.clone();
+            for (key, value) in pairs {
+                keys.insert(key.clone());
+                self.store.set(key, value)?;
+            }
+            pairs = client.scan(Some(after), SNAPSHOT_PAGE)?;
+        }
+        for key in self.store.keys() {
+            if !keys.contains(&key) {
+                self.apply(Command::Remove { key })?;
+            }
+        }
+        Ok(keys.len())
+    }
+
+    fn save_position(&mut self, position: LogPosition) -> Result<()> {
+        // 先写临时文件再 rename，保证位置文件不会写一半
+        let tmp = self.position_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&position)?)?;
+        fs::rename(&tmp, &self.position_path)?;
+        self.position = position;
+        Ok(())
+    }
+}
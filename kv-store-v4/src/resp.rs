@@ -0,0 +1,509 @@
+//! The Redis protocol (RESP2), so that Redis clients can use the store.
+//!
+//! Supported commands: `GET`, `SET key value`, `DEL`, `EXISTS`, `SCAN` with `MATCH` and `COUNT`,
+//! `PING`, `AUTH`, `SELECT 0`, `COMMAND` and `QUIT`. Keys and values must be UTF-8.
+//!
+//! `AUTH password` authenticates with a token of the `Acl`, `AUTH user password` with a password.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "sync-server")]
+use std::io::{BufRead, Read};
+use std::io::{self, Write};
+use std::time::Instant;
+
+use log::debug;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::auth::{Access, Session};
+use crate::common::{Request, Response};
+use crate::metrics::{Metrics, RequestType};
+use crate::handler;
+use crate::{Credentials, ErrorCode, KvsEngine, KvsError, Result};
+
+/// 和 redis 一样，一行的 inline 命令最长 64K
+const MAX_INLINE_LEN: u64 = 64 * 1024;
+/// SCAN 默认一次看多少个 key
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// 一个连接最多记住多少个 SCAN 游标，多了就忘掉最早的
+const MAX_CURSORS: usize = 1024;
+
+/// A RESP2 value, the shape of the frames in `mini-mini-redis`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Frame>),
+}
+
+impl Frame {
+    fn ok() -> Frame {
+        Frame::Simple("OK".to_owned())
+    }
+
+    pub(crate) fn error(message: impl Into<String>) -> Frame {
+        Frame::Error(message.into())
+    }
+
+    fn bulk(s: String) -> Frame {
+        Frame::Bulk(s.into_bytes())
+    }
+
+    pub(crate) fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Frame::Simple(s) => write!(writer, "+{}\r\n", one_line(s)),
+            Frame::Error(e) => write!(writer, "-{}\r\n", one_line(e)),
+            Frame::Integer(n) => write!(writer, ":{}\r\n", n),
+            Frame::Bulk(bytes) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Frame::Null => writer.write_all(b"$-1\r\n"),
+            Frame::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(writer))
+            }
+        }
+    }
+}
+
+/// 简单字符串和错误里不能有换行
+fn one_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+/// Reads the next command, an array of bulk strings or an inline command.
+///
+/// `None` if the connection was closed between two commands.
+#[cfg(feature = "sync-server")]
+pub(crate) fn read_command<R: BufRead>(reader: &mut R, max_size: u32) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(inline_args(&line)));
+    }
+
+    let count = multibulk_len(&line, max_size)?;
+    let mut args = Vec::with_capacity(count.min(64) as usize);
+    let mut size = 0;
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        let len = bulk_len(&line, &mut size, max_size)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        args.push(bulk_arg(arg)?);
+    }
+    Ok(Some(args))
+}
+
+/// The async version of `read_command`, for the async server.
+pub(crate) async fn read_command_async<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_size: u32,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line_async(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(inline_args(&line)));
+    }
+
+    let count = multibulk_len(&line, max_size)?;
+    let mut args = Vec::with_capacity(count.min(64) as usize);
+    let mut size = 0;
+    for _ in 0..count {
+        let line = read_line_async(reader).await?.ok_or_else(unexpected_eof)?;
+        let len = bulk_len(&line, &mut size, max_size)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        args.push(bulk_arg(arg)?);
+    }
+    Ok(Some(args))
+}
+
+/// telnet 之类的工具发的是一行用空格分开的命令
+fn inline_args(line: &[u8]) -> Vec<Vec<u8>> {
+    line.split(|b| b.is_ascii_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// 数组的长度，每个元素至少一个字节，长度超过上限的话请求肯定太大
+fn multibulk_len(line: &[u8], max_size: u32) -> Result<u64> {
+    let count = parse_len(&line[1..], "multibulk length")?;
+    if count > max_size as u64 {
+        return Err(too_large(max_size));
+    }
+    Ok(count)
+}
+
+/// 一个参数的长度，`size` 是这个命令到目前为止的参数总长度
+fn bulk_len(line: &[u8], size: &mut u64, max_size: u32) -> Result<usize> {
+    if line.first() != Some(&b'$') {
+        return Err(KvsError::Protocol(format!(
+            "expected '$', got '{}'",
+            String::from_utf8_lossy(&line[..line.len().min(1)])
+        )));
+    }
+    let len = parse_len(&line[1..], "bulk length")?;
+    *size += len;
+    if *size > max_size as u64 {
+        return Err(too_large(max_size));
+    }
+    Ok(len as usize)
+}
+
+/// 去掉参数后面的 `\r\n`
+fn bulk_arg(mut arg: Vec<u8>) -> Result<Vec<u8>> {
+    if !arg.ends_with(b"\r\n") {
+        return Err(KvsError::Protocol("bulk string not terminated".to_owned()));
+    }
+    arg.truncate(arg.len() - 2);
+    Ok(arg)
+}
+
+/// Reads a line without its `\r\n`, `None` at EOF.
+#[cfg(feature = "sync-server")]
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_INLINE_LEN + 2)
+        .read_until(b'\n', &mut line)?;
+    end_line(line)
+}
+
+async fn read_line_async<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_INLINE_LEN + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    end_line(line)
+}
+
+/// 行最长 `MAX_INLINE_LEN`，读到的是空的就是 EOF
+fn end_line(mut line: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return match line.len() as u64 > MAX_INLINE_LEN {
+            true => Err(KvsError::Protocol("too big inline request".to_owned())),
+            false => Err(unexpected_eof()),
+        };
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], what: &str) -> Result<u64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| KvsError::Protocol(format!("invalid {}", what)))
+}
+
+fn too_large(max_size: u32) -> KvsError {
+    KvsError::Protocol(format!(
+        "request too large, at most {} bytes are accepted",
+        max_size
+    ))
+}
+
+fn unexpected_eof() -> KvsError {
+    KvsError::Io(io::ErrorKind::UnexpectedEof.into())
+}
+
+/// Runs one command, returning the reply and whether the client asked to close the connection.
+pub(crate) fn execute<E: KvsEngine>(
+    engine: &E,
+    args: Vec<Vec<u8>>,
+    read_only: bool,
+    session: &mut Session,
+    cursors: &mut Cursors,
+    metrics: &Metrics,
+) -> (Frame, bool) {
+    let mut args = match args
+        .into_iter()
+        .map(String::from_utf8)
+        .collect::<std::result::Result<Vec<_>, _>>()
+    {
+        Ok(args) => args,
+        Err(_) => return (Frame::error("ERR keys and values must be UTF-8"), false),
+    };
+    let name = args.remove(0).to_ascii_uppercase();
+    debug!("Receive command {} {:?}", name, args);
+    // 和 redis 一样，认证之前除了 AUTH 和 QUIT 什么都不能做
+    if matches!(session.access(), Access::Nothing) && name != "AUTH" && name != "QUIT" {
+        return (Frame::error("NOAUTH Authentication required."), false);
+    }
+
+    let handle = |req| handler::handle(engine, req, read_only, session.access(), metrics);
+    let reply = match (name.as_str(), args.len()) {
+        ("QUIT", _) => return (Frame::ok(), true),
+        ("PING", 0) => Frame::Simple("PONG".to_owned()),
+        ("PING", 1) => Frame::bulk(args.remove(0)),
+        ("GET", 1) => match handle(Request::Get {
+            key: args.remove(0),
+        }) {
+            Response::Value(Some(value)) => Frame::bulk(value),
+            Response::Value(None) => Frame::Null,
+            resp => error(resp),
+        },
+        ("SET", 2) => {
+            let value = args.pop().unwrap();
+            let key = args.pop().unwrap();
+            match handle(Request::Set { key, value }) {
+                Response::Ok => Frame::ok(),
+                resp => error(resp),
+            }
+        }
+        ("SET", n) if n > 2 => Frame::error("ERR SET options are not supported"),
+        ("DEL", n) if n > 0 => match handle(Request::MRemove { keys: args }) {
+            Response::Removed(removed) => count(removed.into_iter()),
+            resp => error(resp),
+        },
+        // 和 redis 一样，重复的 key 算多次
+        ("EXISTS", n) if n > 0 => match handle(Request::MGet { keys: args }) {
+            Response::Values(values) => count(values.iter().map(Option::is_some)),
+            resp => error(resp),
+        },
+        ("SCAN", n) if n > 0 => scan(engine, &args, session.access(), cursors, metrics),
+        ("AUTH", 1) => auth(session, Credentials::Token(args.remove(0)), metrics),
+        ("AUTH", 2) => auth(
+            session,
+            Credentials::Password {
+                user: args.remove(0),
+                password: args.remove(0),
+            },
+            metrics,
+        ),
+        ("SELECT", 1) if args[0] == "0" => Frame::ok(),
+        ("SELECT", 1) => Frame::error("ERR DB index is out of range"),
+        // redis-cli 启动时会查命令的文档，没有也能用
+        ("COMMAND", _) => Frame::Array(Vec::new()),
+        ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "SCAN" | "AUTH" | "SELECT", _) => {
+            Frame::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))
+        }
+        _ => Frame::error(format!("ERR unknown command '{}'", one_line(&name))),
+    };
+    (reply, false)
+}
+
+fn count(items: impl Iterator<Item = bool>) -> Frame {
+    Frame::Integer(items.filter(|&item| item).count() as i64)
+}
+
+fn auth(session: &mut Session, credentials: Credentials, metrics: &Metrics) -> Frame {
+    let started = Instant::now();
+    let resp = session.authenticate(&credentials);
+    metrics.record(RequestType::Auth, started, &resp);
+    match resp {
+        Response::Ok => Frame::ok(),
+        _ => Frame::error("WRONGPASS invalid username-password pair or user is disabled."),
+    }
+}
+
+/// The keys where the `SCAN` cursors of a connection stopped.
+///
+/// redis 客户端要求游标是整数，所以游标只是个编号，对应上一页最后的 key
+#[derive(Default)]
+pub(crate) struct Cursors {
+    last: u64,
+    keys: BTreeMap<u64, String>,
+}
+
+impl Cursors {
+    fn insert(&mut self, key: String) -> u64 {
+        if self.keys.len() >= MAX_CURSORS {
+            self.keys.pop_first();
+        }
+        self.last += 1;
+        self.keys.insert(self.last, key);
+        self.last
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`, a cursor is only valid on the connection that got it.
+///
+/// 和 redis 一样 COUNT 是一次看多少个 key，匹配的可能更少。游标记的是 key，
+/// 两次 SCAN 之间写入的 key 可能返回也可能不返回，一直存在的 key 一定会被返回
+fn scan<E: KvsEngine>(
+    engine: &E,
+    args: &[String],
+    access: &Access,
+    cursors: &mut Cursors,
+    metrics: &Metrics,
+) -> Frame {
+    let started = Instant::now();
+    let page = scan_page(engine, args, access, cursors);
+    match page {
+        Ok(page) => {
+            metrics.record(RequestType::Scan, started, &Response::Ok);
+            page
+        }
+        Err(resp) => {
+            metrics.record(RequestType::Scan, started, &resp);
+            error(resp)
+        }
+    }
+}
+
+/// 失败的时候返回错误的响应，和其他命令一样转成回复，也算进指标里
+fn scan_page<E: KvsEngine>(
+    engine: &E,
+    args: &[String],
+    access: &Access,
+    cursors: &mut Cursors,
+) -> std::result::Result<Frame, Response> {
+    let invalid = |message: &str| Response::Err(ErrorCode::InvalidRequest, message.to_owned());
+    let after = match args[0].parse() {
+        Ok(0) => None,
+        Ok(cursor) => match cursors.keys.get(&cursor) {
+            Some(key) => Some(key.clone()),
+            None => return Err(invalid("invalid cursor")),
+        },
+        Err(_) => return Err(invalid("invalid cursor")),
+    };
+    let mut pattern = "*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in args[1..].chunks(2) {
+        match (option[0].to_ascii_uppercase().as_str(), option.get(1)) {
+            ("MATCH", Some(p)) => pattern = p,
+            ("COUNT", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => count = n,
+                _ => return Err(invalid("value is not an integer or out of range")),
+            },
+            _ => return Err(invalid("syntax error")),
+        }
+    }
+    let count = count.min(handler::MAX_SCAN_LIMIT as usize);
+
+    // 通配符前面的部分交给 engine 按前缀扫描，能读这个前缀才能扫
+    let prefix = &pattern[..pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len())];
+    if let Err(e) = access.check_key(prefix, false) {
+        return Err(Response::Denied(e));
+    }
+    let mut keys = Vec::new();
+    // 第一页从前缀开始：前缀本身单独查，其他带这个前缀的 key 都排在它后面
+    let start = match after {
+        Some(after) => Some(after),
+        None if prefix.is_empty() => None,
+        None => {
+            match engine.get(prefix.to_owned()) {
+                Ok(Some(_)) => keys.push(prefix.to_owned()),
+                Ok(None) => {}
+                Err(e) => return Err(Response::from_error(&e)),
+            }
+            Some(prefix.to_owned())
+        }
+    };
+    let pairs = match engine.scan_after(start, count) {
+        Ok(pairs) => pairs,
+        Err(e) => return Err(Response::from_error(&e)),
+    };
+    let done = pairs.len() < count || pairs.iter().any(|(key, _)| !key.starts_with(prefix));
+    keys.extend(
+        pairs
+            .into_iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix)),
+    );
+    let next = match keys.last() {
+        Some(last) if !done => cursors.insert(last.clone()),
+        _ => 0,
+    };
+    let glob = glob(pattern.as_bytes());
+    let page = keys
+        .into_iter()
+        .filter(|key| glob_match(&glob, key.as_bytes()))
+        .map(Frame::bulk)
+        .collect();
+    Ok(Frame::Array(vec![
+        Frame::bulk(next.to_string()),
+        Frame::Array(page),
+    ]))
+}
+
+/// One element of a glob pattern.
+enum Glob {
+    Any,
+    One,
+    Byte(u8),
+}
+
+/// Parses redis glob patterns with `*`, `?` and `\` escapes, `[` is taken literally.
+fn glob(pattern: &[u8]) -> Vec<Glob> {
+    let mut bytes = pattern.iter();
+    let mut glob = Vec::new();
+    while let Some(&b) = bytes.next() {
+        glob.push(match b {
+            b'*' => Glob::Any,
+            b'?' => Glob::One,
+            // 末尾单独的 `\` 按字面匹配
+            b'\\' => Glob::Byte(*bytes.next().unwrap_or(&b'\\')),
+            b => Glob::Byte(b),
+        });
+    }
+    glob
+}
+
+/// 不递归，失配的时候回到上一个 `*`，让它多吞一个字节，最坏是 O(模式长度 × key 长度)
+fn glob_match(glob: &[Glob], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // 上一个 `*` 后面的位置，和这个 `*` 目前吞到了 s 的哪里
+    let mut star = None;
+    while i < s.len() {
+        match glob.get(p) {
+            Some(Glob::Any) => {
+                star = Some((p + 1, i));
+                p += 1;
+            }
+            Some(Glob::One) => {
+                p += 1;
+                i += 1;
+            }
+            Some(Glob::Byte(b)) if *b == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((after, from)) => {
+                    star = Some((after, from + 1));
+                    p = after;
+                    i = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[p..].iter().all(|g| matches!(g, Glob::Any))
+}
+
+/// The error reply for a failed request, with the prefixes redis uses.
+fn error(resp: Response) -> Frame {
+    match resp {
+        Response::Err(ErrorCode::ReadOnly, _) => {
+            Frame::error("READONLY You can't write against a read only replica.")
+        }
+        Response::Err(_, message) => Frame::error(format!("ERR {}", message)),
+        Response::Denied(message) => Frame::error(format!("NOPERM {}", message)),
+        Response::Redirect(leader) => Frame::error(format!("ERR {}", KvsError::NotLeader(leader))),
+        Response::Busy => Frame::error(format!("ERR {}", KvsError::ServerBusy)),
+        resp => Frame::error(format!("ERR Unexpected response {:?}", resp)),
+    }
+}
@@ -1,76 +1,650 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crossbeam::channel;
 use log::{debug, error, info, warn};
+use rustls::ServerConnection;
+use serde::Deserialize;
 use serde_json::Deserializer;
 
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::auth::{Access, Session};
+use crate::common::{GetResponse, Request, Response};
+use crate::handler::{self, Limits, Protocol, DEFAULT_SHUTDOWN_TIMEOUT, REJECT_TIMEOUT};
+use crate::metrics::Metrics;
+use crate::protocol::{self, Frame};
+use crate::resp;
+use crate::shutdown::ShutdownHandle;
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
+use crate::tls::{ServerTls, TlsStream};
+use crate::trace::Span;
+use crate::{Acl, KvsEngine, KvsError, Result};
 
+/// 多路复用的连接上同时处理请求的线程数
+const MULTIPLEX_WORKERS: usize = 4;
+/// 最多有多少个连接排队等着被拒绝，再多的直接关掉
+const REJECT_BACKLOG: usize = 64;
+/// A server handling each connection on a thread of the pool `P`.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    read_only: bool,
+    tls: Option<ServerTls>,
+    acl: Option<Acl>,
+    limits: Limits,
+    protocol: Protocol,
+    metrics: Metrics,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool,
+            read_only: false,
+            tls: None,
+            acl: None,
+            limits: Limits::default(),
+            protocol: Protocol::Kvs,
+            metrics: Metrics::new(),
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// A read-only server rejects `Set` and `Remove`, used for replicas.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Only accepts TLS connections, authenticated with `tls`.
+    pub fn set_tls(&mut self, tls: ServerTls) {
+        self.tls = Some(tls);
+    }
+
+    /// Requires clients to authenticate, and limits each user to the keys `acl` allows.
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.acl = Some(acl);
+    }
+
+    /// Replaces the default connection limits and timeouts.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Serves `protocol` instead of the kvs protocol.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Records requests and connections in `metrics`, shared with a `MetricsServer`.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Sets how long a shutdown waits for the requests being handled.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Returns a handle which makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        handler::check_protocol(self.protocol, self.tls.is_some())?;
         let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
+        self.shutdown.set_addr(listener.local_addr()?);
+        let connections = Arc::new(Connections::default());
+        let rejecter = Rejecter::spawn(self.tls.is_some())?;
+        // shutdown 可能在 set_addr 之前就调用了，那时它连不过来，要先检查再 accept
+        while !self.shutdown.is_shutdown() {
+            let stream = listener.accept().map(|(stream, _)| stream);
+            // 叫醒 accept 的那个连接不用处理
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let stream = match stream {
+                // 线程池里排队的连接也算，满了就不再往线程池里塞
+                Ok(stream) if connections.len() >= self.limits.max_connections => {
+                    self.metrics.rejected();
+                    rejecter.reject(stream);
+                    continue;
+                }
+                stream => stream,
+            };
             let engine = self.engine.clone();
+            let read_only = self.read_only;
+            let tls = self.tls.clone();
+            let acl = self.acl.clone();
+            let limits = self.limits;
+            let protocol = self.protocol;
+            let metrics = self.metrics.clone();
+            // 在 accept 的线程里登记，还在线程池队列里排队的连接关闭时也能被断开
+            let id = stream.as_ref().ok().and_then(|stream| connections.add(stream));
+            let connections = Arc::clone(&connections);
+            let active = metrics.connection();
+            let queued = metrics.queued();
             self.pool.spawn(move || match stream {
                 // 对于每一个连接，我们都搞一个线程去处理
                 Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
+                    drop(queued);
+                    let _span = Span::connection().enter();
+                    debug!("Serving {:?}", stream.peer_addr());
+                    // TLS 握手也放在线程池里做，不耽误 accept
+                    let res = match (tls, protocol) {
+                        (Some(tls), _) => {
+                            serve_tls(engine, stream, &tls, read_only, acl, limits, metrics)
+                        }
+                        (None, Protocol::Resp) => {
+                            serve_resp(engine, stream, read_only, acl, limits, metrics)
+                        }
+                        (None, Protocol::Kvs) => serve(engine, stream, read_only, acl, limits, metrics),
+                    };
+                    if let Err(e) = res {
                         error!("Error on serving client: {}", e);
                     }
+                    connections.remove(id);
+                    drop(active);
                 }
                 Err(e) => {
                     error!("Connection failed: {}", e);
                 }
             })
         }
-        Ok(())
+
+        // 不再读新的请求，正在处理的请求可以继续写回响应
+        info!("Shutting down, waiting for {} connections", connections.len());
+        connections.shutdown(Shutdown::Read);
+        if !connections.wait(self.shutdown_timeout) {
+            warn!("Requests still running after {:?}", self.shutdown_timeout);
+            connections.shutdown(Shutdown::Both);
+        }
+        drop(self.pool);
+        self.engine.flush()
     }
 }
 
-pub fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+/// 正在服务的连接，关闭时用来断开它们并等待处理完成
+#[derive(Default)]
+struct Connections {
+    streams: Mutex<(u64, HashMap<u64, TcpStream>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn add(&self, stream: &TcpStream) -> Option<u64> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Cannot track connection: {}", e);
+                return None;
+            }
+        };
+        let mut streams = self.streams.lock().unwrap();
+        let id = streams.0;
+        streams.0 += 1;
+        streams.1.insert(id, stream);
+        Some(id)
+    }
+
+    fn remove(&self, id: Option<u64>) {
+        if let Some(id) = id {
+            self.streams.lock().unwrap().1.remove(&id);
+            self.closed.notify_all();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.streams.lock().unwrap().1.len()
+    }
+
+    fn shutdown(&self, how: Shutdown) {
+        for stream in self.streams.lock().unwrap().1.values() {
+            let _ = stream.shutdown(how);
+        }
+    }
+
+    /// 等所有连接处理完，超时返回 false
+    fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut streams = self.streams.lock().unwrap();
+        while !streams.1.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            streams = self.closed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// 连接数满了之后拒绝新的连接，单独一个线程，不占线程池也不耽误 accept
+struct Rejecter {
+    sender: channel::Sender<TcpStream>,
+}
+
+impl Rejecter {
+    /// The thread exits once the `Rejecter` is dropped.
+    fn spawn(tls: bool) -> Result<Rejecter> {
+        let (sender, receiver) = channel::bounded::<TcpStream>(REJECT_BACKLOG);
+        thread::Builder::new()
+            .name("kvs-rejecter".to_owned())
+            .spawn(move || {
+                for tcp in receiver {
+                    if let Err(e) = reject(tcp, tls) {
+                        debug!("Error on rejecting client: {}", e);
+                    }
+                }
+            })?;
+        Ok(Rejecter { sender })
+    }
+
+    fn reject(&self, tcp: TcpStream) {
+        warn!("Too many connections, rejecting {:?}", tcp.peer_addr());
+        // 排不上队的连接直接关掉
+        let _ = self.sender.try_send(tcp);
+    }
+}
+
+/// Tells the client the server is busy and closes the connection.
+///
+/// TLS clients are just disconnected, a handshake costs more than the connection is worth.
+fn reject(mut tcp: TcpStream, tls: bool) -> Result<()> {
+    tcp.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    tcp.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    if !tls {
+        let mut first = [0; 1];
+        if tcp.peek(&mut first)? == 1 {
+            tcp.write_all(&handler::busy_reply(first[0])?)?;
+        }
+    }
+    tcp.shutdown(Shutdown::Write)?;
+    // 客户端发来的数据没读完就关的话会发 RST，客户端可能读不到上面的回复
+    io::copy(&mut tcp.take(64 * 1024), &mut io::sink())?;
+    Ok(())
+}
+
+/// Serves one connection, picking the protocol from the first byte the client sends.
+pub fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+) -> Result<()> {
+    tcp.set_write_timeout(Some(limits.request_timeout))?;
+    let first = match peek_first(&tcp, &limits)? {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    if first != protocol::MAGIC[0] {
+        return serve_json(engine, tcp, read_only, acl, limits, &metrics);
+    }
     let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
+    protocol::server_handshake(&mut &tcp)?;
+    let reader = TimedReader::new(&tcp, tcp.try_clone()?, limits);
+    serve_framed(engine, reader, &tcp, peer_addr, read_only, acl, limits, metrics)
+}
+
+/// Serves one connection speaking RESP.
+fn serve_resp<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+) -> Result<()> {
+    tcp.set_write_timeout(Some(limits.request_timeout))?;
+    let peer_addr = tcp.peer_addr()?;
+    let mut reader = BufReader::new(TimedReader::new(&tcp, tcp.try_clone()?, limits));
     let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-    warn!("{}", peer_addr);
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
+    let mut session = Session::new(acl);
+    let mut cursors = resp::Cursors::default();
+
+    loop {
+        let args = match resp::read_command(&mut reader, limits.max_request_size) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            // 出错之后找不到下一个命令从哪开始，回复之后关闭连接
+            Err(KvsError::Protocol(e)) => {
+                resp::Frame::error(format!("ERR Protocol error: {}", e)).write(&mut writer)?;
+                writer.flush()?;
+                return Err(KvsError::Protocol(e));
+            }
+            Err(e) => return Err(e),
+        };
+        reader.get_mut().request_done();
+        // inline 命令的空行直接忽略
+        if args.is_empty() {
+            continue;
+        }
+        let _span = Span::current_request().enter();
+        let (reply, quit) = resp::execute(
+            &engine,
+            args,
+            read_only,
+            &mut session,
+            &mut cursors,
+            &metrics,
+        );
+        reply.write(&mut writer)?;
+        // 流水线发来的命令还没处理完的话，先不 flush
+        if quit || reader.buffer().is_empty() {
             writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-            warn!("Response sent to {}: {:?}", peer_addr, resp);
-        }};
+        }
+        debug!("Reply sent to {}: {:?}", peer_addr, reply);
+        if quit {
+            break;
+        }
     }
+    Ok(())
+}
 
-    for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
-        match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
-                Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
-            }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
-            }),
+/// Waits at most `idle_timeout` for the first byte, `None` if the client closed or stayed silent.
+///
+/// 之后的握手要在 `request_timeout` 内完成
+fn peek_first(tcp: &TcpStream, limits: &Limits) -> Result<Option<u8>> {
+    tcp.set_read_timeout(Some(limits.idle_timeout))?;
+    let mut first = [0; 1];
+    let first = match tcp.peek(&mut first) {
+        Ok(0) => None,
+        Ok(_) => Some(first[0]),
+        Err(e) if is_timeout(&e) => {
+            debug!("Closing idle connection");
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    tcp.set_read_timeout(Some(limits.request_timeout))?;
+    Ok(first)
+}
+
+/// 超时的时候 socket 返回的错误在不同平台上不一样
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Applies the idle and request timeouts to the reads of a framed connection.
+///
+/// 等下一个请求时用 `idle_timeout`，超时当作连接关闭；读到请求的第一个字节之后，
+/// 整个请求要在 `request_timeout` 内读完。超时是 socket 的选项，TLS 连接读的也是这个 socket
+struct TimedReader<R> {
+    inner: R,
+    tcp: TcpStream,
+    limits: Limits,
+    deadline: Option<Instant>,
+}
+
+impl<R: Read> TimedReader<R> {
+    fn new(inner: R, tcp: TcpStream, limits: Limits) -> Self {
+        TimedReader {
+            inner,
+            tcp,
+            limits,
+            deadline: None,
+        }
+    }
+
+    /// 读完一个请求之后调用，下一次读又是在等新的请求
+    fn request_done(&mut self) {
+        self.deadline = None;
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => {
+                self.tcp.set_read_timeout(Some(self.limits.idle_timeout))?;
+                return match self.inner.read(buf) {
+                    Ok(n) => {
+                        if n > 0 {
+                            self.deadline = Some(Instant::now() + self.limits.request_timeout);
+                        }
+                        Ok(n)
+                    }
+                    Err(e) if is_timeout(&e) => {
+                        debug!("Closing idle connection");
+                        Ok(0)
+                    }
+                    Err(e) => Err(e),
+                };
+            }
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Request timed out"));
+        }
+        self.tcp.set_read_timeout(Some(left))?;
+        self.inner.read(buf)
+    }
+}
+
+/// Serves one TLS connection, which always speaks the framed protocol.
+fn serve_tls<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    tls: &ServerTls,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+) -> Result<()> {
+    tcp.set_write_timeout(Some(limits.request_timeout))?;
+    // 明文的客户端发完 4 字节的握手就在等回复，而 TLS 要等一个完整的记录头，两边会卡住
+    match peek_first(&tcp, &limits)? {
+        Some(first) if first == protocol::MAGIC[0] => {
+            return Err(KvsError::Tls("Plaintext client on a TLS server".to_owned()))
+        }
+        Some(_) => {}
+        None => return Ok(()),
+    }
+    let peer_addr = tcp.peer_addr()?;
+    let timeouts = tcp.try_clone()?;
+    let mut stream = TlsStream::handshake(tcp, ServerConnection::new(tls.config())?.into())?;
+    protocol::server_handshake(&mut stream)?;
+    let reader = TimedReader::new(stream.try_clone()?, timeouts, limits);
+    serve_framed(engine, reader, stream, peer_addr, read_only, acl, limits, metrics)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve_framed<E: KvsEngine, R: Read, W: Write + Send>(
+    engine: E,
+    reader: TimedReader<R>,
+    writer: W,
+    peer_addr: SocketAddr,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: Metrics,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::new(acl);
+
+    loop {
+        let frame = match Frame::read_limited(&mut reader, limits.max_request_size) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(KvsError::FrameTooLarge { id, len }) => {
+                handler::too_large(id, len, &limits)
+                    .to_frame(id)?
+                    .write(&mut writer)?;
+                writer.flush()?;
+                return Err(KvsError::FrameTooLarge { id, len });
+            }
+            Err(e) => return Err(e),
+        };
+        reader.get_mut().request_done();
+        let _span = Span::current_request().enter();
+        if frame.code == protocol::OP_MULTIPLEX {
+            Response::Ok.to_frame(frame.id)?.write(&mut writer)?;
+            writer.flush()?;
+            debug!("{} switched to multiplexed mode", peer_addr);
+            return serve_multiplexed(engine, reader, writer, read_only, session, limits, metrics);
+        }
+        let resp = match handler::authenticate(&mut session, &frame, &metrics) {
+            Some(resp) => resp,
+            None => handler::respond(&engine, &frame, read_only, session.access(), &metrics),
+        };
+        handler::encode(&resp, frame.id)?.write(&mut writer)?;
+        // 客户端流水线发来的请求还没处理完的话，先不 flush，攒一起发回去
+        if reader.buffer().is_empty() {
+            writer.flush()?;
         }
+        debug!("Response sent to {}: {:?}", peer_addr, resp);
     }
     Ok(())
 }
+
+/// Handles the requests of a connection on several threads, answering each as soon as it's done.
+///
+/// 响应的顺序和请求不一样，客户端靠 request id 对应。worker 的数量是固定的，
+/// 都忙的时候 channel 满了，就不再读新的请求
+fn serve_multiplexed<E: KvsEngine, R: Read, W: Write + Send>(
+    engine: E,
+    mut reader: BufReader<TimedReader<R>>,
+    writer: BufWriter<W>,
+    read_only: bool,
+    mut session: Session,
+    limits: Limits,
+    metrics: Metrics,
+) -> Result<()> {
+    let writer = Mutex::new(writer);
+    let (sender, receiver) = channel::bounded::<(Frame, Access, Span)>(MULTIPLEX_WORKERS);
+    thread::scope(|scope| {
+        for _ in 0..MULTIPLEX_WORKERS {
+            let engine = engine.clone();
+            let receiver = receiver.clone();
+            let writer = &writer;
+            let metrics = &metrics;
+            scope.spawn(move || {
+                for (frame, access, span) in receiver {
+                    let _span = span.enter();
+                    let resp = handler::respond(&engine, &frame, read_only, &access, metrics);
+                    send_locked(writer, &resp, frame.id);
+                }
+            });
+        }
+
+        let mut res = Ok(());
+        loop {
+            match Frame::read_limited(&mut reader, limits.max_request_size) {
+                Ok(Some(frame)) => {
+                    reader.get_mut().request_done();
+                    let span = Span::current_request();
+                    // 认证要在后面的请求之前生效，不能交给 worker 并发处理
+                    if let Some(resp) = handler::authenticate(&mut session, &frame, &metrics) {
+                        send_locked(&writer, &resp, frame.id);
+                        continue;
+                    }
+                    // worker 都还活着，发送不会失败
+                    let _ = sender.send((frame, session.access().clone(), span));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    if let KvsError::FrameTooLarge { id, len } = e {
+                        send_locked(&writer, &handler::too_large(id, len, &limits), id);
+                    }
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        // 关掉 channel，worker 处理完手上的请求就退出
+        drop(sender);
+        res
+    })
+}
+
+fn send_locked<W: Write>(writer: &Mutex<BufWriter<W>>, resp: &Response, id: u32) {
+    let res = handler::encode(resp, id).and_then(|resp_frame| {
+        let mut writer = writer.lock().unwrap();
+        resp_frame.write(&mut *writer)?;
+        writer.flush()?;
+        Ok(())
+    });
+    if let Err(e) = res {
+        error!("Error on sending response: {}", e);
+    }
+}
+
+/// The streaming JSON protocol spoken by older clients.
+///
+/// 和帧协议一样，等请求时用 `idle_timeout`，一个请求要在 `request_timeout` 内读完，
+/// 最多 `max_request_size` 字节
+fn serve_json<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    read_only: bool,
+    acl: Option<Acl>,
+    limits: Limits,
+    metrics: &Metrics,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let mut session = Session::new(acl);
+    let mut reader = BufReader::new(TimedReader::new(&tcp, tcp.try_clone()?, limits));
+    let mut writer = BufWriter::new(&tcp);
+    let mut send = |resp: &[u8]| -> Result<()> {
+        writer.write_all(resp)?;
+        writer.flush()?;
+        debug!(
+            "Response sent to {}: {}",
+            peer_addr,
+            String::from_utf8_lossy(resp)
+        );
+        Ok(())
+    };
+
+    while skip_whitespace(&mut reader)? {
+        let mut limited = (&mut reader).take(limits.max_request_size as u64);
+        let req = match Request::deserialize(&mut Deserializer::from_reader(&mut limited)) {
+            Ok(req) => req,
+            // 超过大小的请求没法跳过，回复之后就关闭连接
+            Err(_) if limited.limit() == 0 => {
+                let message = handler::json_too_large(&limits);
+                send(&serde_json::to_vec(&GetResponse::Err(message.clone()))?)?;
+                return Err(KvsError::Protocol(message));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        reader.get_mut().request_done();
+        let _span = Span::current_request().enter();
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        let resp = handler::respond_json(&engine, req, read_only, &mut session, metrics)?;
+        send(&resp)?;
+    }
+    Ok(())
+}
+
+/// Skips the whitespace between two JSON requests, `false` if the connection was closed.
+fn skip_whitespace<R: Read>(reader: &mut BufReader<TimedReader<R>>) -> Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        let n = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let done = n < buf.len();
+        reader.consume(n);
+        if done {
+            return Ok(true);
+        }
+        // 只读到了空白，还是在等下一个请求
+        reader.get_mut().request_done();
+    }
+}
@@ -0,0 +1,57 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+/// Stops a running `KvsServer` or `AsyncKvsServer` from another thread.
+///
+/// The server stops accepting connections, lets the requests being handled
+/// finish, flushes the engine and returns from `run`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    shutdown: AtomicBool,
+    /// 服务器监听的地址，同步服务器阻塞在 accept 上，要连一下才能叫醒它
+    addr: Mutex<Option<SocketAddr>>,
+    notify: Notify,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        ShutdownHandle::default()
+    }
+
+    pub fn shutdown(&self) {
+        if self.inner.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.inner.notify.notify_waiters();
+        if let Some(addr) = *self.inner.addr.lock().unwrap() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shutdown.load(Ordering::SeqCst)
+    }
+
+    #[cfg(feature = "sync-server")]
+    pub(crate) fn set_addr(&self, addr: SocketAddr) {
+        *self.inner.addr.lock().unwrap() = Some(addr);
+    }
+
+    /// Waits until `shutdown` is called.
+    pub(crate) async fn wait(&self) {
+        // 先注册再检查，防止在两者之间调用的 shutdown 被漏掉
+        let notified = self.inner.notify.notified();
+        if self.is_shutdown() {
+            return;
+        }
+        notified.await;
+    }
+}
@@ -0,0 +1,300 @@
+//! TLS for the connections between clients and servers.
+//!
+//! Only the framed protocol can be spoken over TLS, the old JSON clients never
+//! supported it.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+    ServerConfig, ServerName,
+};
+use rustls_pemfile::Item;
+
+use crate::{KvsError, Result};
+
+/// 证书校验不支持 IP 地址，默认按这个名字检查服务端证书
+const DEFAULT_SERVER_NAME: &str = "localhost";
+/// 一次从 socket 读多少密文，明文缓冲区只在读空之后才会填，不会超过 rustls 的上限
+const READ_CHUNK: usize = 8 * 1024;
+
+/// The certificate a server presents, and optionally the CA its clients must be signed by.
+#[derive(Clone)]
+pub struct ServerTls {
+    certs: Vec<Certificate>,
+    key: PrivateKey,
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Loads the PEM encoded certificate chain and private key of the server.
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let certs = load_certs(cert.as_ref())?;
+        let key = load_key(key.as_ref())?;
+        let config = server_config(&certs, &key, None)?;
+        Ok(ServerTls { certs, key, config })
+    }
+
+    /// Requires every client to present a certificate signed by one of the CAs in `ca`.
+    pub fn set_client_ca(&mut self, ca: impl AsRef<Path>) -> Result<()> {
+        let roots = load_roots(ca.as_ref())?;
+        self.config = server_config(&self.certs, &self.key, Some(roots))?;
+        Ok(())
+    }
+
+    pub(crate) fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+}
+
+/// The CAs a client trusts, and optionally the certificate it authenticates itself with.
+#[derive(Clone)]
+pub struct ClientTls {
+    roots: RootCertStore,
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    /// Trusts the servers whose certificate is signed by one of the CAs in the PEM file `ca`.
+    pub fn new(ca: impl AsRef<Path>) -> Result<Self> {
+        let roots = load_roots(ca.as_ref())?;
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        Ok(ClientTls {
+            roots,
+            server_name: ServerName::try_from(DEFAULT_SERVER_NAME).unwrap(),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Presents this certificate to servers which require client authentication.
+    pub fn set_identity(&mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<()> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.roots.clone())
+            .with_single_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+        self.config = Arc::new(config);
+        Ok(())
+    }
+
+    /// Sets the DNS name checked against the server certificate, `localhost` by default.
+    pub fn set_server_name(&mut self, name: &str) -> Result<()> {
+        let name = ServerName::try_from(name)
+            .map_err(|_| KvsError::Tls(format!("Invalid server name {}", name)))?;
+        self.server_name = name;
+        Ok(())
+    }
+
+    pub(crate) fn connect(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let conn = ClientConnection::new(Arc::clone(&self.config), self.server_name.clone())?;
+        TlsStream::handshake(tcp, conn.into())
+    }
+}
+
+fn server_config(
+    certs: &[Certificate],
+    key: &PrivateKey,
+    client_roots: Option<RootCertStore>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_roots {
+        Some(roots) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots)),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(
+        builder.with_single_cert(certs.to_vec(), key.clone())?,
+    ))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "No certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => {}
+        }
+    }
+    Err(KvsError::Tls(format!(
+        "No private key in {}",
+        path.display()
+    )))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| KvsError::Tls(format!("Invalid CA certificate: {}", e)))?;
+    }
+    Ok(roots)
+}
+
+/// A TLS connection which can be cloned like a `TcpStream`, one clone reading while another writes.
+///
+/// 读的时候不拿锁阻塞在 socket 上，读到密文之后才锁住 rustls 的连接解密；
+/// 写的时候先在锁里加密，再在 `write` 锁里发出去，保证密文的顺序
+pub(crate) struct TlsStream {
+    tcp: TcpStream,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    conn: Mutex<Connection>,
+    write: Mutex<()>,
+}
+
+impl TlsStream {
+    pub(crate) fn handshake(mut tcp: TcpStream, mut conn: Connection) -> Result<TlsStream> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+        Ok(TlsStream {
+            tcp,
+            shared: Arc::new(Shared {
+                conn: Mutex::new(conn),
+                write: Mutex::new(()),
+            }),
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            tcp: self.tcp.try_clone()?,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+
+    /// 把加密好的数据发出去，调用的时候要拿着 `write` 锁
+    fn send_tls(&self) -> io::Result<()> {
+        let mut buf = Vec::new();
+        {
+            let mut conn = self.shared.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut buf)?;
+            }
+        }
+        (&self.tcp).write_all(&buf)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.shared.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            let mut chunk = [0; READ_CHUNK];
+            let n = (&self.tcp).read(&mut chunk)?;
+            let wants_write = {
+                let mut conn = self.shared.conn.lock().unwrap();
+                if n == 0 {
+                    // 对方没发 close_notify 就断开了，对上层来说就是 EOF
+                    return Ok(0);
+                }
+                let mut chunk = &chunk[..n];
+                while !chunk.is_empty() {
+                    conn.read_tls(&mut chunk)?;
+                    conn.process_new_packets()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+                conn.wants_write()
+            };
+            // 比如要回复的 alert，正在写的线程会顺便把它发出去
+            if wants_write {
+                if let Ok(_guard) = self.shared.write.try_lock() {
+                    self.send_tls()?;
+                }
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _guard = self.shared.write.lock().unwrap();
+        let n = self.shared.conn.lock().unwrap().writer().write(buf)?;
+        self.send_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _guard = self.shared.write.lock().unwrap();
+        self.shared.conn.lock().unwrap().writer().flush()?;
+        self.send_tls()
+    }
+}
+
+/// A client connection, plain TCP or TLS.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(tcp) => tcp.try_clone().map(Stream::Tcp),
+            Stream::Tls(tls) => tls.try_clone().map(Stream::Tls),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.shutdown(how),
+            Stream::Tls(tls) => tls.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
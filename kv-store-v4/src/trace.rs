@@ -0,0 +1,147 @@
+//! Connection and request ids for the logs, and the audit log of writes.
+//!
+//! Every connection gets a `Span` and every request a child of it. The span entered on the
+//! current thread, or the one of the current tokio task, is what a logger adds to each line,
+//! see `Span::current`.
+//!
+//! Mutating requests are logged with the `kvs::audit` target, one JSON object per message.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, log_enabled, Level};
+use serde_json::json;
+
+use crate::auth::Access;
+use crate::common::{Request, Response};
+
+/// The log target of the audit entries.
+pub const AUDIT_TARGET: &str = "kvs::audit";
+
+/// 连接和请求的 id 在整个进程里递增，0 留给不属于任何连接的日志
+static NEXT_CONN: AtomicU64 = AtomicU64::new(1);
+static NEXT_REQ: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<Span>> = Cell::new(None);
+}
+
+tokio::task_local! {
+    // async 的任务会在不同的线程上跑，span 要跟着任务走
+    static TASK: Span;
+}
+
+/// Identifies the connection, and the request of it, a log line belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub conn: u64,
+    pub req: Option<u64>,
+}
+
+/// Restores the span entered before when dropped.
+///
+/// 只在同步的代码里持有，async 的任务可能在 await 之后换一个线程继续跑
+pub(crate) struct Entered {
+    previous: Option<Span>,
+}
+
+impl Span {
+    /// A span for a new connection.
+    pub(crate) fn connection() -> Span {
+        Span {
+            conn: NEXT_CONN.fetch_add(1, Ordering::Relaxed),
+            req: None,
+        }
+    }
+
+    /// A span for a new request on the connection of `self`.
+    pub(crate) fn request(self) -> Span {
+        Span {
+            conn: self.conn,
+            req: Some(NEXT_REQ.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+
+    /// Makes `self` the current span of this thread until the guard is dropped.
+    pub(crate) fn enter(self) -> Entered {
+        Entered {
+            previous: CURRENT.with(|current| current.replace(Some(self))),
+        }
+    }
+
+    /// Runs `fut` with `self` as the span of the task.
+    pub(crate) fn scope<F: Future>(self, fut: F) -> impl Future<Output = F::Output> {
+        TASK.scope(self, fut)
+    }
+
+    /// The span entered on this thread or the current task, if any.
+    pub fn current() -> Option<Span> {
+        CURRENT
+            .with(Cell::get)
+            .or_else(|| TASK.try_with(|span| *span).ok())
+    }
+
+    /// A request span under the current connection, for code handling one request.
+    pub(crate) fn current_request() -> Span {
+        Span::current().unwrap_or_default().request()
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn={}", self.conn)?;
+        if let Some(req) = self.req {
+            write!(f, " req={}", req)?;
+        }
+        Ok(())
+    }
+}
+
+/// What an audit entry records of a write, `None` for the other requests.
+///
+/// 请求会被 engine 拿走，要在处理之前记下来
+pub(crate) fn audit_entry(req: &Request, access: &Access) -> Option<serde_json::Value> {
+    if !log_enabled!(target: AUDIT_TARGET, Level::Info) {
+        return None;
+    }
+    let (op, keys): (_, Vec<&str>) = match req {
+        Request::Set { key, .. } => ("set", vec![key.as_str()]),
+        Request::Remove { key } => ("remove", vec![key.as_str()]),
+        Request::MSet { pairs } => ("mset", pairs.iter().map(|(key, _)| key.as_str()).collect()),
+        Request::MRemove { keys } => ("mremove", keys.iter().map(String::as_str).collect()),
+        _ => return None,
+    };
+    let span = Span::current().unwrap_or_default();
+    Some(json!({
+        "ts": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0),
+        "conn": span.conn,
+        "req": span.req,
+        "user": access.user(),
+        "op": op,
+        "keys": keys,
+    }))
+}
+
+/// Logs the entry with the outcome of the write.
+pub(crate) fn audit(mut entry: serde_json::Value, resp: &Response) {
+    entry["result"] = match resp {
+        Response::Ok | Response::Removed(_) => json!("ok"),
+        Response::Err(_, message) => json!(message),
+        Response::Denied(_) => json!("permission denied"),
+        Response::Redirect(_) => json!("not leader"),
+        resp => json!(format!("{:?}", resp)),
+    };
+    info!(target: AUDIT_TARGET, "{}", entry);
+}
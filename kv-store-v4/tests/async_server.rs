@@ -0,0 +1,103 @@
+mod common;
+
+use common::start_async_server;
+use kvs::{AsyncKvsClient, KvsClient, KvsError, MultiplexedKvsClient, Pipeline};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// 在 runtime 里启动服务器，runtime 被 drop 时服务器也跟着停掉
+fn start_server(addr: &'static str) -> (TempDir, Runtime) {
+    let temp_dir = TempDir::new().unwrap();
+    let rt = Runtime::new().unwrap();
+    start_async_server(&rt, temp_dir.path(), addr);
+    (temp_dir, rt)
+}
+
+#[test]
+fn async_client() {
+    let (_temp_dir, rt) = start_server("127.0.0.1:4040");
+    rt.block_on(async {
+        let mut client = AsyncKvsClient::connect("127.0.0.1:4040").await.unwrap();
+        client
+            .set("key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).await.unwrap(),
+            Some("value1".to_owned())
+        );
+        client.remove("key1".to_owned()).await.unwrap();
+        assert_eq!(client.get("key1".to_owned()).await.unwrap(), None);
+        assert!(matches!(
+            client.remove("key1".to_owned()).await,
+            Err(KvsError::KeyNotFound)
+        ));
+    });
+}
+
+// Every kind of client the sync server supports works with the async one too
+#[test]
+fn sync_clients() {
+    let (_temp_dir, _rt) = start_server("127.0.0.1:4041");
+
+    let mut client = KvsClient::connect("127.0.0.1:4041").unwrap();
+    let mut pipeline = Pipeline::new();
+    for i in 0..500 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    for i in 0..500 {
+        pipeline.get(format!("key{}", i));
+    }
+    let results = client.execute(pipeline).unwrap();
+    for (i, result) in results[500..].iter().enumerate() {
+        assert_eq!(result.as_ref().unwrap(), &Some(format!("value{}", i)));
+    }
+
+    let multiplexed = MultiplexedKvsClient::connect("127.0.0.1:4041").unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let client = multiplexed.clone();
+            thread::spawn(move || {
+                for i in (t..500).step_by(4) {
+                    assert_eq!(
+                        client.get(format!("key{}", i)).unwrap(),
+                        Some(format!("value{}", i))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // 旧的 json 客户端
+    let stream = TcpStream::connect("127.0.0.1:4041").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(br#"{"Get":{"key":"key1"}}"#).unwrap();
+    let mut buf = Vec::new();
+    reader.read_until(b'}', &mut buf).unwrap();
+    assert_eq!(buf, br#"{"Ok":"value1"}"#);
+}
+
+// Idle connections are tasks, not threads, so a lot of them don't block new clients
+#[test]
+fn many_idle_connections() {
+    let (_temp_dir, _rt) = start_server("127.0.0.1:4042");
+
+    let idle: Vec<_> = (0..1000)
+        .map(|_| KvsClient::connect("127.0.0.1:4042").unwrap())
+        .collect();
+
+    let mut client = KvsClient::connect("127.0.0.1:4042").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(idle);
+}
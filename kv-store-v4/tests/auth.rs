@@ -0,0 +1,246 @@
+mod common;
+
+use common::wait_for;
+use kvs::replication::Follower;
+use kvs::{
+    hash_password, Acl, AsyncKvsClient, AsyncKvsServer, Credentials, KvStore, KvsClient, KvsEngine,
+    KvsError, MultiplexedKvsClient,
+};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const ACL: &str = r#"{
+    "users": {
+        "alice": {
+            "password_hash": "pbkdf2-sha256$1000$a3ZzLXRlc3Qtc2FsdC0wMQ==$EPXEqdHISvvV9KjObLbliWZ6qZ6KpWAvGW+bs4uPnq8=",
+            "rules": [{ "prefix": "app/", "read": true, "write": true }]
+        },
+        "monitor": {
+            "tokens": ["t0k3n"],
+            "rules": [{ "prefix": "app/", "read": true }]
+        },
+        "replica": {
+            "tokens": ["r3pl1ca"],
+            "rules": [{ "prefix": "", "read": true }]
+        }
+    }
+}"#;
+
+fn acl(dir: &Path) -> Acl {
+    let path = dir.join("acl.json");
+    fs::write(&path, ACL).unwrap();
+    Acl::load(path).unwrap()
+}
+
+fn alice() -> Credentials {
+    Credentials::Password {
+        user: "alice".to_owned(),
+        password: "secret".to_owned(),
+    }
+}
+
+fn assert_denied<T: std::fmt::Debug>(res: kvs::Result<T>) {
+    match res {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("Expected permission denied, got {:?}", res),
+    }
+}
+
+#[cfg(feature = "sync-server")]
+fn start_server(addr: &'static str) -> TempDir {
+    common::start_server(addr, |server, dir| server.set_acl(acl(dir)))
+}
+
+// Nothing is allowed before authenticating, afterwards only what the rules grant
+#[cfg(feature = "sync-server")]
+#[test]
+fn acl_rules() {
+    let _temp_dir = start_server("127.0.0.1:4070");
+    let mut client = KvsClient::connect("127.0.0.1:4070").unwrap();
+    assert_denied(client.get("app/key1".to_owned()));
+    assert_denied(client.auth(Credentials::Password {
+        user: "alice".to_owned(),
+        password: "wrong".to_owned(),
+    }));
+    assert_denied(client.auth(Credentials::Token("wrong".to_owned())));
+
+    client.auth(alice()).unwrap();
+    client
+        .set("app/key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert_eq!(
+        client.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_denied(client.set("other/key1".to_owned(), "value1".to_owned()));
+    assert_denied(client.get("other/key1".to_owned()));
+    // One key out of reach denies the whole request
+    assert_denied(client.mget(vec!["app/key1".to_owned(), "other/key1".to_owned()]));
+    assert_eq!(
+        client.mget(vec!["app/key1".to_owned()]).unwrap(),
+        vec![Some("value1".to_owned())]
+    );
+
+    let mut monitor = KvsClient::connect("127.0.0.1:4070").unwrap();
+    monitor
+        .auth(Credentials::Token("t0k3n".to_owned()))
+        .unwrap();
+    assert_eq!(
+        monitor.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_denied(monitor.remove("app/key1".to_owned()));
+    assert_denied(monitor.mset(vec![("app/key2".to_owned(), "value2".to_owned())]));
+
+    // A failed authentication drops the access granted before
+    assert_denied(client.auth(Credentials::Token("wrong".to_owned())));
+    assert_denied(client.get("app/key1".to_owned()));
+}
+
+#[cfg(feature = "sync-server")]
+#[test]
+fn acl_multiplexed_and_json_clients() {
+    let _temp_dir = start_server("127.0.0.1:4071");
+    let client = MultiplexedKvsClient::connect("127.0.0.1:4071").unwrap();
+    assert_denied(client.set("app/key1".to_owned(), "value1".to_owned()));
+    client.auth(alice()).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let client = client.clone();
+            thread::spawn(move || {
+                client
+                    .set(format!("app/key{}", t), format!("value{}", t))
+                    .unwrap();
+                assert_denied(client.get(format!("other/key{}", t)));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let stream = TcpStream::connect("127.0.0.1:4071").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut ask = |req: &str| {
+        writer.write_all(req.as_bytes()).unwrap();
+        let mut buf = Vec::new();
+        reader.read_until(b'}', &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    assert!(ask(r#"{"Get":{"key":"app/key1"}}"#).contains("Permission denied"));
+    assert_eq!(
+        ask(r#"{"Auth":{"Password":{"user":"alice","password":"secret"}}}"#),
+        r#"{"Ok":null}"#
+    );
+    assert_eq!(ask(r#"{"Get":{"key":"app/key1"}}"#), r#"{"Ok":"value1"}"#);
+}
+
+#[test]
+fn acl_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = AsyncKvsServer::new(engine);
+    server.set_acl(acl(temp_dir.path()));
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4072"));
+    wait_for("127.0.0.1:4072");
+
+    rt.block_on(async {
+        let mut client = AsyncKvsClient::connect("127.0.0.1:4072").await.unwrap();
+        assert_denied(client.get("app/key1".to_owned()).await);
+        client.auth(alice()).await.unwrap();
+        client
+            .set("app/key1".to_owned(), "value1".to_owned())
+            .await
+            .unwrap();
+        assert_denied(client.remove("other/key1".to_owned()).await);
+    });
+
+    let client = MultiplexedKvsClient::connect("127.0.0.1:4072").unwrap();
+    client.auth(Credentials::Token("t0k3n".to_owned())).unwrap();
+    assert_eq!(
+        client.get("app/key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_denied(client.set("app/key1".to_owned(), "value2".to_owned()));
+}
+
+// Passwords are stored as hashes, plaintext ones and broken hashes are refused
+#[test]
+fn acl_password_hashes() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("acl.json");
+    let hash = hash_password("hunter2").unwrap();
+    assert!(hash.starts_with("pbkdf2-sha256$"));
+    assert_ne!(hash, hash_password("hunter2").unwrap());
+    fs::write(
+        &path,
+        format!(
+            r#"{{ "users": {{ "bob": {{ "password_hash": "{}" }} }} }}"#,
+            hash
+        ),
+    )
+    .unwrap();
+    let acl = Acl::load(&path).unwrap();
+
+    let mut server = AsyncKvsServer::new(KvStore::open(temp_dir.path()).unwrap());
+    server.set_acl(acl);
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4073"));
+    wait_for("127.0.0.1:4073");
+    let mut client = KvsClient::connect("127.0.0.1:4073").unwrap();
+    assert_denied(client.auth(Credentials::Password {
+        user: "bob".to_owned(),
+        password: hash,
+    }));
+    client
+        .auth(Credentials::Password {
+            user: "bob".to_owned(),
+            password: "hunter2".to_owned(),
+        })
+        .unwrap();
+
+    for user in [
+        r#"{ "password": "secret" }"#,
+        r#"{ "password_hash": "secret" }"#,
+        r#"{ "password_hash": "pbkdf2-sha256$0$c2FsdA==$aGFzaA==" }"#,
+    ] {
+        fs::write(&path, format!(r#"{{ "users": {{ "bob": {} }} }}"#, user)).unwrap();
+        assert!(Acl::load(&path).is_err(), "{} was accepted", user);
+    }
+}
+
+// A follower authenticates before pulling the log of a leader with an ACL
+#[test]
+fn acl_follower() {
+    let leader_dir = TempDir::new().unwrap();
+    let mut server = AsyncKvsServer::new(KvStore::open(leader_dir.path()).unwrap());
+    server.set_acl(acl(leader_dir.path()));
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4074"));
+    wait_for("127.0.0.1:4074");
+    let mut client = KvsClient::connect("127.0.0.1:4074").unwrap();
+    client.auth(alice()).unwrap();
+    client
+        .set("app/key1".to_owned(), "value1".to_owned())
+        .unwrap();
+
+    let follower_dir = TempDir::new().unwrap();
+    let store = KvStore::open(follower_dir.path()).unwrap();
+    let mut follower = Follower::new(store.clone(), "127.0.0.1:4074".parse().unwrap()).unwrap();
+    follower.set_credentials(Credentials::Token("r3pl1ca".to_owned()));
+    follower.spawn().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.get("app/key1".to_owned()).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "follower didn't catch up");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
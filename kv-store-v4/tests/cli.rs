@@ -1,7 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{DirLock, KvStore, KvsClient, KvsEngine, SledKvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -212,12 +215,13 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, pool: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(&["--pool", pool, "--threads", "4"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -299,6 +303,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(&["--pool", pool, "--threads", "4"])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -328,10 +333,634 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "the-book", "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "the-book", "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_server_naive_pool() {
+    cli_access_server("kvs", "naive", "127.0.0.1:4017");
+}
+
+#[test]
+fn cli_access_server_shared_queue_pool() {
+    cli_access_server("kvs", "shared-queue", "127.0.0.1:4018");
+}
+
+#[test]
+fn cli_access_server_rayon_pool() {
+    cli_access_server("kvs", "rayon", "127.0.0.1:4019");
+}
+
+// SIGTERM stops the server cleanly, the data written before is kept
+#[test]
+fn cli_server_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().unwrap();
+}
+
+// Multi-key subcommands
+#[test]
+fn cli_multi_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "key3", "value3", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Missing the value of key4"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "key1", "key3", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mrm", "key1", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found: key3"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "key1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\nvalue2\n");
+    child.kill().unwrap();
+}
+
+// The HTTP gateway runs next to the native port and stops with the server
+#[test]
+fn cli_http_gateway() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--http-addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4009").unwrap();
+    stream
+        .write_all(b"GET /keys/key1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.ends_with("\r\n\r\nvalue1"), "{}", resp);
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+}
+
+// JSON logs carry the connection of each line, writes go to the audit log
+#[test]
+fn cli_json_log_and_audit() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let audit_path = temp_dir.path().join("audit.log");
+    let addr = "127.0.0.1:4014";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--log-format", "json"])
+        .args(&["--log-level", "debug"])
+        .arg("--audit-log")
+        .arg(&audit_path)
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in [
+        &["set", "key1", "value1"][..],
+        &["get", "key1"],
+        &["rm", "key1"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(200));
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let lines: Vec<serde_json::Value> = fs::read_to_string(&stderr_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("not a JSON log line"))
+        .collect();
+    assert!(lines.iter().any(|line| line["msg"]
+        .as_str()
+        .unwrap()
+        .contains(env!("CARGO_PKG_VERSION"))));
+    assert!(lines.iter().any(|line| line["conn"].as_u64() > Some(0)));
+
+    let audit: Vec<serde_json::Value> = fs::read_to_string(&audit_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(audit.len(), 2);
+    assert_eq!(audit[0]["op"], "set");
+    assert_eq!(audit[0]["keys"], serde_json::json!(["key1"]));
+    assert_eq!(audit[0]["result"], "ok");
+    assert_eq!(audit[1]["op"], "remove");
+    assert!(audit[1]["conn"].as_u64() > audit[0]["conn"].as_u64());
+}
+
+// The config file sets the address and data directory, flags override it
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "addr = \"127.0.0.1:4015\"\ndir = \"data\"\npool = \"shared-queue\"\n\n\
+         [storage]\nsync_writes = true\n\n[limits]\nmax_connections = 8\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml", "--print-config"])
+        .args(&["--max-connections", "16"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("addr = \"127.0.0.1:4015\""))
+        .stdout(contains("engine = \"kvs\""))
+        .stdout(contains("pool = \"shared-queue\""))
+        .stdout(contains("sync_writes = true"))
+        .stdout(contains("max_connections = 16"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml", "--print-config"])
+        .args(&["--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("addr = \"127.0.0.1:4016\""));
+
+    fs::write(temp_dir.path().join("bad.toml"), "unknown = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "bad.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field"));
+    for args in [
+        ["--max-connections", "0"],
+        ["--max-request-size", "0"],
+        ["--max-request-size", "100000000"],
+    ] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--config", "kvs.toml", "--print-config"])
+            .args(&args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains(args[0][2..].replace('-', "_")));
+    }
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let data_dir = temp_dir.path().join("data");
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// Migrating kvs -> sled -> kvs keeps every key, the source directory is left alone
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let kvs_dir = temp_dir.path().join("kvs");
+    {
+        let store = KvStore::open(&kvs_dir).unwrap();
+        for i in 0..2500 {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        store.remove("key7".to_owned()).unwrap();
+    }
+    fs::write(kvs_dir.join("engine"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs", "--out", "out"])
+        .args(&["--dir", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not sled"));
+
+    // A running server holds the lock of its directory
+    let lock = DirLock::acquire(&kvs_dir).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", "--out", "sled"])
+        .args(&["--dir", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("stop the server first"));
+    drop(lock);
+
+    let logs = || {
+        let mut logs: Vec<_> = fs::read_dir(&kvs_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().ends_with(".log"))
+            .collect();
+        logs.sort();
+        logs
+    };
+    let before = logs();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", "--out", "sled"])
+        .args(&["--dir", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Migrated 2499 keys"));
+    assert_eq!(logs(), before);
+    let sled_dir = temp_dir.path().join("sled");
+    assert_eq!(fs::read_to_string(sled_dir.join("engine")).unwrap(), "sled");
+    assert_eq!(fs::read_to_string(kvs_dir.join("engine")).unwrap(), "kvs");
+    {
+        let engine = SledKvsEngine::new(sled::open(&sled_dir).unwrap());
+        assert_eq!(
+            engine.get("key42".to_owned()).unwrap(),
+            Some("value42".to_owned())
+        );
+        assert_eq!(engine.get("key7".to_owned()).unwrap(), None);
+    }
+
+    // The output directory must be new
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", "--out", "sled"])
+        .args(&["--dir", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already exists"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs", "--out", "back"])
+        .args(&["--dir", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let store = KvStore::open(temp_dir.path().join("back")).unwrap();
+    assert_eq!(store.scan(String::new()).unwrap().len(), 2499);
+    assert_eq!(
+        store.get("key2499".to_owned()).unwrap(),
+        Some("value2499".to_owned())
+    );
+}
+
+// Exporting from a server and importing into a data directory keeps every pair
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4023";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let pairs = (0..2500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs).unwrap();
+    client
+        .set("multi".to_owned(), "line1\nline2".to_owned())
+        .unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["export", "dump.jsonl", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Exported 2501 pairs"));
+    let dump = fs::read_to_string(temp_dir.path().join("dump.jsonl")).unwrap();
+    assert_eq!(dump.lines().count(), 2501);
+    assert!(dump.contains(r#"{"key":"key42","value":"value42"}"#));
+    assert!(dump.contains(r#"{"key":"multi","value":"bGluZTEKbGluZTI=","encoding":"base64"}"#));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["export", "--format", "csv", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("key,value,encoding\n"))
+        .stdout(contains("multi,bGluZTEKbGluZTI=,base64\n"));
+
+    // The directory of a running server is left alone
+    for args in [&["export", "--dir", "."], &["import", "--dir", "."]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("stop the server first"));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", "dump.jsonl", "--dir", "copy"])
+        .args(&["--batch-size", "100"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("Imported 2501 pairs"));
+    let copy_dir = temp_dir.path().join("copy");
+    assert_eq!(fs::read_to_string(copy_dir.join("engine")).unwrap(), "kvs");
+    {
+        let store = KvStore::open(temp_dir.path().join("copy")).unwrap();
+        assert_eq!(store.scan(String::new()).unwrap().len(), 2501);
+        assert_eq!(
+            store.get("multi".to_owned()).unwrap(),
+            Some("line1\nline2".to_owned())
+        );
+    }
+
+    // The line of a broken record is reported
+    fs::write(
+        temp_dir.path().join("broken.jsonl"),
+        "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", "broken.jsonl", "--dir", "copy"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid record on line 2"));
+}
+
+// A script on stdin runs on one connection, failed lines are reported and make it fail
+#[test]
+fn cli_shell_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4024";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--addr", addr])
+        .write_stdin(
+            "# comments are skipped\n\
+             set key1 \"hello world\"\n\
+             get key1\n\
+             mset key2 a key3 b\n\
+             mget key2 none\n\
+             rm key1\n\
+             get key1\n\
+             bogus\n\
+             set key4\n\
+             scan key1\n",
+        )
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("OK\nhello world\nOK\na\nKey not found\nOK\nKey not found\nkey2\ta\nkey3\tb\n")
+        .stderr(contains("ms)"))
+        .stderr(contains("Line 8: Unknown command bogus"))
+        .stderr(contains("Line 9: Usage: set KEY VALUE"))
+        .stderr(contains("2 commands failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["shell", "--no-timing", "--addr", addr])
+        .write_stdin("get key2\nexit\nget key3\n")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a\n")
+        .stderr(is_empty());
+    child.kill().unwrap();
+}
+
+// A short bench reports every kind of request
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["bench", "--connections", "2", "--duration", "1"])
+        .args(&["--keys", "100", "--distribution", "zipfian"])
+        .args(&["--mix", "60:30:10", "--value-size", "16", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("requests/s, 0 errors"))
+        .stdout(contains("p999 ms"))
+        .stdout(contains("\nget "))
+        .stdout(contains("\nset "))
+        .stdout(contains("\nrm "))
+        .stdout(contains("\nall "));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["bench", "--mix", "0:0:0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("like 80:15:5"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["bench", "--mix", "4294967295:1:0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("like 80:15:5"));
+    for exponent in ["-1", "NaN", "inf"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["bench", &format!("--zipf-exponent={}", exponent)])
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("0 or more"));
+    }
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.scan(None, 1000).unwrap().len() <= 100);
+
+    // A server going away is counted as errors, the bench still reports
+    let bench = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["bench", "--duration", "2", "--no-preload", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let output = bench.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("A bench connection failed"));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains(", 0 errors"));
+}
+
+// A TLS server doesn't start with plaintext raft peers, leader or HTTP gateway
+#[test]
+fn cli_tls_with_replication() {
+    let temp_dir = TempDir::new().unwrap();
+    let tls = ["--tls-cert", "cert.pem", "--tls-key", "key.pem"];
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&tls)
+        .args(&["--raft-addr", "127.0.0.1:4028", "--peers", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--raft-addr"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&tls)
+        .args(&["--replica-of", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--leader-ca"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&tls)
+        .args(&["--http-addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--http-addr"));
 }
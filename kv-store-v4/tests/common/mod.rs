@@ -0,0 +1,56 @@
+//! Fixtures shared by the integration tests.
+
+// 每个测试文件只用到其中的一部分
+#![allow(dead_code)]
+
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "sync-server")]
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+#[cfg(feature = "sync-server")]
+use kvs::KvsServer;
+use kvs::{AsyncKvsServer, KvStore};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// 服务器起不来的话，等这么久之后让测试失败，而不是一直卡住
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits until something listens on `addr`, panics if nothing does in time.
+pub fn wait_for(addr: &str) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(addr).is_err() {
+        if Instant::now() > deadline {
+            panic!("Nothing listens on {} after {:?}", addr, STARTUP_TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Runs a `KvsServer` with 4 threads on a new store in the background.
+///
+/// `configure` gets the server and the store directory before the server starts,
+/// the server stops with the test process.
+#[cfg(feature = "sync-server")]
+pub fn start_server<F>(addr: &'static str, configure: F) -> TempDir
+where
+    F: FnOnce(&mut KvsServer<KvStore, SharedQueueThreadPool>, &Path),
+{
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap());
+    configure(&mut server, temp_dir.path());
+    thread::spawn(move || server.run(addr).unwrap());
+    wait_for(addr);
+    temp_dir
+}
+
+/// Runs an `AsyncKvsServer` on `rt` with the store in `dir`, it stops with the runtime.
+pub fn start_async_server(rt: &Runtime, dir: &Path, addr: &'static str) {
+    let engine = KvStore::open(dir).unwrap();
+    rt.spawn(AsyncKvsServer::new(engine).run(addr));
+    wait_for(addr);
+}
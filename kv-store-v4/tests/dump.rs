@@ -0,0 +1,56 @@
+use kvs::{DumpFormat, DumpReader, DumpWriter};
+
+fn pairs() -> Vec<(String, String)> {
+    vec![
+        ("plain".to_owned(), "value".to_owned()),
+        ("comma, \"quote\"".to_owned(), "tab\there".to_owned()),
+        ("newline".to_owned(), "line1\nline2".to_owned()),
+        ("empty".to_owned(), String::new()),
+        ("unicode".to_owned(), "值\u{7f}".to_owned()),
+    ]
+}
+
+fn round_trip(format: DumpFormat) -> String {
+    let mut writer = DumpWriter::new(Vec::new(), format).unwrap();
+    for (key, value) in pairs() {
+        writer.write(&key, &value).unwrap();
+    }
+    let dump = writer.finish().unwrap();
+    let read: Vec<_> = DumpReader::new(&dump[..], format)
+        .collect::<kvs::Result<_>>()
+        .unwrap();
+    assert_eq!(read, pairs());
+    String::from_utf8(dump).unwrap()
+}
+
+#[test]
+fn json_lines_round_trip() {
+    let dump = round_trip(DumpFormat::JsonLines);
+    assert_eq!(dump.lines().count(), 5);
+    assert!(dump.starts_with("{\"key\":\"plain\",\"value\":\"value\"}\n"));
+    assert!(dump.contains(r#"{"key":"newline","value":"bGluZTEKbGluZTI=","encoding":"base64"}"#));
+}
+
+#[test]
+fn csv_round_trip() {
+    let dump = round_trip(DumpFormat::Csv);
+    assert!(dump.starts_with("key,value,encoding\nplain,value,\n"));
+    assert!(dump.contains("newline,bGluZTEKbGluZTI=,base64\n"));
+}
+
+// Blank lines are skipped, a broken record names its line
+#[test]
+fn json_lines_errors() {
+    let dump = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\",\"value\":2}\n";
+    let mut reader = DumpReader::new(dump.as_bytes(), DumpFormat::JsonLines);
+    assert_eq!(
+        reader.next().unwrap().unwrap(),
+        ("a".to_owned(), "1".to_owned())
+    );
+    let err = reader.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("line 3"));
+
+    let dump = "{\"key\":\"a\",\"value\":\"!!\",\"encoding\":\"base64\"}\n";
+    let mut reader = DumpReader::new(dump.as_bytes(), DumpFormat::JsonLines);
+    assert!(reader.next().unwrap().is_err());
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use common::wait_for;
+use kvs::{Acl, AsyncKvsServer, HttpGateway, KvStore, KvsClient, Limits};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// 发一个 HTTP/1.1 请求，返回状态码和 body
+fn request(addr: &str, method: &str, path: &str, headers: &[&str], body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for header in headers {
+        req.push_str(header);
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+    stream.write_all(req.as_bytes()).unwrap();
+
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let status = resp[9..12].parse().unwrap();
+    let body = resp.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+fn get(addr: &str, path: &str) -> (u16, String) {
+    request(addr, "GET", path, &[], "")
+}
+
+// The gateway and the native protocol share the engine
+#[test]
+fn http_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let rt = Runtime::new().unwrap();
+    rt.spawn(AsyncKvsServer::new(engine.clone()).run("127.0.0.1:4090"));
+    let gateway = HttpGateway::new(engine);
+    let shutdown = gateway.shutdown_handle();
+    let gateway = rt.spawn(gateway.run("127.0.0.1:4091"));
+    wait_for("127.0.0.1:4090");
+    wait_for("127.0.0.1:4091");
+    let addr = "127.0.0.1:4091";
+
+    assert_eq!(get(addr, "/health"), (200, r#"{"status":"ok"}"#.to_owned()));
+    assert_eq!(get(addr, "/keys/key1").0, 404);
+    assert_eq!(
+        request(addr, "PUT", "/keys/key1", &[], "value1"),
+        (204, String::new())
+    );
+    assert_eq!(get(addr, "/keys/key1"), (200, "value1".to_owned()));
+
+    // Keys may contain slashes and escaped characters
+    assert_eq!(request(addr, "PUT", "/keys/app/a%20b", &[], "值").0, 204);
+    assert_eq!(get(addr, "/keys/app%2Fa%20b"), (200, "值".to_owned()));
+    let mut client = KvsClient::connect("127.0.0.1:4090").unwrap();
+    assert_eq!(
+        client.get("app/a b".to_owned()).unwrap(),
+        Some("值".to_owned())
+    );
+    client.set("app/c".to_owned(), "value3".to_owned()).unwrap();
+
+    assert_eq!(
+        get(addr, "/keys?prefix=app%2F"),
+        (
+            200,
+            r#"{"next":null,"pairs":{"app/a b":"值","app/c":"value3"}}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        get(addr, "/keys?prefix=none"),
+        (200, r#"{"next":null,"pairs":{}}"#.to_owned())
+    );
+    // Pages end at `limit` pairs, `next` is where the following one starts
+    assert_eq!(
+        get(addr, "/keys?prefix=app%2F&limit=1"),
+        (
+            200,
+            r#"{"next":"app/a b","pairs":{"app/a b":"值"}}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        get(addr, "/keys?prefix=app%2F&limit=1&after=app%2Fa%20b"),
+        (
+            200,
+            r#"{"next":"app/c","pairs":{"app/c":"value3"}}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        get(addr, "/keys?prefix=app%2F&limit=1&after=app%2Fc"),
+        (200, r#"{"next":null,"pairs":{}}"#.to_owned())
+    );
+    assert_eq!(get(addr, "/keys?prefix=app%2F&limit=0").0, 400);
+
+    assert_eq!(
+        request(addr, "DELETE", "/keys/key1", &[], ""),
+        (204, String::new())
+    );
+    let (status, body) = request(addr, "DELETE", "/keys/key1", &[], "");
+    assert_eq!(status, 404);
+    assert!(body.contains("Key not found"), "{}", body);
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    assert_eq!(request(addr, "POST", "/keys/key1", &[], "").0, 405);
+    assert_eq!(get(addr, "/other").0, 404);
+    assert_eq!(get(addr, "/keys/%zz").0, 400);
+
+    let (status, stats) = get(addr, "/stats");
+    assert_eq!(status, 200);
+    let stats: serde_json::Value = serde_json::from_str(&stats).unwrap();
+    assert_eq!(stats["requests"]["put"], 2);
+    assert_eq!(stats["requests"]["delete"], 2);
+    assert_eq!(stats["requests"]["scan"], 6);
+    assert_eq!(stats["client_errors"], 3);
+
+    shutdown.shutdown();
+    rt.block_on(gateway).unwrap().unwrap();
+}
+
+// Read-only gateways, bearer tokens and the body size limit
+#[test]
+fn http_access() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let rt = Runtime::new().unwrap();
+
+    let mut replica = HttpGateway::new(engine.clone());
+    replica.set_read_only(true);
+    rt.spawn(replica.run("127.0.0.1:4092"));
+
+    let acl_path = temp_dir.path().join("acl.json");
+    fs::write(
+        &acl_path,
+        r#"{ "users": { "app": { "tokens": ["t0k3n"], "rules": [{ "prefix": "app/", "read": true, "write": true }] } } }"#,
+    )
+    .unwrap();
+    let mut gateway = HttpGateway::new(engine);
+    gateway.set_acl(Acl::load(&acl_path).unwrap());
+    gateway.set_limits(Limits {
+        max_request_size: 16,
+        ..Limits::default()
+    });
+    rt.spawn(gateway.run("127.0.0.1:4093"));
+    wait_for("127.0.0.1:4092");
+    wait_for("127.0.0.1:4093");
+
+    let (status, body) = request("127.0.0.1:4092", "PUT", "/keys/app/key1", &[], "value1");
+    assert_eq!(status, 403);
+    assert!(body.contains("Read-only"), "{}", body);
+
+    let addr = "127.0.0.1:4093";
+    let token = "Authorization: Bearer t0k3n";
+    assert_eq!(get(addr, "/keys/app/key1").0, 401);
+    assert_eq!(
+        request(
+            addr,
+            "GET",
+            "/keys/app/key1",
+            &["Authorization: Bearer wrong"],
+            ""
+        )
+        .0,
+        401
+    );
+    assert_eq!(
+        request(addr, "PUT", "/keys/app/key1", &[token], "value1").0,
+        204
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys/app/key1", &[token], ""),
+        (200, "value1".to_owned())
+    );
+    assert_eq!(
+        request(addr, "PUT", "/keys/other", &[token], "value1").0,
+        403
+    );
+    // Scanning a prefix the user can't read would leak other keys
+    assert_eq!(request(addr, "GET", "/keys?prefix=", &[token], "").0, 403);
+    assert_eq!(
+        request(addr, "GET", "/keys?prefix=app/", &[token], ""),
+        (
+            200,
+            r#"{"next":null,"pairs":{"app/key1":"value1"}}"#.to_owned()
+        )
+    );
+
+    let (status, body) = request(addr, "PUT", "/keys/app/key2", &[token], &"x".repeat(17));
+    assert_eq!(status, 413);
+    assert!(body.contains("Request too large"), "{}", body);
+    assert_eq!(get(addr, "/health").0, 200);
+}
@@ -1,4 +1,6 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -81,6 +83,59 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should write several keys at once and report which removed keys existed
+#[test]
+fn set_and_remove_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    store.set_many(pairs)?;
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    let removed = store.remove_many(vec![
+        "key1".to_owned(),
+        "none".to_owned(),
+        "key1".to_owned(),
+        "key2".to_owned(),
+    ])?;
+    assert_eq!(removed, vec![true, false, false, true]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.scan("key".to_owned())?.len(), 98);
+
+    // Reopen and check the batch was persisted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should return pairs with the given prefix in key order
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("user:2".to_owned(), "b".to_owned())?;
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("user:3".to_owned(), "c".to_owned())?;
+    store.set("users".to_owned(), "x".to_owned())?;
+    store.set("other".to_owned(), "y".to_owned())?;
+    store.remove("user:3".to_owned())?;
+
+    let expected = vec![
+        ("user:1".to_owned(), "a".to_owned()),
+        ("user:2".to_owned(), "b".to_owned()),
+    ];
+    assert_eq!(store.scan("user:".to_owned())?, expected);
+    assert_eq!(store.scan("".to_owned())?.len(), 4);
+    assert!(store.scan("none".to_owned())?.is_empty());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,6 +182,25 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Values must stay readable from the same store after compaction rewrote the log.
+#[test]
+fn get_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..400 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(!temp_dir.path().join("1.log").exists(), "No compaction detected");
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("399".to_owned()));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -158,6 +232,53 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Writes batched together by the writer thread keep their order
+#[test]
+fn concurrent_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..10 {
+                let key = format!("key{}-{}", thread_id, i);
+                store.set(key.clone(), "value".to_owned()).unwrap();
+                if i % 2 == 0 {
+                    store.remove(key.clone()).unwrap();
+                    assert!(store.remove(key).is_err());
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..100 {
+            for i in 0..10 {
+                let expected = if i % 2 == 0 {
+                    None
+                } else {
+                    Some("value".to_owned())
+                };
+                assert_eq!(store.get(format!("key{}-{}", thread_id, i))?, expected);
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_batch_size(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +330,116 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Stats count the keys, and the bytes a compaction would free
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.keys, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.generations, 1);
+    assert_eq!(stats.stale_bytes, 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.stale_bytes > 0);
+
+    // Reopening starts a new log and finds the same stale bytes
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.generations, 2);
+    assert_eq!(store.stats()?.stale_bytes, stats.stale_bytes);
+
+    Ok(())
+}
+
+// A low threshold compacts long before the default would
+#[test]
+fn store_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        write_batch: 1,
+        sync_writes: true,
+        compaction_threshold: 1024,
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats()?.stale_bytes <= 1024);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value999".to_owned()));
+    drop(store);
+
+    let options = KvStoreOptions {
+        write_batch: 0,
+        ..options
+    };
+    assert!(KvStore::open_with_options(temp_dir.path(), options).is_err());
+    assert!(KvStore::open_with_batch_size(temp_dir.path(), 0).is_err());
+    Ok(())
+}
+
+// A command cut off by a crash at the end of a log is dropped when the store is opened
+#[test]
+fn torn_command_at_log_end() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(br#"{"Set":{"key":"key2","val"#)?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Walking the keys a page at a time visits each one once, in order
+#[test]
+fn scan_after_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..25 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.remove("key03".to_owned())?;
+
+    let mut keys = Vec::new();
+    let mut after = None;
+    loop {
+        let page = store.scan_after(after.take(), 10)?;
+        assert!(page.len() <= 10);
+        match page.last() {
+            Some((key, _)) => after = Some(key.clone()),
+            None => break,
+        }
+        keys.extend(page.into_iter().map(|(key, _)| key));
+    }
+    let expected: Vec<_> = (0..25)
+        .filter(|&i| i != 3)
+        .map(|i| format!("key{:02}", i))
+        .collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
@@ -0,0 +1,216 @@
+mod common;
+
+use common::wait_for;
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsError, Limits, MultiplexedKvsClient};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+#[cfg(feature = "sync-server")]
+fn start_server(addr: &'static str, limits: Limits) -> TempDir {
+    common::start_server(addr, |server, _| server.set_limits(limits))
+}
+
+fn assert_busy<T: std::fmt::Debug>(res: kvs::Result<T>) {
+    match res {
+        Err(KvsError::ServerBusy) => {}
+        res => panic!("Expected server busy, got {:?}", res),
+    }
+}
+
+/// 服务端关闭连接的话，读会很快返回 EOF 或者错误
+fn assert_closed_within(mut stream: TcpStream, timeout: Duration) {
+    let start = Instant::now();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 64];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    assert!(start.elapsed() < timeout, "took {:?}", start.elapsed());
+}
+
+// Connections beyond the limit are told the server is busy, whatever protocol they speak
+#[cfg(feature = "sync-server")]
+#[test]
+fn limits_max_connections() {
+    let limits = Limits {
+        max_connections: 1,
+        ..Limits::default()
+    };
+    let _temp_dir = start_server("127.0.0.1:4080", limits);
+    let mut first = KvsClient::connect("127.0.0.1:4080").unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut second = KvsClient::connect("127.0.0.1:4080").unwrap();
+    assert_busy(second.get("key1".to_owned()));
+    assert_busy(MultiplexedKvsClient::connect("127.0.0.1:4080").map(|_| ()));
+
+    let mut json = TcpStream::connect("127.0.0.1:4080").unwrap();
+    json.write_all(br#"{"Get":{"key":"key1"}}"#).unwrap();
+    let mut resp = Vec::new();
+    BufReader::new(json).read_until(b'}', &mut resp).unwrap();
+    assert_eq!(resp, br#"{"Err":"Server busy"}"#);
+
+    // The first connection is still served, and its slot is free again once it's closed
+    assert_eq!(
+        first.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    drop(first);
+    let start = Instant::now();
+    loop {
+        let res = KvsClient::connect("127.0.0.1:4080").and_then(|mut c| c.get("key1".to_owned()));
+        match res {
+            Ok(value) => {
+                assert_eq!(value, Some("value1".to_owned()));
+                break;
+            }
+            Err(KvsError::ServerBusy) if start.elapsed() < Duration::from_secs(2) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+// Idle connections and requests sent too slowly are closed, freeing their thread
+#[cfg(feature = "sync-server")]
+#[test]
+fn limits_timeouts() {
+    let limits = Limits {
+        idle_timeout: Duration::from_millis(300),
+        request_timeout: Duration::from_millis(300),
+        ..Limits::default()
+    };
+    let _temp_dir = start_server("127.0.0.1:4081", limits);
+
+    // Never sends anything
+    let silent = TcpStream::connect("127.0.0.1:4081").unwrap();
+    assert_closed_within(silent, Duration::from_secs(2));
+
+    // Sends half a frame header and stalls
+    let mut stalled = TcpStream::connect("127.0.0.1:4081").unwrap();
+    stalled.write_all(b"KVS\x01\x01\x00").unwrap();
+    let mut answer = [0; 4];
+    stalled.read_exact(&mut answer).unwrap();
+    assert_closed_within(stalled, Duration::from_secs(2));
+
+    // Sends half a JSON request and stalls
+    let mut stalled = TcpStream::connect("127.0.0.1:4081").unwrap();
+    stalled.write_all(br#"{"Get":{"key""#).unwrap();
+    assert_closed_within(stalled, Duration::from_secs(2));
+
+    let mut client = KvsClient::connect("127.0.0.1:4081").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    // Clients which keep talking aren't affected
+    let mut client = KvsClient::connect("127.0.0.1:4081").unwrap();
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+}
+
+#[cfg(feature = "sync-server")]
+#[test]
+fn limits_max_request_size() {
+    let limits = Limits {
+        max_request_size: 1024,
+        ..Limits::default()
+    };
+    let _temp_dir = start_server("127.0.0.1:4082", limits);
+    let mut client = KvsClient::connect("127.0.0.1:4082").unwrap();
+    client.set("key1".to_owned(), "x".repeat(1000)).unwrap();
+    match client.set("key2".to_owned(), "x".repeat(2000)) {
+        Err(KvsError::Remote { message, .. }) => {
+            assert!(message.contains("Request too large"), "{}", message)
+        }
+        res => panic!("Expected an error, got {:?}", res),
+    }
+    // The rest of the oversized request can't be skipped, the connection is closed
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let client = MultiplexedKvsClient::connect("127.0.0.1:4082").unwrap();
+    assert!(client.set("key2".to_owned(), "x".repeat(2000)).is_err());
+
+    // The JSON protocol has no lengths, the server stops reading at the limit
+    let mut json = TcpStream::connect("127.0.0.1:4082").unwrap();
+    let req = format!(
+        r#"{{"Set":{{"key":"key2","value":"{}"}}}}"#,
+        "x".repeat(2000)
+    );
+    json.write_all(req.as_bytes()).unwrap();
+    let mut resp = Vec::new();
+    BufReader::new(json.try_clone().unwrap())
+        .read_until(b'}', &mut resp)
+        .unwrap();
+    let resp = String::from_utf8(resp).unwrap();
+    assert!(resp.contains("Request too large"), "{}", resp);
+    assert_closed_within(json, Duration::from_secs(2));
+
+    let mut client = KvsClient::connect("127.0.0.1:4082").unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn limits_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = AsyncKvsServer::new(engine);
+    server.set_limits(Limits {
+        max_connections: 2,
+        idle_timeout: Duration::from_millis(300),
+        request_timeout: Duration::from_millis(300),
+        max_request_size: 1024,
+    });
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4083"));
+    wait_for("127.0.0.1:4083");
+
+    let mut client = KvsClient::connect("127.0.0.1:4083").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let multiplexed = MultiplexedKvsClient::connect("127.0.0.1:4083").unwrap();
+    assert!(multiplexed
+        .set("key2".to_owned(), "x".repeat(2000))
+        .is_err());
+
+    // The multiplexed connection was closed for its oversized request
+    let start = Instant::now();
+    let mut second = loop {
+        let mut second = KvsClient::connect("127.0.0.1:4083").unwrap();
+        match second.get("key1".to_owned()) {
+            Ok(value) => {
+                assert_eq!(value, Some("value1".to_owned()));
+                break second;
+            }
+            Err(KvsError::ServerBusy) if start.elapsed() < Duration::from_secs(2) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("{}", e),
+        }
+    };
+    let mut third = KvsClient::connect("127.0.0.1:4083").unwrap();
+    assert_busy(third.get("key1".to_owned()));
+
+    thread::sleep(Duration::from_millis(600));
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(second.get("key1".to_owned()).is_err());
+    let mut client = KvsClient::connect("127.0.0.1:4083").unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
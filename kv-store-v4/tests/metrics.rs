@@ -0,0 +1,88 @@
+mod common;
+
+use common::wait_for;
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsError, Metrics, MetricsServer};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// 抓一次 `/metrics`，返回状态码和 body
+fn scrape(addr: &str, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    let status = resp[9..12].parse().unwrap();
+    (status, resp.split_once("\r\n\r\n").unwrap().1.to_owned())
+}
+
+fn assert_metric(body: &str, line: &str) {
+    assert!(
+        body.lines().any(|l| l == line),
+        "{} not found in\n{}",
+        line,
+        body
+    );
+}
+
+// Requests, errors, connections and the engine are all reported
+#[test]
+fn metrics_endpoint() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let metrics = Metrics::new();
+    let mut server = AsyncKvsServer::new(engine.clone());
+    server.set_metrics(metrics.clone());
+    let metrics_server = MetricsServer::new(engine, metrics);
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4110"));
+    rt.spawn(metrics_server.run("127.0.0.1:4111"));
+    wait_for("127.0.0.1:4110");
+    wait_for("127.0.0.1:4111");
+
+    let mut client = KvsClient::connect("127.0.0.1:4110").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    assert!(matches!(
+        client.remove("none".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let (status, body) = scrape("127.0.0.1:4111", "/metrics");
+    assert_eq!(status, 200);
+    assert_metric(&body, r#"kvs_requests_total{type="set"} 3"#);
+    assert_metric(&body, r#"kvs_requests_total{type="get"} 1"#);
+    assert_metric(&body, r#"kvs_requests_total{type="remove"} 1"#);
+    assert_metric(
+        &body,
+        r#"kvs_request_errors_total{type="remove",code="key_not_found"} 1"#,
+    );
+    assert_metric(
+        &body,
+        r#"kvs_request_duration_seconds_bucket{type="set",le="+Inf"} 3"#,
+    );
+    assert_metric(&body, r#"kvs_request_duration_seconds_count{type="get"} 1"#);
+    assert_metric(&body, "kvs_connections_active 1");
+    assert_metric(&body, "kvs_engine_keys 2");
+    assert!(body.contains("# TYPE kvs_request_duration_seconds histogram"));
+    assert_metric(&body, "kvs_pool_queue_depth 0");
+    assert!(!body.lines().any(|l| l == "kvs_engine_stale_bytes 0"));
+
+    drop(client);
+    thread::sleep(Duration::from_millis(100));
+    assert_metric(
+        &scrape("127.0.0.1:4111", "/metrics").1,
+        "kvs_connections_active 0",
+    );
+    assert_eq!(scrape("127.0.0.1:4111", "/other").0, 404);
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{start_async_server, wait_for};
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsClientPool, Limits};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// Many threads share a pool without opening more connections than its size
+#[test]
+fn pool_shared_by_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let rt = Runtime::new().unwrap();
+    start_async_server(&rt, temp_dir.path(), "127.0.0.1:4130");
+    let mut pool = KvsClientPool::new("127.0.0.1:4130").unwrap();
+    pool.set_size(2);
+    let pool = Arc::new(pool);
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(pool.open_connections() <= 2);
+    assert_eq!(pool.scan(None, 1000).unwrap().len(), 400);
+
+    // 一直借着的连接会让别的线程等到超时
+    let mut pool = KvsClientPool::new("127.0.0.1:4130").unwrap();
+    pool.set_size(1);
+    pool.set_checkout_timeout(Duration::from_millis(100));
+    let mut client = pool.checkout().unwrap();
+    assert_eq!(
+        client.get("key0-0".to_owned()).unwrap(),
+        Some("value0".to_owned())
+    );
+    let start = Instant::now();
+    assert!(pool.checkout().is_err());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    drop(client);
+    assert!(pool.checkout().is_ok());
+}
+
+// Gets survive a server restart, the pool reconnects with backoff
+#[test]
+fn pool_reconnects_after_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let rt = Arc::new(Runtime::new().unwrap());
+    let addr = "127.0.0.1:4131";
+    let server = AsyncKvsServer::new(KvStore::open(temp_dir.path()).unwrap());
+    let handle = server.shutdown_handle();
+    let running = rt.spawn(server.run(addr));
+    wait_for(addr);
+
+    let mut pool = KvsClientPool::new(addr).unwrap();
+    pool.set_size(2);
+    pool.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    pool.set_max_retries(10);
+    pool.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(pool.open_connections(), 1);
+
+    handle.shutdown();
+    rt.block_on(running).unwrap().unwrap();
+    let restart = {
+        let rt = Arc::clone(&rt);
+        let path = temp_dir.path().to_owned();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            let engine = KvStore::open(path).unwrap();
+            rt.spawn(AsyncKvsServer::new(engine).run(addr));
+        })
+    };
+
+    assert_eq!(
+        pool.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    restart.join().unwrap();
+    assert_eq!(pool.open_connections(), 1);
+    pool.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        pool.mget(vec!["key1".to_owned(), "key2".to_owned()])
+            .unwrap(),
+        vec![Some("value1".to_owned()), Some("value2".to_owned())]
+    );
+}
+
+// An idle connection past the health check interval is checked before it's handed out
+#[test]
+fn pool_health_check() {
+    let temp_dir = TempDir::new().unwrap();
+    let rt = Runtime::new().unwrap();
+    let addr = "127.0.0.1:4132";
+    let server = AsyncKvsServer::new(KvStore::open(temp_dir.path()).unwrap());
+    let handle = server.shutdown_handle();
+    let running = rt.spawn(server.run(addr));
+    wait_for(addr);
+
+    let mut pool = KvsClientPool::new(addr).unwrap();
+    pool.set_health_check_interval(Duration::ZERO);
+    pool.set_max_retries(0);
+    pool.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    handle.shutdown();
+    rt.block_on(running).unwrap().unwrap();
+    start_async_server(&rt, temp_dir.path(), addr);
+
+    // 写请求不重试，能成功说明拿到的是新的连接
+    pool.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(pool.open_connections(), 1);
+}
+
+// A busy server closes only the refused connection, reads back off until a slot is free
+#[test]
+fn pool_server_busy() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = AsyncKvsServer::new(KvStore::open(temp_dir.path()).unwrap());
+    server.set_limits(Limits {
+        max_connections: 2,
+        ..Limits::default()
+    });
+    let rt = Runtime::new().unwrap();
+    let addr = "127.0.0.1:4133";
+    rt.spawn(server.run(addr));
+    wait_for(addr);
+
+    let mut pool = KvsClientPool::new(addr).unwrap();
+    pool.set_size(2);
+    pool.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    pool.set_max_retries(10);
+    pool.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut held = pool.checkout().unwrap();
+
+    let mut other = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        other.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        drop(other);
+    });
+
+    let start = Instant::now();
+    assert_eq!(
+        pool.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    release.join().unwrap();
+    assert_eq!(pool.open_connections(), 2);
+    assert_eq!(
+        held.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
@@ -0,0 +1,294 @@
+#![cfg(feature = "sync-server")]
+
+mod common;
+
+use kvs::{KvsClient, KvsError, MultiplexedKvsClient, Pipeline};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use tempfile::TempDir;
+
+fn start_server(addr: &'static str) -> TempDir {
+    common::start_server(addr, |_, _| {})
+}
+
+fn frame(id: u32, code: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![1];
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.push(code);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn read_frame(stream: &mut impl Read) -> (u32, u8, Vec<u8>) {
+    let mut header = [0; 10];
+    stream.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 1);
+    let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).unwrap();
+    (id, header[5], payload)
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut buf = (s.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(s.as_bytes());
+    buf
+}
+
+// Clients speaking the old streaming JSON protocol are still served
+#[test]
+fn json_client_still_works() {
+    let _temp_dir = start_server("127.0.0.1:4030");
+    let mut client = KvsClient::connect("127.0.0.1:4030").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let stream = TcpStream::connect("127.0.0.1:4030").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut ask = |req: &str| {
+        writer.write_all(req.as_bytes()).unwrap();
+        writer.write_all(b"\n").unwrap();
+        let mut buf = Vec::new();
+        // 响应后面没有换行，读到 '}' 为止
+        reader.read_until(b'}', &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    assert_eq!(ask(r#"{"Get":{"key":"key1"}}"#), r#"{"Ok":"value1"}"#);
+    assert_eq!(
+        ask(r#"{"Set":{"key":"key2","value":"value2"}}"#),
+        r#"{"Ok":null}"#
+    );
+    assert_eq!(ask(r#"{"Remove":{"key":"key1"}}"#), r#"{"Ok":null}"#);
+
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+// An unknown opcode gets an error response, the following frames are still understood
+#[test]
+fn framed_unknown_opcode() {
+    let _temp_dir = start_server("127.0.0.1:4031");
+    let mut stream = TcpStream::connect("127.0.0.1:4031").unwrap();
+    stream.write_all(b"KVS\x01").unwrap();
+    let mut answer = [0; 4];
+    stream.read_exact(&mut answer).unwrap();
+    assert_eq!(&answer, b"KVS\x01");
+
+    stream.write_all(&frame(7, 200, b"garbage")).unwrap();
+    let (id, status, payload) = read_frame(&mut stream);
+    assert_eq!((id, status), (7, 4));
+    // The message is followed by the InvalidRequest error code
+    assert_eq!(payload.last(), Some(&7));
+    assert!(String::from_utf8(payload)
+        .unwrap()
+        .contains("Unknown opcode"));
+
+    let mut set = string("key1");
+    set.extend(string("value1"));
+    stream.write_all(&frame(8, 2, &set)).unwrap();
+    assert_eq!(read_frame(&mut stream), (8, 0, Vec::new()));
+
+    stream.write_all(&frame(9, 1, &string("key1"))).unwrap();
+    assert_eq!(read_frame(&mut stream), (9, 1, string("value1")));
+    stream.write_all(&frame(10, 1, &string("none"))).unwrap();
+    assert_eq!(read_frame(&mut stream), (10, 2, Vec::new()));
+}
+
+// The server answers an unsupported version with its own and closes the connection
+#[test]
+fn framed_unsupported_version() {
+    let _temp_dir = start_server("127.0.0.1:4032");
+    let mut stream = TcpStream::connect("127.0.0.1:4032").unwrap();
+    stream.write_all(b"KVS\x09").unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, b"KVS\x01");
+}
+
+// Pipelined requests are answered in order, failures don't stop the rest
+#[test]
+fn pipelined_requests() {
+    let _temp_dir = start_server("127.0.0.1:4033");
+    let mut client = KvsClient::connect("127.0.0.1:4033").unwrap();
+
+    // 比流水线窗口大，中间要边发边收
+    let mut pipeline = Pipeline::new();
+    for i in 0..1000 {
+        pipeline.set(format!("key{}", i), format!("value{}", i));
+    }
+    for i in 0..1000 {
+        pipeline.get(format!("key{}", i));
+    }
+    pipeline.remove("key0".to_owned()).remove("key0".to_owned());
+    assert_eq!(pipeline.len(), 2002);
+
+    let results = client.execute(pipeline).unwrap();
+    assert_eq!(results.len(), 2002);
+    for result in &results[..1000] {
+        assert_eq!(result.as_ref().unwrap(), &None);
+    }
+    for (i, result) in results[1000..2000].iter().enumerate() {
+        assert_eq!(result.as_ref().unwrap(), &Some(format!("value{}", i)));
+    }
+    assert!(results[2000].is_ok());
+    assert!(matches!(results[2001], Err(KvsError::KeyNotFound)));
+
+    // The connection is still in sync afterwards
+    assert_eq!(client.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Multi-key requests answer for every key in one response
+#[test]
+fn multi_key_requests() {
+    let _temp_dir = start_server("127.0.0.1:4035");
+    let mut client = KvsClient::connect("127.0.0.1:4035").unwrap();
+    let pairs = (0..500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs).unwrap();
+
+    let keys: Vec<_> = (0..500).map(|i| format!("key{}", i)).collect();
+    let values = client.mget(keys.clone()).unwrap();
+    assert_eq!(values.len(), 500);
+    assert_eq!(values[123], Some("value123".to_owned()));
+
+    let removed = client
+        .mremove(vec!["key0".to_owned(), "none".to_owned()])
+        .unwrap();
+    assert_eq!(removed, vec![true, false]);
+    assert_eq!(
+        client
+            .mget(vec![
+                "key0".to_owned(),
+                "key1".to_owned(),
+                "none".to_owned()
+            ])
+            .unwrap(),
+        vec![None, Some("value1".to_owned()), None]
+    );
+    assert_eq!(
+        client.mget(Vec::new()).unwrap(),
+        Vec::<Option<String>>::new()
+    );
+}
+
+// Many threads share one multiplexed connection
+#[test]
+fn multiplexed_client() {
+    let _temp_dir = start_server("127.0.0.1:4034");
+    let client = MultiplexedKvsClient::connect("127.0.0.1:4034").unwrap();
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let client = client.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(
+                        client.get(key.clone()).unwrap(),
+                        Some(format!("value{}", i))
+                    );
+                    if i % 2 == 0 {
+                        client.remove(key.clone()).unwrap();
+                        assert!(client.remove(key).is_err());
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(client.get("key0-0".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key7-99".to_owned()).unwrap(),
+        Some("value99".to_owned())
+    );
+}
+
+// Scans page through the keys in order, the server caps the page size
+#[test]
+fn scan_pages() {
+    let _temp_dir = start_server("127.0.0.1:4036");
+    let mut client = KvsClient::connect("127.0.0.1:4036").unwrap();
+    let pairs = (0..1500)
+        .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs).unwrap();
+
+    let page = client.scan(None, 3).unwrap();
+    assert_eq!(
+        page,
+        vec![
+            ("key0000".to_owned(), "value0".to_owned()),
+            ("key0001".to_owned(), "value1".to_owned()),
+            ("key0002".to_owned(), "value2".to_owned()),
+        ]
+    );
+    let page = client.scan(Some("key0002".to_owned()), 1).unwrap();
+    assert_eq!(page, vec![("key0003".to_owned(), "value3".to_owned())]);
+
+    let page = client.scan(None, u32::MAX).unwrap();
+    assert_eq!(page.len(), 1000);
+    let page = client.scan(Some("key0999".to_owned()), u32::MAX).unwrap();
+    assert_eq!(page.len(), 500);
+    assert!(client
+        .scan(Some("key1499".to_owned()), 10)
+        .unwrap()
+        .is_empty());
+
+    // Pages of large values stop at the byte budget, the rest is on the next pages
+    let big = (0..10)
+        .map(|i| (format!("big{}", i), "x".repeat(1024 * 1024)))
+        .collect();
+    client.mset(big).unwrap();
+    let page = client.scan(None, 100).unwrap();
+    assert_eq!(page.len(), 4);
+    let mut after = page.last().map(|(key, _)| key.clone());
+    let mut seen = page.len();
+    while let Some(last) = after.filter(|key| key.starts_with("big")) {
+        let page = client.scan(Some(last), 100).unwrap();
+        seen += page
+            .iter()
+            .filter(|(key, _)| key.starts_with("big"))
+            .count();
+        after = page.last().map(|(key, _)| key.clone());
+    }
+    assert_eq!(seen, 10);
+}
+
+// Requests and responses over the frame limit fail on their own, the connection stays usable
+#[test]
+fn frames_too_large() {
+    let _temp_dir = start_server("127.0.0.1:4037");
+    let mut client = KvsClient::connect("127.0.0.1:4037").unwrap();
+    let value = "x".repeat(40 * 1024 * 1024);
+    client.set("key1".to_owned(), value.clone()).unwrap();
+    client.set("key2".to_owned(), value.clone()).unwrap();
+
+    match client.mget(vec!["key1".to_owned(), "key2".to_owned()]) {
+        Err(KvsError::Remote { message, .. }) => assert!(message.contains("Response too large")),
+        res => panic!("unexpected {:?}", res.map(|values| values.len())),
+    }
+    let too_large = "x".repeat(70 * 1024 * 1024);
+    assert!(matches!(
+        client.set("key3".to_owned(), too_large),
+        Err(KvsError::FrameTooLarge { .. })
+    ));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap().map(|v| v.len()),
+        Some(value.len())
+    );
+}
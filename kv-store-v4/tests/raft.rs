@@ -0,0 +1,150 @@
+use assert_cmd::prelude::*;
+use kvs::{KvsClient, KvsError, Result};
+use std::fs;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const CLIENT_ADDRS: [&str; 3] = ["127.0.0.1:4020", "127.0.0.1:4021", "127.0.0.1:4022"];
+const RAFT_ADDRS: [&str; 3] = ["127.0.0.1:4120", "127.0.0.1:4121", "127.0.0.1:4122"];
+
+// Kills the server when dropped, so a failing test doesn't leave it holding the port
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_node(dir: &TempDir, id: usize) -> Server {
+    let peers: Vec<&str> = (0..3).filter(|&i| i != id).map(|i| RAFT_ADDRS[i]).collect();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", CLIENT_ADDRS[id]])
+        .args(["--raft-addr", RAFT_ADDRS[id], "--peers", &peers.join(",")])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    Server(child)
+}
+
+// Writes through every alive node until one of them accepts it as the leader.
+fn set_on_leader(alive: &[usize], key: &str, value: &str) -> Result<usize> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        for &id in alive {
+            let mut client = KvsClient::connect(CLIENT_ADDRS[id])?;
+            match client.set(key.to_owned(), value.to_owned()) {
+                Ok(()) => return Ok(id),
+                Err(KvsError::NotLeader(_))
+                | Err(KvsError::NoLeader)
+                | Err(KvsError::StringError(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if Instant::now() > deadline {
+            panic!("no leader elected among {:?}", alive);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// Reads are only answered by the leader, follow the redirect of a follower.
+fn get_from_leader(id: usize, key: &str) -> Result<Option<String>> {
+    match KvsClient::connect(CLIENT_ADDRS[id])?.get(key.to_owned()) {
+        Err(KvsError::NotLeader(leader)) => KvsClient::connect(leader)?.get(key.to_owned()),
+        res => res,
+    }
+}
+
+fn wait_for_value(id: usize, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        // An election may be going on, or the node may still know the old leader
+        let value = match get_from_leader(id, key) {
+            Ok(value) if value.as_deref() == expected => return Ok(()),
+            Ok(value) => Ok(value),
+            Err(KvsError::NoLeader)
+            | Err(KvsError::Io(_))
+            | Err(KvsError::Remote { .. })
+            | Err(KvsError::StringError(_)) => Err(()),
+            Err(e) => return Err(e),
+        };
+        if Instant::now() > deadline {
+            panic!("node {} has {:?} for {}, expected {:?}", id, value, key, expected);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn raft_cluster_survives_leader_failure() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut nodes: Vec<Option<Server>> = (0..3).map(|id| Some(spawn_node(&dirs[id], id))).collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let leader = set_on_leader(&[0, 1, 2], "key1", "value1")?;
+    for id in 0..3 {
+        wait_for_value(id, "key1", Some("value1"))?;
+    }
+
+    // Followers redirect writes to the leader
+    let follower = (leader + 1) % 3;
+    match KvsClient::connect(CLIENT_ADDRS[follower])?.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::NotLeader(addr)) => assert_eq!(addr, CLIENT_ADDRS[leader]),
+        res => panic!("expected a redirect, got {:?}", res),
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", CLIENT_ADDRS[follower]])
+        .assert()
+        .success();
+    wait_for_value(follower, "key2", Some("value2"))?;
+
+    // Followers redirect reads as well, a read never sees an older value than a write before it
+    match KvsClient::connect(CLIENT_ADDRS[follower])?.get("key2".to_owned()) {
+        Err(KvsError::NotLeader(addr)) => assert_eq!(addr, CLIENT_ADDRS[leader]),
+        res => panic!("expected a redirect, got {:?}", res),
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", CLIENT_ADDRS[follower]])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    // Removing a missing key still fails through the cluster
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key3", "--addr", CLIENT_ADDRS[follower]])
+        .assert()
+        .failure();
+
+    // Kill the leader, the other two elect a new one and keep the data
+    nodes[leader] = None;
+    let alive: Vec<usize> = (0..3).filter(|&id| id != leader).collect();
+    let new_leader = set_on_leader(&alive, "key3", "value3")?;
+    assert_ne!(new_leader, leader);
+    for &id in &alive {
+        wait_for_value(id, "key1", Some("value1"))?;
+        wait_for_value(id, "key2", Some("value2"))?;
+        wait_for_value(id, "key3", Some("value3"))?;
+    }
+
+    // The old leader catches up after it comes back
+    nodes[leader] = Some(spawn_node(&dirs[leader], leader));
+    thread::sleep(Duration::from_secs(1));
+    wait_for_value(leader, "key3", Some("value3"))?;
+
+    // The applied index is saved, a restarted node doesn't replay the whole log
+    for &id in &alive {
+        let state = fs::read(dirs[id].path().join("raft").join("state"))?;
+        let state: serde_json::Value = serde_json::from_slice(&state)?;
+        assert!(state["applied"].as_u64().unwrap() >= 4);
+    }
+
+    Ok(())
+}
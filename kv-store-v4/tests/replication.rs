@@ -0,0 +1,152 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, Result};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Kills the server when dropped, so a failing test doesn't leave it holding the port
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(dir: &TempDir, addr: &str, replica_of: Option<&str>) -> Server {
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--engine", "kvs", "--addr", addr]).current_dir(dir);
+    if let Some(leader) = replica_of {
+        cmd.args(["--replica-of", leader]);
+    }
+    Server(cmd.spawn().unwrap())
+}
+
+// Poll the replica until it reports the expected value or time runs out.
+fn wait_for_value(addr: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let value = KvsClient::connect(addr)?.get(key.to_owned())?;
+        if value.as_deref() == expected {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            panic!("replica has {:?} for {}, expected {:?}", value, key, expected);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn follower_replicates_writes() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = spawn_server(&leader_dir, "127.0.0.1:4010", None);
+    let follower = spawn_server(&follower_dir, "127.0.0.1:4011", Some("127.0.0.1:4010"));
+    thread::sleep(Duration::from_secs(1));
+
+    // Each connection holds a server thread, so don't keep clients around
+    {
+        let mut client = KvsClient::connect("127.0.0.1:4010")?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key2".to_owned(), "value2".to_owned())?;
+        client.set("key1".to_owned(), "value3".to_owned())?;
+        client.remove("key2".to_owned())?;
+    }
+
+    wait_for_value("127.0.0.1:4011", "key1", Some("value3"))?;
+    wait_for_value("127.0.0.1:4011", "key2", None)?;
+
+    // The replica is read-only
+    {
+        let mut replica = KvsClient::connect("127.0.0.1:4011")?;
+        assert!(matches!(
+            replica.set("key3".to_owned(), "value".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+        assert!(matches!(
+            replica.remove("key1".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+    }
+
+    // Restarted follower resumes from where it stopped
+    drop(follower);
+    KvsClient::connect("127.0.0.1:4010")?.set("key4".to_owned(), "value4".to_owned())?;
+    let _follower = spawn_server(&follower_dir, "127.0.0.1:4011", Some("127.0.0.1:4010"));
+    thread::sleep(Duration::from_secs(1));
+    wait_for_value("127.0.0.1:4011", "key4", Some("value4"))?;
+    wait_for_value("127.0.0.1:4011", "key1", Some("value3"))?;
+
+    // The follower keeps one connection and reconnects when the leader restarts
+    drop(leader);
+    let _leader = spawn_server(&leader_dir, "127.0.0.1:4010", None);
+    thread::sleep(Duration::from_secs(1));
+    KvsClient::connect("127.0.0.1:4010")?.set("key5".to_owned(), "value5".to_owned())?;
+    wait_for_value("127.0.0.1:4011", "key5", Some("value5"))?;
+
+    Ok(())
+}
+
+#[test]
+fn follower_bootstraps_from_snapshot() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+
+    // Overwrite the same keys until the leader compacts away its first log
+    {
+        let store = KvStore::open(leader_dir.path())?;
+        for iter in 0..400 {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        store.remove("key0".to_owned())?;
+    }
+    assert!(!leader_dir.path().join("1.log").exists());
+
+    let _leader = spawn_server(&leader_dir, "127.0.0.1:4012", None);
+    let _follower = spawn_server(&follower_dir, "127.0.0.1:4013", Some("127.0.0.1:4012"));
+    thread::sleep(Duration::from_secs(1));
+
+    wait_for_value("127.0.0.1:4013", "key99", Some("value399"))?;
+    wait_for_value("127.0.0.1:4013", "key0", None)?;
+
+    // Writes after the snapshot are streamed as usual
+    KvsClient::connect("127.0.0.1:4012")?.set("key0".to_owned(), "back".to_owned())?;
+    wait_for_value("127.0.0.1:4013", "key0", Some("back"))?;
+
+    Ok(())
+}
+
+// A snapshot larger than one response is paged in with scans
+#[test]
+fn follower_loads_large_snapshot() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+
+    {
+        let store = KvStore::open(leader_dir.path())?;
+        for iter in 0..3 {
+            for key_id in 0..100 {
+                let value = format!("{}{}", iter, "x".repeat(64 * 1024));
+                store.set(format!("key{:03}", key_id), value)?;
+            }
+        }
+        store.remove("key000".to_owned())?;
+    }
+    assert!(!leader_dir.path().join("1.log").exists());
+
+    let _leader = spawn_server(&leader_dir, "127.0.0.1:4026", None);
+    let _follower = spawn_server(&follower_dir, "127.0.0.1:4027", Some("127.0.0.1:4026"));
+    thread::sleep(Duration::from_secs(1));
+
+    let expected = format!("2{}", "x".repeat(64 * 1024));
+    wait_for_value("127.0.0.1:4027", "key099", Some(&expected))?;
+    wait_for_value("127.0.0.1:4027", "key001", Some(&expected))?;
+    wait_for_value("127.0.0.1:4027", "key000", None)?;
+
+    Ok(())
+}
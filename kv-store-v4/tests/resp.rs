@@ -0,0 +1,262 @@
+mod common;
+
+use common::wait_for;
+use kvs::{Acl, AsyncKvsServer, KvStore, Limits, Protocol};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+#[cfg(feature = "sync-server")]
+fn start_server(addr: &'static str, limits: Limits) -> TempDir {
+    common::start_server(addr, |server, _| {
+        server.set_protocol(Protocol::Resp);
+        server.set_limits(limits);
+    })
+}
+
+/// 一个最简单的 redis 客户端，回复按 redis-cli 的样子转成字符串
+struct Redis {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Redis {
+    fn connect(addr: &str) -> Redis {
+        let stream = TcpStream::connect(addr).unwrap();
+        Redis {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut cmd = format!("*{}\r\n", args.len());
+        for arg in args {
+            cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(cmd.as_bytes()).unwrap();
+    }
+
+    fn call(&mut self, args: &[&str]) -> String {
+        self.send(args);
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => rest.to_owned(),
+            "-" => format!("(error) {}", rest),
+            ":" => format!("(integer) {}", rest),
+            "$" if rest == "-1" => "(nil)".to_owned(),
+            "$" => {
+                let mut buf = vec![0; rest.parse::<usize>().unwrap() + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                format!("{:?}", String::from_utf8_lossy(&buf[..buf.len() - 2]))
+            }
+            "*" => {
+                let items: Vec<_> = (0..rest.parse().unwrap()).map(|_| self.reply()).collect();
+                format!("[{}]", items.join(", "))
+            }
+            _ => panic!("Unexpected reply {}", line),
+        }
+    }
+}
+
+fn commands(addr: &str) {
+    let mut redis = Redis::connect(addr);
+    assert_eq!(redis.call(&["PING"]), "PONG");
+    assert_eq!(redis.call(&["ping", "hello"]), r#""hello""#);
+    assert_eq!(redis.call(&["GET", "key1"]), "(nil)");
+    assert_eq!(redis.call(&["SET", "key1", "value1"]), "OK");
+    assert_eq!(redis.call(&["GET", "key1"]), r#""value1""#);
+    assert_eq!(redis.call(&["SET", "key 2", "值\r\n"]), "OK");
+    assert_eq!(redis.call(&["get", "key 2"]), r#""值\r\n""#);
+    assert_eq!(
+        redis.call(&["EXISTS", "key1", "none", "key1"]),
+        "(integer) 2"
+    );
+    assert_eq!(redis.call(&["DEL", "key1", "none"]), "(integer) 1");
+    assert_eq!(redis.call(&["EXISTS", "key1"]), "(integer) 0");
+
+    assert_eq!(
+        redis.call(&["GET"]),
+        "(error) ERR wrong number of arguments for 'get' command"
+    );
+    assert_eq!(
+        redis.call(&["SET", "key1", "value1", "NX"]),
+        "(error) ERR SET options are not supported"
+    );
+    assert_eq!(
+        redis.call(&["FLUSHALL"]),
+        "(error) ERR unknown command 'FLUSHALL'"
+    );
+    assert_eq!(redis.call(&["SELECT", "0"]), "OK");
+
+    // Pipelined commands are answered in order
+    for i in 0..25 {
+        redis.send(&["SET", &format!("user:{:02}", i), &i.to_string()]);
+    }
+    for _ in 0..25 {
+        assert_eq!(redis.reply(), "OK");
+    }
+
+    // Inline commands, as sent by telnet
+    redis.writer.write_all(b"GET user:07\r\n\r\n").unwrap();
+    assert_eq!(redis.reply(), r#""7""#);
+
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "user:1?", "COUNT", "4"]),
+        r#"["1", ["user:10", "user:11", "user:12", "user:13"]]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "1", "MATCH", "user:1?", "COUNT", "4"]),
+        r#"["2", ["user:14", "user:15", "user:16", "user:17"]]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "2", "MATCH", "user:1?", "COUNT", "4"]),
+        r#"["0", ["user:18", "user:19"]]"#
+    );
+    // COUNT is how many keys are looked at, a page may have fewer matches
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "*2*"]),
+        r#"["3", ["key 2", "user:02"]]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "3", "MATCH", "*2*", "COUNT", "100"]),
+        r#"["0", ["user:12", "user:20", "user:21", "user:22", "user:23", "user:24"]]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "user:2\\*"]),
+        r#"["0", []]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "u*e*r*:*2*", "COUNT", "100"]),
+        r#"["0", ["user:02", "user:12", "user:20", "user:21", "user:22", "user:23", "user:24"]]"#
+    );
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "user:0", "COUNT", "100"]),
+        r#"["0", []]"#
+    );
+    assert_eq!(redis.call(&["SCAN", "99"]), "(error) ERR invalid cursor");
+    // Many stars don't backtrack exponentially
+    let long = "a".repeat(60);
+    assert_eq!(redis.call(&["SET", &long, "long"]), "OK");
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "a*a*a*a*a*a*a*a*a*a*a*a*b"]),
+        r#"["0", []]"#
+    );
+    assert_eq!(redis.call(&["DEL", &long]), "(integer) 1");
+    assert_eq!(
+        redis.call(&["SCAN", "0", "COUNT", "0"]),
+        "(error) ERR value is not an integer or out of range"
+    );
+
+    assert_eq!(redis.call(&["QUIT"]), "OK");
+    let mut rest = Vec::new();
+    redis.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[cfg(feature = "sync-server")]
+#[test]
+fn resp_commands() {
+    let _temp_dir = start_server("127.0.0.1:4100", Limits::default());
+    commands("127.0.0.1:4100");
+}
+
+#[test]
+fn resp_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = AsyncKvsServer::new(engine);
+    server.set_protocol(Protocol::Resp);
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4101"));
+    wait_for("127.0.0.1:4101");
+    commands("127.0.0.1:4101");
+
+    // Idle connections don't hold a thread, more of them than tokio has blocking threads
+    let idle: Vec<_> = (0..600)
+        .map(|_| {
+            let mut redis = Redis::connect("127.0.0.1:4101");
+            assert_eq!(redis.call(&["PING"]), "PONG");
+            redis
+        })
+        .collect();
+    let mut redis = Redis::connect("127.0.0.1:4101");
+    assert_eq!(redis.call(&["SET", "key1", "value1"]), "OK");
+    assert_eq!(redis.call(&["GET", "key1"]), r#""value1""#);
+    drop(idle);
+}
+
+// AUTH maps to the ACL users, errors use the prefixes Redis clients expect
+#[cfg(feature = "sync-server")]
+#[test]
+fn resp_auth_and_errors() {
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use kvs::KvsServer;
+
+    let temp_dir = TempDir::new().unwrap();
+    let acl_path = temp_dir.path().join("acl.json");
+    fs::write(
+        &acl_path,
+        r#"{ "users": { "alice": { "password_hash": "pbkdf2-sha256$1000$a3ZzLXRlc3Qtc2FsdC0wMQ==$EPXEqdHISvvV9KjObLbliWZ6qZ6KpWAvGW+bs4uPnq8=", "rules": [{ "prefix": "app/", "read": true, "write": true }] } } }"#,
+    )
+    .unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(2).unwrap());
+    server.set_protocol(Protocol::Resp);
+    server.set_acl(Acl::load(&acl_path).unwrap());
+    thread::spawn(move || server.run("127.0.0.1:4102").unwrap());
+    let mut replica = KvsServer::new(engine, SharedQueueThreadPool::new(2).unwrap());
+    replica.set_protocol(Protocol::Resp);
+    replica.set_read_only(true);
+    replica.set_limits(Limits {
+        max_request_size: 64,
+        ..Limits::default()
+    });
+    thread::spawn(move || replica.run("127.0.0.1:4103").unwrap());
+    wait_for("127.0.0.1:4102");
+    wait_for("127.0.0.1:4103");
+
+    let mut redis = Redis::connect("127.0.0.1:4102");
+    assert_eq!(
+        redis.call(&["GET", "app/key1"]),
+        "(error) NOAUTH Authentication required."
+    );
+    assert!(redis
+        .call(&["AUTH", "alice", "wrong"])
+        .starts_with("(error) WRONGPASS"));
+    assert_eq!(redis.call(&["AUTH", "alice", "secret"]), "OK");
+    assert_eq!(redis.call(&["SET", "app/key1", "value1"]), "OK");
+    assert!(redis.call(&["GET", "other"]).starts_with("(error) NOPERM"));
+    assert!(redis
+        .call(&["DEL", "app/key1", "other"])
+        .starts_with("(error) NOPERM"));
+    assert!(redis.call(&["SCAN", "0"]).starts_with("(error) NOPERM"));
+    assert_eq!(
+        redis.call(&["SCAN", "0", "MATCH", "app/*"]),
+        r#"["0", ["app/key1"]]"#
+    );
+
+    let mut replica = Redis::connect("127.0.0.1:4103");
+    assert_eq!(replica.call(&["GET", "app/key1"]), r#""value1""#);
+    assert_eq!(
+        replica.call(&["SET", "app/key1", "value2"]),
+        "(error) READONLY You can't write against a read only replica."
+    );
+    // An oversized request can't be skipped, the connection is closed after the error
+    assert!(replica
+        .call(&["GET", &"x".repeat(100)])
+        .starts_with("(error) ERR Protocol error: request too large"));
+    let mut rest = Vec::new();
+    replica.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
@@ -0,0 +1,115 @@
+use kvs::{KvsEngine, Result, ShardedKvStore};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+const SHARDS: usize = 4;
+
+// Should get previously stored values, wherever they are stored
+#[test]
+fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Keys are actually spread over the shards
+    for shard in 0..SHARDS {
+        assert!(temp_dir.path().join(format!("shard-{}", shard)).is_dir());
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Scans merge the shards in key order
+#[test]
+fn scan_across_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+
+    for i in (0..50).rev() {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let pairs = store.scan("key".to_owned())?;
+    let expected: Vec<(String, String)> = (0..50)
+        .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(store.scan("".to_owned())?.len(), 51);
+
+    Ok(())
+}
+
+// The shard count is fixed when the store is created
+#[test]
+fn reopen_with_other_shard_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(ShardedKvStore::open(temp_dir.path(), SHARDS)?);
+    assert!(ShardedKvStore::open(temp_dir.path(), SHARDS + 1).is_err());
+    assert!(ShardedKvStore::open(temp_dir.path(), SHARDS).is_ok());
+    Ok(())
+}
+
+// Zero shards is an error, not a panic
+#[test]
+fn open_without_shards() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(ShardedKvStore::open(temp_dir.path(), 0).is_err());
+    assert!(!temp_dir.path().join("shards").exists());
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = ShardedKvStore::open(temp_dir.path(), SHARDS)?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
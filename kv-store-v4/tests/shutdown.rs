@@ -0,0 +1,89 @@
+mod common;
+
+use common::wait_for;
+use kvs::{AsyncKvsServer, KvStore, KvsClient, KvsEngine};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// Shutting down doesn't wait for idle connections, and everything set before is on disk
+#[cfg(feature = "sync-server")]
+#[test]
+fn sync_server_shutdown() {
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use kvs::KvsServer;
+
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap());
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run("127.0.0.1:4050"));
+    wait_for("127.0.0.1:4050");
+
+    let mut client = KvsClient::connect("127.0.0.1:4050").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let _idle = KvsClient::connect("127.0.0.1:4050").unwrap();
+
+    let start = Instant::now();
+    handle.shutdown();
+    running.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn async_server_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = AsyncKvsServer::new(engine);
+    let handle = server.shutdown_handle();
+    let rt = Runtime::new().unwrap();
+    let running = rt.spawn(server.run("127.0.0.1:4051"));
+    wait_for("127.0.0.1:4051");
+
+    let mut client = KvsClient::connect("127.0.0.1:4051").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let _idle = KvsClient::connect("127.0.0.1:4051").unwrap();
+
+    let start = Instant::now();
+    handle.shutdown();
+    rt.block_on(running).unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// A shutdown before the server started listening still makes it return
+#[cfg(feature = "sync-server")]
+#[test]
+fn sync_server_shutdown_before_run() {
+    use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+    use kvs::KvsServer;
+
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(1).unwrap());
+    server.shutdown_handle().shutdown();
+    let running = thread::spawn(move || server.run("127.0.0.1:4052"));
+    let start = Instant::now();
+    while !running.is_finished() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "server kept running"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    running.join().unwrap().unwrap();
+}
@@ -0,0 +1,239 @@
+mod common;
+
+use common::wait_for;
+use kvs::replication::Follower;
+use kvs::{
+    AsyncKvsServer, ClientTls, KvStore, KvsClient, KvsEngine, MultiplexedKvsClient, ServerTls,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// 测试用的一套证书：一个 CA，它签发的服务端和客户端证书，另外一个不相干的 CA
+struct Certs {
+    dir: TempDir,
+}
+
+impl Certs {
+    fn generate() -> Certs {
+        let dir = TempDir::new().unwrap();
+        let ca = ca_cert();
+        write_pem(dir.path(), "ca", &ca.serialize_pem().unwrap());
+        write_pem(dir.path(), "other-ca", &ca_cert().serialize_pem().unwrap());
+
+        let server = Certificate::from_params(CertificateParams::new(vec![
+            "localhost".to_owned(),
+            "kvs.example".to_owned(),
+        ]))
+        .unwrap();
+        write_pem(
+            dir.path(),
+            "server",
+            &server.serialize_pem_with_signer(&ca).unwrap(),
+        );
+        write_pem(
+            dir.path(),
+            "server-key",
+            &server.serialize_private_key_pem(),
+        );
+
+        let client =
+            Certificate::from_params(CertificateParams::new(vec!["client".to_owned()])).unwrap();
+        write_pem(
+            dir.path(),
+            "client",
+            &client.serialize_pem_with_signer(&ca).unwrap(),
+        );
+        write_pem(
+            dir.path(),
+            "client-key",
+            &client.serialize_private_key_pem(),
+        );
+        Certs { dir }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(format!("{}.pem", name))
+    }
+
+    fn server_tls(&self) -> ServerTls {
+        ServerTls::new(self.path("server"), self.path("server-key")).unwrap()
+    }
+
+    fn client_tls(&self) -> ClientTls {
+        ClientTls::new(self.path("ca")).unwrap()
+    }
+}
+
+fn ca_cert() -> Certificate {
+    let mut params = CertificateParams::new(Vec::new());
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+fn write_pem(dir: &Path, name: &str, pem: &str) {
+    fs::write(dir.join(format!("{}.pem", name)), pem).unwrap();
+}
+
+#[cfg(feature = "sync-server")]
+fn start_server(addr: &'static str, tls: ServerTls) -> TempDir {
+    common::start_server(addr, |server, _| server.set_tls(tls))
+}
+
+// Plain and multiplexed clients work over TLS, plaintext clients are refused
+#[cfg(feature = "sync-server")]
+#[test]
+fn tls_clients() {
+    let certs = Certs::generate();
+    let _temp_dir = start_server("127.0.0.1:4060", certs.server_tls());
+
+    let mut client = KvsClient::connect_tls("127.0.0.1:4060", &certs.client_tls()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // Values larger than a TLS record
+    let value = "x".repeat(100_000);
+    client.set("key2".to_owned(), value.clone()).unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), Some(value));
+
+    let multiplexed =
+        MultiplexedKvsClient::connect_tls("127.0.0.1:4060", &certs.client_tls()).unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let client = multiplexed.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i);
+                    client.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let plain = KvsClient::connect("127.0.0.1:4060");
+    assert!(plain
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+}
+
+// The client refuses servers whose certificate isn't signed by its CA or doesn't match the name
+#[cfg(feature = "sync-server")]
+#[test]
+fn tls_untrusted_server() {
+    let certs = Certs::generate();
+    let _temp_dir = start_server("127.0.0.1:4061", certs.server_tls());
+
+    let other = ClientTls::new(certs.path("other-ca")).unwrap();
+    assert!(KvsClient::connect_tls("127.0.0.1:4061", &other).is_err());
+
+    let mut wrong_name = certs.client_tls();
+    wrong_name.set_server_name("example.com").unwrap();
+    assert!(KvsClient::connect_tls("127.0.0.1:4061", &wrong_name).is_err());
+
+    let mut by_name = certs.client_tls();
+    by_name.set_server_name("kvs.example").unwrap();
+    let mut client = KvsClient::connect_tls("127.0.0.1:4061", &by_name).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+}
+
+// With a client CA set, only clients presenting a certificate signed by it are served
+#[cfg(feature = "sync-server")]
+#[test]
+fn tls_client_auth() {
+    let certs = Certs::generate();
+    let mut tls = certs.server_tls();
+    tls.set_client_ca(certs.path("ca")).unwrap();
+    let _temp_dir = start_server("127.0.0.1:4062", tls);
+
+    let anonymous = KvsClient::connect_tls("127.0.0.1:4062", &certs.client_tls());
+    assert!(anonymous
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+
+    let mut identified = certs.client_tls();
+    identified
+        .set_identity(certs.path("client"), certs.path("client-key"))
+        .unwrap();
+    let mut client = KvsClient::connect_tls("127.0.0.1:4062", &identified).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn tls_async_server() {
+    let certs = Certs::generate();
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = AsyncKvsServer::new(engine);
+    let mut tls = certs.server_tls();
+    tls.set_client_ca(certs.path("ca")).unwrap();
+    server.set_tls(tls);
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4063"));
+    wait_for("127.0.0.1:4063");
+
+    let mut identified = certs.client_tls();
+    identified
+        .set_identity(certs.path("client"), certs.path("client-key"))
+        .unwrap();
+    let mut client = KvsClient::connect_tls("127.0.0.1:4063", &identified).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let multiplexed = MultiplexedKvsClient::connect_tls("127.0.0.1:4063", &identified).unwrap();
+    assert_eq!(
+        multiplexed.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let anonymous = KvsClient::connect_tls("127.0.0.1:4063", &certs.client_tls());
+    assert!(anonymous
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+    let plain = KvsClient::connect("127.0.0.1:4063");
+    assert!(plain
+        .and_then(|mut client| client.get("key1".to_owned()))
+        .is_err());
+}
+
+// A follower pulls the log of a TLS leader over TLS
+#[test]
+fn tls_follower() {
+    let certs = Certs::generate();
+    let leader_dir = TempDir::new().unwrap();
+    let mut server = AsyncKvsServer::new(KvStore::open(leader_dir.path()).unwrap());
+    server.set_tls(certs.server_tls());
+    let rt = Runtime::new().unwrap();
+    rt.spawn(server.run("127.0.0.1:4064"));
+    wait_for("127.0.0.1:4064");
+    let mut client = KvsClient::connect_tls("127.0.0.1:4064", &certs.client_tls()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let follower_dir = TempDir::new().unwrap();
+    let store = KvStore::open(follower_dir.path()).unwrap();
+    let mut follower = Follower::new(store.clone(), "127.0.0.1:4064".parse().unwrap()).unwrap();
+    follower.set_tls(certs.client_tls());
+    follower.spawn().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.get("key1".to_owned()).unwrap().is_none() {
+        assert!(Instant::now() < deadline, "follower didn't catch up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}